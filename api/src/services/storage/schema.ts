@@ -1,5 +1,21 @@
 import {relations as drizzleRelations, type InferSelectModel} from "drizzle-orm"
-import {boolean, index, jsonb, pgEnum, pgTable, primaryKey, serial, text, uuid} from "drizzle-orm/pg-core"
+import {
+	bigint,
+	bigserial,
+	boolean,
+	doublePrecision,
+	index,
+	integer,
+	jsonb,
+	numeric,
+	pgEnum,
+	pgTable,
+	primaryKey,
+	serial,
+	text,
+	timestamp,
+	uuid,
+} from "drizzle-orm/pg-core"
 
 export const ipfsCache = pgTable("ipfs_cache", {
 	id: serial(),
@@ -53,12 +69,32 @@ export const values = pgTable(
 		value: text().notNull(),
 		language: text(),
 		unit: text(),
+		/**
+		 * The edit that last set the value. Null if its edit couldn't be
+		 * indexed.
+		 */
+		editId: uuid(),
+		/**
+		 * The value parsed according to its property's data type, so values
+		 * can be filtered and sorted without casting the text value. Only
+		 * the columns of the property's data type are set. Text and Relation
+		 * values only have their text value.
+		 */
+		number: numeric(),
+		boolean: boolean(),
+		time: timestamp({withTimezone: true}),
+		x: doublePrecision(),
+		y: doublePrecision(),
 	},
 	(table) => [
 		// Basic index for text searches - will add GIN via migration
 		index("values_text_idx").on(table.value),
 		// Composite index for space-filtered searches
 		index("values_space_text_idx").on(table.spaceId, table.value),
+		// Range filters and sorting on typed values of a property
+		index("values_property_number_idx").on(table.propertyId, table.number),
+		index("values_property_time_idx").on(table.propertyId, table.time),
+		index("values_point_idx").on(table.x, table.y),
 	],
 )
 
@@ -75,8 +111,135 @@ export const relations = pgTable("relations", {
 	position: text(),
 	spaceId: uuid().notNull(),
 	verified: boolean(),
+	/**
+	 * The edit that last created or updated the relation. Null if its edit
+	 * couldn't be indexed.
+	 */
+	editId: uuid(),
 })
 
+/**
+ * Every edit published to a space, in the order edits were applied. Values
+ * and relations reference the edit that last touched them. Keyed by space as
+ * the same edit may be published to several spaces.
+ */
+export const edits = pgTable(
+	"edits",
+	{
+		id: uuid().notNull(),
+		name: text().notNull(),
+		contentUri: text().notNull(),
+		spaceId: uuid().notNull(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+		editIndex: integer().notNull(),
+		blockTimestamp: text().notNull(),
+		authors: uuid().array().notNull(),
+		language: uuid(),
+		opCount: integer().notNull(),
+		opCounts: jsonb().notNull(),
+	},
+	(table) => [
+		primaryKey({columns: [table.id, table.spaceId]}),
+		index("edits_space_idx").on(table.spaceId, table.blockNumber, table.editIndex),
+		index("edits_block_idx").on(table.blockNumber, table.editIndex),
+	],
+)
+
+/**
+ * The state of a value after every edit that set or deleted it, keyed by the
+ * block and the position of the edit within the block. Values are read as of
+ * a block or edit from their latest version up to it.
+ */
+export const valueVersions = pgTable(
+	"value_versions",
+	{
+		valueId: text().notNull(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+		editIndex: integer().notNull(),
+		editId: uuid().notNull(),
+		blockTimestamp: text().notNull(),
+		authors: uuid().array().notNull(),
+		propertyId: uuid().notNull(),
+		entityId: uuid().notNull(),
+		spaceId: text().notNull(),
+		value: text().notNull(),
+		language: text(),
+		unit: text(),
+		deleted: boolean().notNull(),
+	},
+	(table) => [
+		primaryKey({columns: [table.valueId, table.blockNumber, table.editIndex]}),
+		index("value_versions_entity_idx").on(table.entityId, table.blockNumber, table.editIndex),
+		index("value_versions_edit_idx").on(table.editId),
+	],
+)
+
+/**
+ * The state of a relation after every edit that created, updated or deleted
+ * it, like value versions.
+ */
+export const relationVersions = pgTable(
+	"relation_versions",
+	{
+		relationId: uuid().notNull(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+		editIndex: integer().notNull(),
+		editId: uuid().notNull(),
+		blockTimestamp: text().notNull(),
+		authors: uuid().array().notNull(),
+		entityId: uuid().notNull(),
+		typeId: uuid().notNull(),
+		fromEntityId: uuid().notNull(),
+		fromSpaceId: uuid(),
+		fromVersionId: uuid(),
+		toEntityId: uuid().notNull(),
+		toSpaceId: uuid(),
+		toVersionId: uuid(),
+		position: text(),
+		spaceId: uuid().notNull(),
+		verified: boolean(),
+		deleted: boolean().notNull(),
+	},
+	(table) => [
+		primaryKey({columns: [table.relationId, table.blockNumber, table.editIndex]}),
+		index("relation_versions_from_entity_idx").on(table.fromEntityId, table.blockNumber, table.editIndex),
+		index("relation_versions_edit_idx").on(table.editId),
+	],
+)
+
+/**
+ * Values set by edits that were rejected instead of written, either because
+ * they don't match their property's data type or because their property
+ * doesn't exist. Keyed like value versions so re-indexing an edit doesn't
+ * record its rejections twice.
+ */
+export const rejectedValues = pgTable(
+	"rejected_values",
+	{
+		valueId: text().notNull(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+		editIndex: integer().notNull(),
+		editId: uuid().notNull(),
+		contentUri: text().notNull(),
+		entityId: uuid().notNull(),
+		propertyId: uuid().notNull(),
+		spaceId: uuid().notNull(),
+		value: text().notNull(),
+		/**
+		 * The data type the value was expected to have. Null if its property
+		 * doesn't exist.
+		 */
+		dataType: dataTypesEnum(),
+		reason: text().notNull(),
+		message: text().notNull(),
+	},
+	(table) => [
+		primaryKey({columns: [table.valueId, table.blockNumber, table.editIndex]}),
+		index("rejected_values_space_idx").on(table.spaceId, table.blockNumber),
+		index("rejected_values_property_idx").on(table.propertyId),
+	],
+)
+
 export const members = pgTable(
 	"members",
 	{
@@ -95,6 +258,49 @@ export const editors = pgTable(
 	(table) => [primaryKey({columns: [table.address, table.spaceId]})],
 )
 
+export const cursors = pgTable(
+	"cursors",
+	{
+		sinkName: text().notNull(),
+		moduleHash: text().notNull(),
+		cursor: text().notNull(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+	},
+	(table) => [primaryKey({columns: [table.sinkName, table.moduleHash]})],
+)
+
+/**
+ * Block ranges a sink finished backfilling, so an interrupted backfill only
+ * streams the ranges it didn't finish when run again.
+ */
+export const backfillRanges = pgTable(
+	"backfill_ranges",
+	{
+		sinkName: text().notNull(),
+		moduleHash: text().notNull(),
+		startBlock: bigint({mode: "number"}).notNull(),
+		endBlock: bigint({mode: "number"}).notNull(),
+	},
+	(table) => [primaryKey({columns: [table.sinkName, table.moduleHash, table.startBlock, table.endBlock]})],
+)
+
+/**
+ * Snapshots of every row a block writes to, taken before the block is
+ * written. The indexer uses these to revert blocks that get reorganized
+ * out of the chain. Entries are pruned once their block is final.
+ */
+export const blockUndoLog = pgTable(
+	"block_undo_log",
+	{
+		id: bigserial({mode: "number"}).primaryKey(),
+		blockNumber: bigint({mode: "number"}).notNull(),
+		tableName: text().notNull(),
+		rowKey: jsonb().notNull(),
+		snapshot: jsonb(),
+	},
+	(table) => [index("block_undo_log_block_number_idx").on(table.blockNumber)],
+)
+
 export const entityForeignValues = drizzleRelations(entities, ({many, one}) => ({
 	values: many(values),
 	property: one(properties, {
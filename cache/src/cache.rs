@@ -35,6 +35,10 @@ impl Storage {
         return Ok(Storage { connection });
     }
 
+    pub fn pool(&self) -> &sqlx::Pool<Postgres> {
+        &self.connection
+    }
+
     pub async fn insert(&self, item: &CacheItem) -> Result<(), CacheError> {
         let json_string = serde_json::to_value(&item.json)?;
 
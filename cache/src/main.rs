@@ -3,44 +3,50 @@ use indexer_utils::id::derive_space_id;
 use indexer_utils::network_ids::GEO;
 use std::sync::Arc;
 use std::{env, io::Error};
+use stream::backfill::PostgresRangeStore;
+use stream::config::env_var;
+use stream::cursor::{Cursor, CursorError, CursorStore, PostgresCursorStore};
+use stream::decoded::OutputError;
+use stream::health::HealthConfig;
+use stream::logging::{init_tracing, LogFormat};
+use stream::shutdown::shutdown_on_signals;
 use stream::utils::BlockMetadata;
 use thiserror::Error;
-use tokio::task;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{error, info, info_span, warn, Instrument};
 
 use dotenv::dotenv;
-use prost::Message;
-use stream::Sink;
+use stream::{Backfill, DecodedSink, EndpointConfig, RunConfig, Sink};
 use tokio::sync::{Mutex, Semaphore};
 
 const PKG_FILE: &str = "geo_substream.spkg";
 const MODULE_NAME: &str = "geo_out";
 const START_BLOCK: i64 = 53965;
+const SINK_NAME: &str = "ipfs_cache";
+const BACKFILL_CONCURRENCY: usize = 8;
+const METRICS_ADDR: &str = "0.0.0.0:9091";
 
 use grc20::pb::chain::{EditPublished, GeoOutput};
 
 mod cache;
+mod metrics;
 use cache::{Cache, CacheItem};
 use ipfs::IpfsClient;
 
-type CacheIndexerError = Error;
-
-pub struct EventData {
-    pub block: BlockMetadata,
-    pub edits_published: Vec<(EditPublished, Vec<EditPublished>)>,
-}
-
 struct CacheIndexer {
     semaphore: Arc<Semaphore>,
     cache: Arc<Mutex<Cache>>,
     ipfs: Arc<IpfsClient>,
+    cursor_store: PostgresCursorStore,
 }
 
 impl CacheIndexer {
-    pub fn new(cache: Cache, ipfs: IpfsClient) -> Self {
+    pub fn new(cache: Cache, ipfs: IpfsClient, cursor_store: PostgresCursorStore) -> Self {
         CacheIndexer {
             cache: Arc::new(Mutex::new(cache)),
             ipfs: Arc::new(ipfs),
             semaphore: Arc::new(Semaphore::new(20)),
+            cursor_store,
         }
     }
 }
@@ -49,49 +55,54 @@ impl CacheIndexer {
 enum IndexerError {
     #[error("Cache indexer error: {0}")]
     Error(#[from] cache::CacheError),
+
+    #[error("Cache indexer error: {0}")]
+    CursorError(#[from] CursorError),
+
+    #[error("Cache indexer error: {0}")]
+    OutputError(#[from] OutputError),
+
+    #[error("Cache indexer error: {0}")]
+    TaskError(#[from] JoinError),
 }
 
-impl Sink<EventData> for CacheIndexer {
-    type Error = CacheIndexerError;
+impl DecodedSink for CacheIndexer {
+    type Output = GeoOutput;
+    type Error = IndexerError;
 
-    async fn load_persisted_cursor(&self) -> Result<Option<String>, Self::Error> {
-        Ok(Some("".to_string()))
+    async fn load_persisted_cursor(
+        &self,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, Self::Error> {
+        Ok(self
+            .cursor_store
+            .load_checked(SINK_NAME, module_hash)
+            .await?)
     }
 
-    async fn persist_cursor(&self, _cursor: String) -> Result<(), Self::Error> {
-        Ok(())
+    async fn persist_cursor(&self, module_hash: &str, cursor: Cursor) -> Result<(), Self::Error> {
+        Ok(self
+            .cursor_store
+            .persist(SINK_NAME, module_hash, &cursor)
+            .await?)
     }
 
-    async fn process_block_scoped_data(
+    async fn process_decoded_block(
         &self,
-        block_data: &stream::pb::sf::substreams::rpc::v2::BlockScopedData,
+        block_metadata: &BlockMetadata,
+        geo: GeoOutput,
     ) -> Result<(), Self::Error> {
-        let output = stream::utils::output(block_data);
-
-        // @TODO: Parsing and decoding of event data should happen in a separate module.
-        // This makes it so we can generate test data using these decoders and pass them
-        // to any arbitrary handler. This gives us testing and prototyping by mocking the
-        // events coming via the stream.
-
-        // We should take the code to get the output and decode it into
-        // a "GeoOutput" into it's own module that any Sink trait impl
-        // can consume to get the decoded data from the substream.
-
         // We want to enable extensible governance actions. This means we should probably
         // distinguish between KG messages and governance messages.
-        let geo = GeoOutput::decode(output.value.as_slice())?;
-
-        let block_metadata = stream::utils::block_metadata(block_data);
-
-        println!(
-            "Block #{} - Payload {} ({} bytes) - Drift {}s – Edits Published {}",
+        info!(
+            "Block #{} - Drift {}s – Edits Published {}",
             block_metadata.block_number,
-            output.type_url.replace("type.googleapis.com/", ""),
-            output.value.len(),
             block_metadata.timestamp,
             geo.edits_published.len()
         );
 
+        let mut tasks = JoinSet::new();
+
         for edit in geo.edits_published {
             if get_blocklist()
                 .dao_addresses
@@ -104,13 +115,28 @@ impl Sink<EventData> for CacheIndexer {
             let cache = self.cache.clone();
             let ipfs = self.ipfs.clone();
 
-            let block_metadata = stream::utils::block_metadata(block_data);
+            let block_metadata = block_metadata.clone();
+            let span = info_span!(
+                "edit",
+                content_uri = %edit.content_uri,
+                dao_address = %edit.dao_address
+            );
+
+            tasks.spawn(
+                async move {
+                    process_edit_event(edit, &cache, &ipfs, &block_metadata).await?;
+                    drop(permit);
+                    Ok::<(), IndexerError>(())
+                }
+                .instrument(span),
+            );
+        }
 
-            task::spawn(async move {
-                process_edit_event(edit, &cache, &ipfs, &block_metadata).await?;
-                drop(permit);
-                Ok::<(), IndexerError>(())
-            });
+        // Every edit in the block has to be cached before the block's cursor
+        // is persisted, otherwise a restart could skip edits that were still
+        // in flight.
+        while let Some(result) = tasks.join_next().await {
+            result??;
         }
 
         Ok(())
@@ -127,6 +153,7 @@ async fn process_edit_event(
         let mut cache_instance = cache.lock().await;
 
         if cache_instance.has(&edit.content_uri).await? {
+            metrics::record_edit("hit");
             return Ok(());
         }
     }
@@ -135,6 +162,8 @@ async fn process_edit_event(
 
     match data {
         Ok(result) => {
+            metrics::record_edit("miss");
+
             let item = CacheItem {
                 uri: edit.content_uri,
                 block: block.timestamp.clone(),
@@ -147,7 +176,8 @@ async fn process_edit_event(
             cache_instance.put(&item).await?;
         }
         Err(error) => {
-            println!("Error writing decoded edit event to cache {}", error);
+            metrics::record_edit("error");
+            warn!("Error writing decoded edit event to cache {}", error);
 
             // We may receive events where the format of the ipfs contents is
             // invalid. We still write a cache item with an is_errored status
@@ -173,24 +203,85 @@ async fn process_edit_event(
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     dotenv().ok();
+    init_tracing(LogFormat::from_env());
+
+    let metrics_addr = env::var("METRICS_ADDR")
+        .unwrap_or_else(|_| METRICS_ADDR.to_string())
+        .parse()
+        .expect("METRICS_ADDR must be a socket address");
 
     let ipfs = IpfsClient::new("https://gateway.lighthouse.storage/ipfs/");
     let storage = cache::Storage::new().await;
 
     match storage {
         Ok(result) => {
+            // Metrics and the health probes are served on the same address.
+            let router = stream::metrics::router().merge(stream::health::router(
+                HealthConfig::from_env(),
+                Some(result.pool().clone()),
+            ));
+            tokio::spawn(async move {
+                if let Err(error) = stream::metrics::serve(metrics_addr, router).await {
+                    error!("Error serving metrics {}", error);
+                }
+            });
+
+            let cursor_store = PostgresCursorStore::new(result.pool().clone());
+            let range_store = PostgresRangeStore::new(result.pool().clone());
             let kv = cache::Cache::new(result);
-            let indexer = CacheIndexer::new(kv, ipfs);
+            let indexer = CacheIndexer::new(kv, ipfs, cursor_store);
+
+            // Replaying a recording doesn't need an endpoint.
+            let replay_file = env_var("SUBSTREAMS_REPLAY_FILE");
+            let endpoints = EndpointConfig::from_env();
+            if replay_file.is_none() && endpoints.is_empty() {
+                panic!("SUBSTREAMS_ENDPOINT not set");
+            }
 
-            let endpoint_url =
-                env::var("SUBSTREAMS_ENDPOINT").expect("SUBSTREAMS_ENDPOINT not set");
+            // The cache doesn't handle undo signals so it only streams
+            // final blocks. The indexer retries cache reads while the cache
+            // catches up to the blocks it is processing.
+            let mut config = RunConfig {
+                start_block: START_BLOCK,
+                final_blocks_only: true,
+                record_file: env_var("SUBSTREAMS_RECORD_FILE"),
+                replay_file,
+                package_cache_dir: env_var("SUBSTREAMS_PACKAGE_CACHE_DIR"),
+                expected_module_hash: env_var("SUBSTREAMS_MODULE_HASH"),
+                // Stops taking new blocks on SIGINT or SIGTERM, e.g., during a
+                // deploy, and returns once the blocks in flight are written.
+                shutdown: shutdown_on_signals(),
+                ..RunConfig::new(endpoints, PKG_FILE, MODULE_NAME)
+            };
+
+            // The cache's output doesn't depend on the order blocks are
+            // processed in, so a fresh cache can be filled up to the head by
+            // streaming ranges of blocks concurrently before streaming
+            // sequentially from there.
+            if let Ok(range_size) = env::var("CACHE_BACKFILL_RANGE_SIZE") {
+                let range_size = range_size
+                    .parse()
+                    .expect("CACHE_BACKFILL_RANGE_SIZE must be a number");
+                let backfill = Backfill::new(
+                    config.clone(),
+                    SINK_NAME,
+                    range_store,
+                    range_size,
+                    BACKFILL_CONCURRENCY,
+                );
+
+                let end_block = backfill.run(&indexer).await.map_err(Error::other)?;
+                if config.shutdown.is_cancelled() {
+                    return Ok(());
+                }
+
+                config.start_block = end_block as i64;
+            }
 
-            let _result = indexer
-                .run(&endpoint_url, PKG_FILE, MODULE_NAME, START_BLOCK, 0)
-                .await;
+            indexer.run(&config).await.map_err(Error::other)?;
         }
         Err(err) => {
-            println!("Error initializing stream {}", err);
+            error!("Error initializing stream {}", err);
         }
     }
 
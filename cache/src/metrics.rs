@@ -0,0 +1,15 @@
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+lazy_static! {
+    pub static ref EDITS: IntCounterVec = register_int_counter_vec!(
+        "cache_edits_total",
+        "Edits seen by the cache, by whether they were already cached, fetched from IPFS or failed to fetch",
+        &["result"]
+    )
+    .unwrap();
+}
+
+pub fn record_edit(result: &str) {
+    EDITS.with_label_values(&[result]).inc();
+}
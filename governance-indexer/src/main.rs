@@ -2,11 +2,17 @@ use std::{env, io::Error};
 
 use chrono::{DateTime, Utc};
 use dotenv::dotenv;
-use stream::Sink;
+use stream::{
+    cursor::Cursor,
+    health::HealthConfig,
+    logging::{init_tracing, LogFormat},
+    EndpointConfig, RunConfig, Sink,
+};
 
 const PKG_FILE: &str = "geo_substream.spkg";
 const MODULE_NAME: &str = "geo_out";
 const START_BLOCK: i64 = 53965;
+const METRICS_ADDR: &str = "0.0.0.0:9092";
 
 type GovernanceIndexerError = Error;
 
@@ -32,14 +38,22 @@ impl GovernanceIndexer {
 impl Sink<GovernanceData> for GovernanceIndexer {
     type Error = GovernanceIndexerError;
 
-    async fn load_persisted_cursor(&self) -> Result<Option<String>, Self::Error> {
-        Ok(Some("".to_string()))
+    async fn load_persisted_cursor(&self, _module_hash: &str) -> Result<Option<Cursor>, Self::Error> {
+        Ok(None)
     }
 
-    async fn persist_cursor(&self, _cursor: String) -> Result<(), Self::Error> {
+    async fn persist_cursor(&self, _module_hash: &str, _cursor: Cursor) -> Result<(), Self::Error> {
         Ok(())
     }
 
+    async fn process_block_undo_signal(
+        &self,
+        _undo_signal: &stream::pb::sf::substreams::rpc::v2::BlockUndoSignal,
+    ) -> Result<(), Self::Error> {
+        // Only final blocks are streamed, which are never undone.
+        Err(Error::other("undoing blocks is not supported"))
+    }
+
     async fn process_block_scoped_data(
         &self,
         block_data: &stream::pb::sf::substreams::rpc::v2::BlockScopedData,
@@ -87,14 +101,36 @@ impl Sink<GovernanceData> for GovernanceIndexer {
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     dotenv().ok();
+    init_tracing(LogFormat::from_env());
+
+    // The governance indexer doesn't use a database yet, so it is ready as
+    // soon as it streams head blocks.
+    let metrics_addr = env::var("METRICS_ADDR")
+        .unwrap_or_else(|_| METRICS_ADDR.to_string())
+        .parse()
+        .expect("METRICS_ADDR must be a socket address");
+    let router =
+        stream::metrics::router().merge(stream::health::router(HealthConfig::from_env(), None));
+    tokio::spawn(async move {
+        if let Err(error) = stream::metrics::serve(metrics_addr, router).await {
+            tracing::error!("Error serving metrics {}", error);
+        }
+    });
 
     let indexer = KgIndexer::new();
 
-    let endpoint_url = env::var("SUBSTREAMS_ENDPOINT").expect("SUBSTREAMS_ENDPOINT not set");
+    let endpoints = EndpointConfig::from_env();
+    if endpoints.is_empty() {
+        panic!("SUBSTREAMS_ENDPOINT not set");
+    }
+
+    let config = RunConfig {
+        start_block: START_BLOCK,
+        final_blocks_only: true,
+        ..RunConfig::new(endpoints, PKG_FILE, MODULE_NAME)
+    };
 
-    let _result = indexer
-        .run(&endpoint_url, PKG_FILE, MODULE_NAME, START_BLOCK, 0)
-        .await;
+    indexer.run(&config).await.map_err(Error::other)?;
 
     Ok(())
 }
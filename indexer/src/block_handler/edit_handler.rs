@@ -1,17 +1,20 @@
 use std::sync::Arc;
 
-use futures::future::join_all;
 use stream::utils::BlockMetadata;
+use tracing::{info_span, warn, Instrument};
 
 use crate::cache::properties_cache::ImmutableCache;
+use crate::metrics;
+use crate::models::edits::EditsModel;
 use crate::models::properties::PropertiesModel;
 use crate::models::relations::RelationsModel;
+use crate::models::versions::VersionsModel;
 use crate::models::{
     entities::EntitiesModel,
-    values::{ValueOp, ValuesModel},
+    values::{RejectedValue, RejectionReason, ValueOp, ValuesModel},
 };
 use crate::storage::StorageBackend;
-use crate::validators::validate_string_by_datatype;
+use crate::validators::{validate_by_datatype, ValidatedValue};
 use crate::{cache::PreprocessedEdit, error::IndexingError};
 
 /// Validates created values against their property data types.
@@ -19,34 +22,52 @@ use crate::{cache::PreprocessedEdit, error::IndexingError};
 /// For each value operation that sets data (ValueChangeType::SET), we:
 /// 1. Look up the property's DataType from the properties cache
 /// 2. Validate the string value against the expected DataType format
-/// 3. Include valid values in the final batch for storage
-/// 4. Log and skip invalid values to prevent data corruption
+/// 3. Include valid values in the final batch for storage, along with the
+///    value parsed according to its DataType
+/// 4. Reject invalid values, and values of unknown properties, to prevent
+///    data corruption
 ///
 /// This validation ensures data integrity by rejecting values that don't
 /// match their property's expected format (e.g., non-numeric strings for
 /// Number properties, invalid checkbox values, malformed coordinates, etc.).
-async fn validate_created_values<C>(created_values: Vec<ValueOp>, cache: &Arc<C>) -> Vec<ValueOp>
+/// Rejected values are returned so they can be recorded.
+async fn validate_created_values<C>(
+    created_values: Vec<ValueOp>,
+    cache: &Arc<C>,
+) -> (Vec<ValueOp>, Vec<RejectedValue>)
 where
     C: ImmutableCache + Send + Sync + 'static,
 {
     let mut validated_created_values = Vec::new();
+    let mut rejected_values = Vec::new();
 
-    for value in created_values {
+    for mut value in created_values {
         // Only validate + write values that have actual content in the value
         if let Some(ref string_value) = value.value {
             match cache.get(&value.property_id).await {
                 Ok(data_type) => {
-                    match validate_string_by_datatype(data_type, string_value) {
-                        Ok(_) => {
+                    match validate_by_datatype(data_type, string_value) {
+                        Ok(validated_value) => {
+                            // Text is already stored as is so only the other
+                            // data types keep their parsed value.
+                            value.typed_value = match validated_value {
+                                ValidatedValue::Text(_) => None,
+                                typed_value => Some(typed_value),
+                            };
                             validated_created_values.push(value);
                         }
                         Err(validation_error) => {
-                            // @TODO: tracing
-                            eprintln!(
+                            metrics::record_validation_rejection(data_type);
+                            warn!(
                                 "Validation error for property {} with value '{}': {}",
                                 value.property_id, string_value, validation_error
                             );
                             // Skip invalid values rather than failing the entire edit
+                            rejected_values.push(RejectedValue {
+                                value,
+                                data_type: Some(data_type),
+                                reason: RejectionReason::Invalid(validation_error),
+                            });
                         }
                     }
                 }
@@ -57,21 +78,29 @@ where
                 // this indexer reads every edit on the chain therefore properties
                 // can't get out of sync.
                 Err(_) => {
-                    // @TODO: tracing
-                    // eprintln!(
-                    //     "Property {} not found in cache, skipping value validation",
-                    //     value.property_id
-                    // );
+                    warn!(
+                        "Property {} not found in cache, skipping value '{}'",
+                        value.property_id, string_value
+                    );
+                    rejected_values.push(RejectedValue {
+                        value,
+                        data_type: None,
+                        reason: RejectionReason::PropertyNotFound,
+                    });
                 }
             }
         }
     }
 
-    validated_created_values
+    (validated_created_values, rejected_values)
 }
 
+/// Writes the block's edits one after the other in the order they were
+/// published on chain. Edits in the same block may touch the same values
+/// and relations, so applying them concurrently would make the result
+/// depend on which write lands last.
 pub async fn run<S, C>(
-    output: &Vec<PreprocessedEdit>,
+    output: &[PreprocessedEdit],
     block_metadata: &BlockMetadata,
     storage: &Arc<S>,
     properties_cache: &Arc<C>,
@@ -80,176 +109,146 @@ where
     S: StorageBackend + Send + Sync + 'static,
     C: ImmutableCache + Send + Sync + 'static,
 {
-    for preprocessed_edit in output {
-        let storage = storage.clone();
-        let block = block_metadata.clone();
-
-        let handle = tokio::spawn({
-            let preprocessed_edit = preprocessed_edit.clone();
-            let storage = storage.clone();
-            let cache = properties_cache.clone();
-            let block = block.clone();
-
-            let mut handles = Vec::new();
-
-            async move {
-                // The Edit might be malformed. The Cache still stores it with an
-                // is_errored flag to denote that the entry exists but can't be
-                // decoded.
-                if !preprocessed_edit.is_errored {
-                    let edit = preprocessed_edit.edit.unwrap();
-                    let space_id = preprocessed_edit.space_id;
-
-                    // We write properties first to update the cache with any properties
-                    // created within the edit. This makes it simpler to do validation
-                    // later in the edit handler as the properties cache will already
-                    // be up-to-date.
-                    let properties = PropertiesModel::map_edit_to_properties(&edit);
-
-                    // For now we write properties to an in-memory cache that we reference
-                    // when validating values in the edit. There's a weird mismatch between
-                    // where properties data lives. We store properties on disk in order
-                    // to be able to query properties. We need to do this in "real-time" as
-                    // our external API depends on being able to query for properties when
-                    // querying for values.
-                    //
-                    // This does mean we write properties in two places, one for the cache,
-                    // and one for the queryable store. Eventually I think we want to move
-                    // to in-memory for _all_ data stores with a disk-based commit log, but
-                    // for now we'll write properties twice.
-                    for property in &properties {
-                        cache.insert(&property.id, property.data_type.clone()).await;
-                    }
-
-                    if let Err(error) = storage.insert_properties(&properties).await {
-                        println!("Error writing properties: {}", error);
-                    }
-
-                    {
-                        let edit = edit.clone();
-                        let block = block.clone();
-                        let storage = storage.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            let entities = EntitiesModel::map_edit_to_entities(&edit, &block);
-
-                            if let Err(error) = storage.insert_entities(&entities).await {
-                                eprintln!("Error writing entities: {}", error);
-                            }
-                        }));
-                    }
-
-                    let (created_values, deleted_values) =
-                        ValuesModel::map_edit_to_values(&edit, &space_id);
-
-                    {
-                        let storage = storage.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            // Validate created values against their property data types
-                            let validated_created_values =
-                                validate_created_values(created_values, &cache).await;
-
-                            let write_values_result =
-                                storage.insert_values(&validated_created_values).await;
-
-                            if let Err(error) = write_values_result {
-                                println!("Error writing set values {}", error);
-                            }
-                        }));
-                    }
-
-                    {
-                        let storage = storage.clone();
-                        let space_id = space_id.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            let write_values_result =
-                                storage.delete_values(&deleted_values, &space_id).await;
-
-                            if let Err(error) = write_values_result {
-                                println!("Error writing set values {}", error);
-                            }
-                        }));
-                    }
-
-                    let (
-                        created_relations,
-                        updated_relations,
-                        unset_relations,
-                        deleted_relation_ids,
-                    ) = RelationsModel::map_edit_to_relations(&edit, &space_id);
-
-                    {
-                        let storage = storage.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            let write_relations_result =
-                                storage.insert_relations(&created_relations).await;
-
-                            if let Err(write_error) = write_relations_result {
-                                println!("Error writing relations {}", write_error);
-                            }
-                        }));
-                    }
-
-                    {
-                        let storage = storage.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            let update_relations_result =
-                                storage.update_relations(&updated_relations).await;
-
-                            if let Err(write_error) = update_relations_result {
-                                println!("Error updating relations {}", write_error);
-                            }
-                        }));
-                    }
-
-                    {
-                        let storage = storage.clone();
-
-                        handles.push(tokio::spawn(async move {
-                            let unset_relations_result =
-                                storage.unset_relation_fields(&unset_relations).await;
-
-                            if let Err(write_error) = unset_relations_result {
-                                println!("Error unsetting relation fields {}", write_error);
-                            }
-                        }));
-                    }
-
-                    {
-                        let storage = storage.clone();
+    for (edit_index, preprocessed_edit) in output.iter().enumerate() {
+        let span = info_span!(
+            "edit",
+            content_uri = %preprocessed_edit.content_uri,
+            space_id = %preprocessed_edit.space_id
+        );
+
+        // Errors fail the whole block so it gets rolled back instead of
+        // being committed with part of the edit missing.
+        write_edit(
+            preprocessed_edit,
+            edit_index,
+            block_metadata,
+            storage,
+            properties_cache,
+        )
+        .instrument(span)
+        .await?;
+    }
 
-                        handles.push(tokio::spawn(async move {
-                            let delete_relations_result = storage
-                                .delete_relations(&deleted_relation_ids, &space_id)
-                                .await;
+    Ok(())
+}
 
-                            if let Err(write_error) = delete_relations_result {
-                                println!("Error deleting relations {}", write_error);
-                            }
-                        }));
-                    }
-                }
+/// Writes a single edit. Its writes are applied in a fixed order, so
+/// re-indexing the edit always produces the same rows. The state it leaves
+/// values and relations in is also recorded as a new version of them, and
+/// they're linked to the edit as the last one that touched them.
+async fn write_edit<S, C>(
+    preprocessed_edit: &PreprocessedEdit,
+    edit_index: usize,
+    block: &BlockMetadata,
+    storage: &Arc<S>,
+    cache: &Arc<C>,
+) -> Result<(), IndexingError>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    C: ImmutableCache + Send + Sync + 'static,
+{
+    // The Edit might be malformed. The Cache still stores it with an
+    // is_errored flag to denote that the entry exists but can't be
+    // decoded.
+    if preprocessed_edit.is_errored {
+        return Ok(());
+    }
 
-                join_all(handles).await;
-            }
-        })
-        .await;
+    let Some(edit) = &preprocessed_edit.edit else {
+        return Ok(());
+    };
+    let space_id = preprocessed_edit.space_id;
+
+    // Edits with a malformed id can't be recorded, so nothing written by
+    // them could be versioned or linked to them. We skip them like edits
+    // that can't be decoded.
+    let Some(version) = VersionsModel::map_edit_to_version(edit, block, edit_index) else {
+        return Ok(());
+    };
+
+    let edit_item = EditsModel::map_edit_to_edit_item(
+        edit,
+        &preprocessed_edit.content_uri,
+        &space_id,
+        block,
+        edit_index,
+    );
+    if let Some(edit_item) = &edit_item {
+        storage.insert_edit(edit_item).await?;
+    }
 
-        match handle {
-            Ok(_) => {
-                //
-            }
-            Err(error) => println!(
-                "[Root handler] Error executing task {} for edit {:?}",
-                error, preprocessed_edit
-            ),
-        }
+    // We write properties first to update the cache with any properties
+    // created within the edit. This makes it simpler to do validation
+    // later in the edit handler as the properties cache will already
+    // be up-to-date.
+    let properties = PropertiesModel::map_edit_to_properties(edit);
+
+    // For now we write properties to an in-memory cache that we reference
+    // when validating values in the edit. There's a weird mismatch between
+    // where properties data lives. We store properties on disk in order
+    // to be able to query properties. We need to do this in "real-time" as
+    // our external API depends on being able to query for properties when
+    // querying for values.
+    //
+    // This does mean we write properties in two places, one for the cache,
+    // and one for the queryable store. Eventually I think we want to move
+    // to in-memory for _all_ data stores with a disk-based commit log, but
+    // for now we'll write properties twice.
+    for property in &properties {
+        cache.insert(&property.id, property.data_type).await;
     }
 
+    storage.insert_properties(&properties).await?;
+
+    let entities = EntitiesModel::map_edit_to_entities(edit, block);
+    storage.insert_entities(&entities).await?;
+
+    let (created_values, deleted_values) = ValuesModel::map_edit_to_values(edit, &space_id);
+
+    // Validate created values against their property data types
+    let (validated_created_values, rejected_values) =
+        validate_created_values(created_values, cache).await;
+    storage.insert_values(&validated_created_values).await?;
+    storage
+        .insert_rejected_values(&version, &preprocessed_edit.content_uri, &rejected_values)
+        .await?;
+
+    let set_value_ids: Vec<_> = validated_created_values
+        .iter()
+        .map(|value| value.id)
+        .collect();
+    storage
+        .insert_value_versions(&version, &set_value_ids, &deleted_values)
+        .await?;
+    storage.delete_values(&deleted_values, &space_id).await?;
+
+    let (created_relations, updated_relations, unset_relations, deleted_relation_ids) =
+        RelationsModel::map_edit_to_relations(edit, &space_id);
+
+    storage.insert_relations(&created_relations).await?;
+    storage.update_relations(&updated_relations).await?;
+    storage.unset_relation_fields(&unset_relations).await?;
+
+    let written_relation_ids: Vec<_> = created_relations
+        .iter()
+        .map(|relation| relation.id)
+        .chain(updated_relations.iter().map(|relation| relation.id))
+        .chain(unset_relations.iter().map(|relation| relation.id))
+        .collect();
+    storage
+        .link_edit(
+            edit_item.as_ref().map(|edit_item| &edit_item.id),
+            &set_value_ids,
+            &written_relation_ids,
+        )
+        .await?;
+    storage
+        .insert_relation_versions(&version, &written_relation_ids, &deleted_relation_ids)
+        .await?;
+    storage
+        .delete_relations(&deleted_relation_ids, &space_id)
+        .await?;
+
     Ok(())
 }
 
@@ -259,6 +258,7 @@ mod tests {
     use crate::cache::properties_cache::PropertiesCache;
     use crate::models::properties::DataType;
     use crate::models::values::{ValueChangeType, ValueOp};
+    use crate::validators::Point;
     use std::sync::Arc;
     use uuid::Uuid;
 
@@ -281,11 +281,17 @@ mod tests {
             value: Some("123.45".to_string()),
             language: None,
             unit: None,
+            typed_value: None,
         }];
 
-        let validated = validate_created_values(values, &cache).await;
+        let (validated, rejected) = validate_created_values(values, &cache).await;
         assert_eq!(validated.len(), 1);
         assert_eq!(validated[0].value, Some("123.45".to_string()));
+        assert_eq!(
+            validated[0].typed_value,
+            Some(ValidatedValue::Number(123.45))
+        );
+        assert!(rejected.is_empty());
     }
 
     #[tokio::test]
@@ -308,6 +314,7 @@ mod tests {
                 value: Some("123.45".to_string()), // Valid number
                 language: None,
                 unit: None,
+                typed_value: None,
             },
             ValueOp {
                 id: Uuid::new_v4(),
@@ -318,13 +325,20 @@ mod tests {
                 value: Some("not-a-number".to_string()), // Invalid number
                 language: None,
                 unit: None,
+                typed_value: None,
             },
         ];
 
-        let validated = validate_created_values(values, &cache).await;
+        let (validated, rejected) = validate_created_values(values, &cache).await;
         // Only the valid value should remain
         assert_eq!(validated.len(), 1);
         assert_eq!(validated[0].value, Some("123.45".to_string()));
+
+        // The invalid value is rejected with the data type it should've had
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].value.value, Some("not-a-number".to_string()));
+        assert_eq!(rejected[0].data_type, Some(DataType::Number));
+        assert_eq!(rejected[0].reason.kind(), "InvalidCharacters");
     }
 
     #[tokio::test]
@@ -343,11 +357,13 @@ mod tests {
             value: None, // None values should pass through without validation
             language: None,
             unit: None,
+            typed_value: None,
         }];
 
-        let validated = validate_created_values(values, &cache).await;
+        let (validated, rejected) = validate_created_values(values, &cache).await;
         // None values are filtered out by the current implementation
         assert_eq!(validated.len(), 0);
+        assert!(rejected.is_empty());
     }
 
     #[tokio::test]
@@ -366,11 +382,15 @@ mod tests {
             value: Some("some-value".to_string()),
             language: None,
             unit: None,
+            typed_value: None,
         }];
 
-        let validated = validate_created_values(values, &cache).await;
+        let (validated, rejected) = validate_created_values(values, &cache).await;
         // Value should be filtered out when property not found in cache
         assert_eq!(validated.len(), 0);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].data_type, None);
+        assert_eq!(rejected[0].reason, RejectionReason::PropertyNotFound);
     }
 
     #[tokio::test]
@@ -398,6 +418,7 @@ mod tests {
                 value: Some("Hello World".to_string()), // Valid text
                 language: None,
                 unit: None,
+                typed_value: None,
             },
             ValueOp {
                 id: Uuid::new_v4(),
@@ -408,6 +429,7 @@ mod tests {
                 value: Some("1".to_string()), // Valid checkbox
                 language: None,
                 unit: None,
+                typed_value: None,
             },
             ValueOp {
                 id: Uuid::new_v4(),
@@ -418,6 +440,7 @@ mod tests {
                 value: Some("invalid-checkbox".to_string()), // Invalid checkbox
                 language: None,
                 unit: None,
+                typed_value: None,
             },
             ValueOp {
                 id: Uuid::new_v4(),
@@ -428,12 +451,15 @@ mod tests {
                 value: Some("1.5,2.5".to_string()), // Valid point
                 language: None,
                 unit: None,
+                typed_value: None,
             },
         ];
 
-        let validated = validate_created_values(values, &cache).await;
+        let (validated, rejected) = validate_created_values(values, &cache).await;
         // Should have 3 valid values (text, valid checkbox, point)
         assert_eq!(validated.len(), 3);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].data_type, Some(DataType::Checkbox));
 
         // Verify the specific values that made it through
         let text_values: Vec<_> = validated
@@ -442,6 +468,8 @@ mod tests {
             .collect();
         assert_eq!(text_values.len(), 1);
         assert_eq!(text_values[0].value, Some("Hello World".to_string()));
+        // Text values aren't parsed into a typed value
+        assert_eq!(text_values[0].typed_value, None);
 
         let valid_checkbox_values: Vec<_> = validated
             .iter()
@@ -449,6 +477,10 @@ mod tests {
             .collect();
         assert_eq!(valid_checkbox_values.len(), 1);
         assert_eq!(valid_checkbox_values[0].value, Some("1".to_string()));
+        assert_eq!(
+            valid_checkbox_values[0].typed_value,
+            Some(ValidatedValue::Checkbox(true))
+        );
 
         let point_values: Vec<_> = validated
             .iter()
@@ -456,5 +488,9 @@ mod tests {
             .collect();
         assert_eq!(point_values.len(), 1);
         assert_eq!(point_values[0].value, Some("1.5,2.5".to_string()));
+        assert_eq!(
+            point_values[0].typed_value,
+            Some(ValidatedValue::Point(Point { x: 1.5, y: 2.5 }))
+        );
     }
 }
@@ -9,7 +9,7 @@ use crate::{
     AddedMember, 
     RemovedMember,
 };
-use crate::block_handler::utils::handle_task_result;
+use crate::block_handler::utils::{handle_task_result, spawn_in_current_span};
 
 pub async fn run<S>(
     added_members: &Vec<AddedMember>,
@@ -26,7 +26,7 @@ where
         let storage = Arc::clone(storage);
         let added_members = added_members.clone();
         let removed_members = removed_members.clone();
-        tokio::spawn(async move {
+        spawn_in_current_span(async move {
             // Process added members
             if !added_members.is_empty() {
                 let members_to_add = MembershipModel::map_added_members(&added_members);
@@ -47,7 +47,7 @@ where
         let storage = Arc::clone(storage);
         let added_editors = added_editors.clone();
         let removed_editors = removed_editors.clone();
-        tokio::spawn(async move {
+        spawn_in_current_span(async move {
             // Process added editors
             if !added_editors.is_empty() {
                 let editors_to_add = MembershipModel::map_added_editors(&added_editors);
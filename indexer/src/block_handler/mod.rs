@@ -2,4 +2,5 @@ pub mod edit_handler;
 pub mod membership_handler;
 pub mod root_handler;
 pub mod space_handler;
+pub mod undo_handler;
 pub mod utils;
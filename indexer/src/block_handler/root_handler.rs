@@ -1,13 +1,16 @@
 use std::sync::Arc;
 
 use stream::utils::BlockMetadata;
+use tracing::info;
 
 use crate::block_handler::{
-    edit_handler, membership_handler, space_handler, utils::handle_task_result,
+    edit_handler, membership_handler, space_handler,
+    utils::{handle_task_result, spawn_in_current_span},
 };
 use crate::cache::properties_cache::ImmutableCache;
 
 use crate::error::IndexingError;
+use crate::models::undo::UndoModel;
 use crate::storage::StorageBackend;
 use crate::KgData;
 
@@ -21,17 +24,26 @@ where
     S: StorageBackend + Send + Sync + 'static,
     C: ImmutableCache + Send + Sync + 'static,
 {
-    println!(
+    info!(
         "Block #{} – Drift {}s",
         block_metadata.block_number, block_metadata.timestamp,
     );
 
+    // Snapshot everything this block may write before writing it so the
+    // block can be reverted if it gets reorganized out of the chain.
+    let undo_keys = UndoModel::map_kg_data_to_keys(output);
+    storage
+        .snapshot_block(block_metadata.block_number, &undo_keys)
+        .await?;
+
     let space_task = {
         let storage = Arc::clone(storage);
         let block_metadata = block_metadata.clone();
         let spaces = output.spaces.clone();
 
-        tokio::spawn(async move { space_handler::run(&spaces, &block_metadata, &storage).await })
+        spawn_in_current_span(async move {
+            space_handler::run(&spaces, &block_metadata, &storage).await
+        })
     };
 
     let edit_task = {
@@ -39,7 +51,7 @@ where
         let properties_cache = Arc::clone(properties_cache);
         let block_metadata = block_metadata.clone();
         let edits = output.edits.clone();
-        tokio::spawn(async move {
+        spawn_in_current_span(async move {
             edit_handler::run(&edits, &block_metadata, &storage, &properties_cache).await
         })
     };
@@ -51,7 +63,7 @@ where
         let removed_members = output.removed_members.clone();
         let added_editors = output.added_editors.clone();
         let removed_editors = output.removed_editors.clone();
-        tokio::spawn(async move {
+        spawn_in_current_span(async move {
             membership_handler::run(
                 &added_members,
                 &removed_members,
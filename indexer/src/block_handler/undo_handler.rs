@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use stream::pb::sf::substreams::rpc::v2::BlockUndoSignal;
+use tracing::info;
+
+use crate::cache::properties_cache::ImmutableCache;
+use crate::error::IndexingError;
+use crate::storage::StorageBackend;
+
+/// Reverts everything written after the undo signal's last valid block.
+/// The stream resumes from the last valid block's cursor afterwards, so
+/// the reverted blocks get re-indexed from the new canonical chain.
+pub async fn run<S, C>(
+    undo_signal: &BlockUndoSignal,
+    storage: &Arc<S>,
+    properties_cache: &Arc<C>,
+) -> Result<(), IndexingError>
+where
+    S: StorageBackend + Send + Sync + 'static,
+    C: ImmutableCache + Send + Sync + 'static,
+{
+    let last_valid_block = undo_signal
+        .last_valid_block
+        .as_ref()
+        .map_or(0, |block| block.number);
+
+    info!("Reverting blocks after #{}", last_valid_block);
+
+    let removed_property_ids = storage.revert_blocks_after(last_valid_block).await?;
+
+    for property_id in &removed_property_ids {
+        properties_cache.remove(property_id).await;
+    }
+
+    Ok(())
+}
@@ -1,3 +1,8 @@
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
 use crate::error::IndexingError;
 
 pub fn handle_task_result(
@@ -7,4 +12,15 @@ pub fn handle_task_result(
         Ok(handler_result) => handler_result,
         Err(join_error) => Err(IndexingError::from(join_error)),
     }
-}
\ No newline at end of file
+}
+
+/// Spawns `future` in the current span, e.g., the block or the edit being
+/// processed. Spawned tasks don't otherwise inherit the span they were
+/// spawned from.
+pub fn spawn_in_current_span<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
@@ -39,6 +39,7 @@ impl CacheBackend for PostgresCache {
 
         if is_errored {
             return Ok(PreprocessedEdit {
+                content_uri: uri.clone(),
                 edit: None,
                 is_errored: true,
                 space_id: space,
@@ -50,6 +51,7 @@ impl CacheBackend for PostgresCache {
         let edit = serde_json::from_value::<Edit>(json)?;
 
         Ok(PreprocessedEdit {
+            content_uri: uri.clone(),
             edit: Some(edit),
             is_errored: false,
             space_id: space,
@@ -1,9 +1,16 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::models::properties::DataType;
+use crate::{
+    models::properties::DataType,
+    storage::{postgres::PostgresStorage, StorageError},
+};
 
 pub struct PropertiesCache {
     /// Represents the cache of property id -> data type. We store
@@ -30,6 +37,39 @@ impl PropertiesCache {
             inner: Arc::new(RwLock::new(HashMap::new())),
         }
     }
+
+    /// Loads every property already indexed, `page_size` properties at a
+    /// time. Values are only written if their property is cached, so an
+    /// indexer resuming from a persisted cursor has to know about the
+    /// properties created before it.
+    pub async fn load(storage: &PostgresStorage, page_size: i64) -> Result<Self, StorageError> {
+        let started_at = Instant::now();
+        let cache = Self::new();
+        let mut after_id = None;
+        let mut loaded = 0;
+
+        loop {
+            let page = storage.get_properties_page(after_id, page_size).await?;
+            let Some(last) = page.last() else {
+                break;
+            };
+            after_id = Some(last.id);
+            loaded += page.len();
+
+            let mut write = cache.inner.write().await;
+            for property in page {
+                write.insert(property.id, property.data_type);
+            }
+        }
+
+        tracing::info!(
+            "[PropertiesCache][Load] Loaded {} properties in {:?}",
+            loaded,
+            started_at.elapsed()
+        );
+
+        Ok(cache)
+    }
 }
 
 #[derive(Debug)]
@@ -45,6 +85,10 @@ pub enum PropertiesCacheError {
 pub trait ImmutableCache {
     async fn insert(&self, key: &Uuid, value: DataType);
     async fn get(&self, key: &Uuid) -> Result<DataType, PropertiesCacheError>;
+
+    /// Properties are immutable once created, but a property created in a
+    /// block that gets reverted by a chain reorganization never existed.
+    async fn remove(&self, key: &Uuid);
 }
 
 #[async_trait::async_trait]
@@ -74,6 +118,68 @@ impl ImmutableCache for PropertiesCache {
             None => Err(PropertiesCacheError::PropertyNotFoundError),
         };
     }
+
+    async fn remove(&self, key: &Uuid) {
+        let mut write = self.inner.write().await;
+        write.remove(key);
+    }
+}
+
+/// The properties cache as seen by the writes of a single block. Changes
+/// are made to the shared cache right away so the rest of the block is
+/// validated against them, and are undone if the block is rolled back
+/// instead of committed.
+pub struct BlockPropertiesCache {
+    cache: Arc<PropertiesCache>,
+    /// Each property changed by the block with its data type before the
+    /// change, in the order the changes were made.
+    changes: Mutex<Vec<(Uuid, Option<DataType>)>>,
+}
+
+impl BlockPropertiesCache {
+    pub fn new(cache: Arc<PropertiesCache>) -> Self {
+        Self {
+            cache,
+            changes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Restores the properties changed by the block.
+    pub async fn rollback(self) {
+        let changes = self.changes.into_inner().unwrap();
+
+        for (key, previous) in changes.into_iter().rev() {
+            match previous {
+                Some(value) => self.cache.insert(&key, value).await,
+                None => self.cache.remove(&key).await,
+            }
+        }
+    }
+
+    fn record_change(&self, key: &Uuid, previous: Option<DataType>) {
+        self.changes.lock().unwrap().push((*key, previous));
+    }
+}
+
+#[async_trait::async_trait]
+impl ImmutableCache for BlockPropertiesCache {
+    async fn insert(&self, key: &Uuid, value: DataType) {
+        if self.cache.get(key).await.is_err() {
+            self.cache.insert(key, value).await;
+            self.record_change(key, None);
+        }
+    }
+
+    async fn get(&self, key: &Uuid) -> Result<DataType, PropertiesCacheError> {
+        self.cache.get(key).await
+    }
+
+    async fn remove(&self, key: &Uuid) {
+        if let Ok(previous) = self.cache.get(key).await {
+            self.cache.remove(key).await;
+            self.record_change(key, Some(previous));
+        }
+    }
 }
 
 #[cfg(test)]
@@ -222,6 +328,20 @@ mod tests {
             DataType::Time | DataType::Point | DataType::Relation));
     }
 
+    #[tokio::test]
+    async fn test_remove_allows_reinsert() {
+        let cache = PropertiesCache::new();
+        let key = Uuid::new_v4();
+
+        cache.insert(&key, DataType::Text).await;
+        cache.remove(&key).await;
+        assert!(cache.get(&key).await.is_err());
+
+        // A property reverted by a reorg may be re-created with a different type
+        cache.insert(&key, DataType::Number).await;
+        assert_eq!(cache.get(&key).await.unwrap(), DataType::Number);
+    }
+
     #[tokio::test]
     async fn test_multiple_different_properties() {
         let cache = PropertiesCache::new();
@@ -244,4 +364,38 @@ mod tests {
         let prop4 = Uuid::new_v4();
         assert!(cache.get(&prop4).await.is_err());
     }
+
+    #[tokio::test]
+    async fn test_block_rollback_removes_created_properties() {
+        let cache = Arc::new(PropertiesCache::new());
+        let existing = Uuid::new_v4();
+        let created = Uuid::new_v4();
+        cache.insert(&existing, DataType::Text).await;
+
+        let block = BlockPropertiesCache::new(cache.clone());
+        block.insert(&existing, DataType::Number).await;
+        block.insert(&created, DataType::Number).await;
+        assert_eq!(cache.get(&created).await.unwrap(), DataType::Number);
+
+        block.rollback().await;
+
+        // Only the property created by the block is removed
+        assert_eq!(cache.get(&existing).await.unwrap(), DataType::Text);
+        assert!(cache.get(&created).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_block_rollback_restores_removed_properties() {
+        let cache = Arc::new(PropertiesCache::new());
+        let key = Uuid::new_v4();
+        cache.insert(&key, DataType::Text).await;
+
+        let block = BlockPropertiesCache::new(cache.clone());
+        block.remove(&key).await;
+        assert!(cache.get(&key).await.is_err());
+
+        block.rollback().await;
+
+        assert_eq!(cache.get(&key).await.unwrap(), DataType::Text);
+    }
 }
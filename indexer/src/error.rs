@@ -1,4 +1,5 @@
 use prost::DecodeError;
+use stream::{cursor::CursorError, decoded::OutputError, StreamError};
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -17,4 +18,13 @@ pub enum IndexingError {
 
     #[error("Indexing error: {0}")]
     TaskError(#[from] JoinError),
+
+    #[error("Indexing error: {0}")]
+    CursorError(#[from] CursorError),
+
+    #[error("Indexing error: {0}")]
+    StreamError(#[from] StreamError),
+
+    #[error("Indexing error: {0}")]
+    OutputError(#[from] OutputError),
 }
@@ -4,8 +4,10 @@ use stream::utils::BlockMetadata;
 pub mod block_handler;
 pub mod cache;
 pub mod error;
+pub mod metrics;
 pub mod models;
 pub mod preprocess;
+pub mod sink;
 pub mod storage;
 pub mod validators;
 
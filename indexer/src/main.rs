@@ -1,114 +1,99 @@
 use indexer::{
-    block_handler::root_handler,
     cache::{postgres::PostgresCache, properties_cache::PropertiesCache},
     error::IndexingError,
-    preprocess,
+    sink::KgIndexer,
     storage::postgres::PostgresStorage,
-    KgData,
 };
-use std::{env, sync::Arc};
+use std::env;
 
 use dotenv::dotenv;
-use stream::{pb::sf::substreams::rpc::v2::BlockScopedData, PreprocessedSink};
+use stream::{
+    config::env_var,
+    health::HealthConfig,
+    logging::{init_tracing, LogFormat},
+    shutdown::shutdown_on_signals,
+    EndpointConfig, PreprocessedSink, RunConfig,
+};
+use tracing::{error, info};
 
 const PKG_FILE: &str = "geo_substream.spkg";
 const MODULE_NAME: &str = "geo_out";
 const START_BLOCK: i64 = 53965;
-
-struct KgIndexer {
-    storage: Arc<PostgresStorage>,
-    ipfs_cache: Arc<PostgresCache>,
-    properties_cache: Arc<PropertiesCache>,
-}
-
-impl KgIndexer {
-    pub fn new(
-        storage: PostgresStorage,
-        ipfs_cache: PostgresCache,
-        properties_cache: PropertiesCache,
-    ) -> Self {
-        KgIndexer {
-            storage: Arc::new(storage),
-            ipfs_cache: Arc::new(ipfs_cache),
-            properties_cache: Arc::new(properties_cache),
-        }
-    }
-}
-
-impl PreprocessedSink<KgData> for KgIndexer {
-    type Error = IndexingError;
-
-    async fn load_persisted_cursor(&self) -> Result<Option<String>, Self::Error> {
-        Ok(Some("".to_string()))
-    }
-
-    async fn persist_cursor(&self, _cursor: String) -> Result<(), Self::Error> {
-        Ok(())
-    }
-
-    /**
-    We can pre-process any edits we care about in the chain in this separate function.
-    There's lots of decoding steps and filtering done to the Knowledge Graphs events
-    so it's helpful to do this decoding/filtering/data-fetching ahead of time so the
-    process steps can focus purely on mapping and writing data to the sink.
-    */
-    async fn preprocess_block_scoped_data(
-        &self,
-        block_data: &BlockScopedData,
-    ) -> Result<KgData, Self::Error> {
-        let kg_data =
-            preprocess::preprocess_block_scoped_data(block_data, &self.ipfs_cache).await?;
-
-        Ok(kg_data)
-    }
-
-    async fn process_block_scoped_data(
-        &self,
-        _block_data: &BlockScopedData,
-        decoded_data: KgData,
-    ) -> Result<(), Self::Error> {
-        // @TODO: Need to figure out to abstract the different types of streams so
-        // people can write their own sinks over specific events however they want.
-        //
-        // One idea is implementing the decoding at the stream level, so anybody
-        // consuming the stream just gets the block data + the already-decoded contents
-        // of each event.
-        //
-        // async fn process_block(&self, block_data: &DecodedBlockData, _raw_block_data: &BlockScopedData);
-        root_handler::run(
-            &decoded_data,
-            &decoded_data.block,
-            &self.storage,
-            &self.properties_cache,
-        )
-        .await?;
-
-        Ok(())
-    }
-}
+const PREPROCESS_LOOKAHEAD: usize = 8;
+const METRICS_ADDR: &str = "0.0.0.0:9090";
+const PROPERTIES_PAGE_SIZE: i64 = 10_000;
 
 #[tokio::main]
 async fn main() -> Result<(), IndexingError> {
     dotenv().ok();
+    init_tracing(LogFormat::from_env());
+
+    let metrics_addr = env::var("METRICS_ADDR")
+        .unwrap_or_else(|_| METRICS_ADDR.to_string())
+        .parse()
+        .expect("METRICS_ADDR must be a socket address");
 
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
     let storage = PostgresStorage::new(&database_url).await;
 
     match storage {
         Ok(result) => {
+            // Value IDs used to be derived with a hash that isn't stable
+            // across Rust releases. Databases indexed before then are
+            // migrated once before indexing on top of them.
+            if env::var("MIGRATE_VALUE_IDS").is_ok_and(|value| value == "true") {
+                let migrated = result.migrate_value_ids().await?;
+                info!("Migrated {} value IDs", migrated);
+            }
+
+            // Metrics and the health probes are served on the same address.
+            let router = stream::metrics::router().merge(stream::health::router(
+                HealthConfig::from_env(),
+                Some(result.pool.clone()),
+            ));
+            tokio::spawn(async move {
+                if let Err(error) = stream::metrics::serve(metrics_addr, router).await {
+                    error!("Error serving metrics {}", error);
+                }
+            });
+
             let cache = PostgresCache::new().await?;
-            let properties_cache = PropertiesCache::new();
+            // Resuming from a persisted cursor needs the properties that were
+            // indexed before it to validate values against.
+            let properties_cache = PropertiesCache::load(&result, PROPERTIES_PAGE_SIZE).await?;
             let indexer = KgIndexer::new(result, cache, properties_cache);
 
-            let endpoint_url =
-                env::var("SUBSTREAMS_ENDPOINT").expect("SUBSTREAMS_ENDPOINT not set");
-
-            let _result = indexer
-                .run(&endpoint_url, PKG_FILE, MODULE_NAME, START_BLOCK, 0)
-                .await;
+            // Replaying a recording doesn't need an endpoint.
+            let replay_file = env_var("SUBSTREAMS_REPLAY_FILE");
+            let endpoints = EndpointConfig::from_env();
+            if replay_file.is_none() && endpoints.is_empty() {
+                panic!("SUBSTREAMS_ENDPOINT not set");
+            }
+
+            // The indexer reverts reorganized blocks itself so it can
+            // stream head blocks.
+            let config = RunConfig {
+                start_block: START_BLOCK,
+                final_blocks_only: false,
+                // Preprocessing mostly waits on IPFS cache reads so we run
+                // it ahead of the block being written.
+                preprocess_lookahead: PREPROCESS_LOOKAHEAD,
+                record_file: env_var("SUBSTREAMS_RECORD_FILE"),
+                replay_file,
+                package_cache_dir: env_var("SUBSTREAMS_PACKAGE_CACHE_DIR"),
+                expected_module_hash: env_var("SUBSTREAMS_MODULE_HASH"),
+                // Stops taking new blocks on SIGINT or SIGTERM, e.g., during a
+                // deploy, and returns once the blocks in flight are written.
+                shutdown: shutdown_on_signals(),
+                ..RunConfig::new(endpoints, PKG_FILE, MODULE_NAME)
+            };
+
+            // Errors are returned so the process exits with a failure and
+            // can be restarted by whatever supervises it.
+            indexer.run(&config).await?;
         }
         Err(error) => {
-            println!("Error initializing stream {}", error);
+            error!("Error initializing stream {}", error);
         }
     }
 
@@ -0,0 +1,124 @@
+use lazy_static::lazy_static;
+use prometheus::{IntCounterVec, Opts, Registry};
+
+use crate::models::properties::DataType;
+
+lazy_static! {
+    pub static ref METRICS: IndexerMetrics =
+        IndexerMetrics::register(prometheus::default_registry()).unwrap();
+}
+
+/// The metrics recorded by the indexer. `METRICS` registers them with the
+/// default registry.
+pub struct IndexerMetrics {
+    pub rows_written: IntCounterVec,
+    pub validation_rejections: IntCounterVec,
+    pub ipfs_cache_reads: IntCounterVec,
+}
+
+impl IndexerMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = IndexerMetrics {
+            rows_written: IntCounterVec::new(
+                Opts::new(
+                    "indexer_rows_written_total",
+                    "Rows inserted, updated or deleted by the indexer",
+                ),
+                &["table"],
+            )?,
+            validation_rejections: IntCounterVec::new(
+                Opts::new(
+                    "indexer_validation_rejections_total",
+                    "Values skipped because they don't match their property's data type",
+                ),
+                &["data_type"],
+            )?,
+            ipfs_cache_reads: IntCounterVec::new(
+                Opts::new(
+                    "indexer_ipfs_cache_reads_total",
+                    "Reads from the IPFS cache, retried while the cache catches up",
+                ),
+                &["result"],
+            )?,
+        };
+
+        registry.register(Box::new(metrics.rows_written.clone()))?;
+        registry.register(Box::new(metrics.validation_rejections.clone()))?;
+        registry.register(Box::new(metrics.ipfs_cache_reads.clone()))?;
+
+        Ok(metrics)
+    }
+
+    fn record_rows_written(&self, table: &str, rows: u64) {
+        self.rows_written.with_label_values(&[table]).inc_by(rows);
+    }
+
+    fn record_validation_rejection(&self, data_type: DataType) {
+        self.validation_rejections
+            .with_label_values(&[data_type.as_ref()])
+            .inc();
+    }
+
+    fn record_ipfs_cache_read(&self, attempt: usize, hit: bool) {
+        let result = match (attempt, hit) {
+            (_, true) => "hit",
+            (0, false) => "miss",
+            (_, false) => "retry",
+        };
+
+        self.ipfs_cache_reads.with_label_values(&[result]).inc();
+    }
+}
+
+pub fn record_rows_written(table: &str, rows: u64) {
+    METRICS.record_rows_written(table, rows);
+}
+
+pub fn record_validation_rejection(data_type: DataType) {
+    METRICS.record_validation_rejection(data_type);
+}
+
+/// Records a read of the IPFS cache, counting reads after the first one for
+/// the same edit as retries.
+pub fn record_ipfs_cache_read(attempt: usize, hit: bool) {
+    METRICS.record_ipfs_cache_read(attempt, hit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_ipfs_cache_reads() {
+        let metrics = IndexerMetrics::register(&Registry::new()).unwrap();
+
+        metrics.record_ipfs_cache_read(0, false);
+        metrics.record_ipfs_cache_read(1, false);
+        metrics.record_ipfs_cache_read(2, true);
+        metrics.record_ipfs_cache_read(0, true);
+
+        let reads = |result: &str| metrics.ipfs_cache_reads.with_label_values(&[result]).get();
+        assert_eq!(reads("hit"), 2);
+        assert_eq!(reads("miss"), 1);
+        assert_eq!(reads("retry"), 1);
+    }
+
+    #[test]
+    fn test_records_validation_rejections_by_data_type() {
+        let metrics = IndexerMetrics::register(&Registry::new()).unwrap();
+
+        metrics.record_validation_rejection(DataType::Checkbox);
+        metrics.record_validation_rejection(DataType::Checkbox);
+        metrics.record_validation_rejection(DataType::Number);
+
+        let rejections = |data_type: &str| {
+            metrics
+                .validation_rejections
+                .with_label_values(&[data_type])
+                .get()
+        };
+        assert_eq!(rejections("Checkbox"), 2);
+        assert_eq!(rejections("Number"), 1);
+        assert_eq!(rejections("Time"), 0);
+    }
+}
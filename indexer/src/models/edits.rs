@@ -0,0 +1,124 @@
+use grc20::pb::grc20::{op::Payload, Edit};
+use indexer_utils::id;
+use serde::{Deserialize, Serialize};
+use stream::utils::BlockMetadata;
+use uuid::Uuid;
+
+/// An edit published to a space, along with the block it was published in.
+#[derive(Clone, Debug)]
+pub struct EditItem {
+    pub id: Uuid,
+    pub name: String,
+    pub content_uri: String,
+    pub space_id: Uuid,
+    pub edit_index: i32,
+    pub block_number: u64,
+    pub block_timestamp: String,
+    pub authors: Vec<Uuid>,
+    pub language: Option<Uuid>,
+    pub op_counts: OpCounts,
+}
+
+/// How many ops of each kind an edit contains.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OpCounts {
+    pub update_entity: i32,
+    pub create_relation: i32,
+    pub update_relation: i32,
+    pub delete_relation: i32,
+    pub create_property: i32,
+    pub unset_entity_values: i32,
+    pub unset_relation_fields: i32,
+}
+
+impl OpCounts {
+    pub fn total(&self) -> i32 {
+        self.update_entity
+            + self.create_relation
+            + self.update_relation
+            + self.delete_relation
+            + self.create_property
+            + self.unset_entity_values
+            + self.unset_relation_fields
+    }
+}
+
+pub struct EditsModel;
+
+impl EditsModel {
+    /// Maps the edit at `edit_index` in the block's edits. Edits with a
+    /// malformed id can't be referenced by the rows they write so they
+    /// aren't mapped.
+    pub fn map_edit_to_edit_item(
+        edit: &Edit,
+        content_uri: &str,
+        space_id: &Uuid,
+        block: &BlockMetadata,
+        edit_index: usize,
+    ) -> Option<EditItem> {
+        let Ok(edit_id_bytes) = id::transform_id_bytes(edit.id.clone()) else {
+            tracing::error!(
+                "[Edits] Could not transform Vec<u8> for edit.id {:?}",
+                &edit.id
+            );
+            return None;
+        };
+
+        let language = edit.language.as_ref().and_then(|language| {
+            match id::transform_id_bytes(language.clone()) {
+                Ok(bytes) => Some(Uuid::from_bytes(bytes)),
+                Err(_) => {
+                    tracing::error!(
+                        "[Edits] Could not transform Vec<u8> for edit.language {:?}",
+                        language
+                    );
+                    None
+                }
+            }
+        });
+
+        let mut op_counts = OpCounts::default();
+        for op in &edit.ops {
+            match &op.payload {
+                Some(Payload::UpdateEntity(_)) => op_counts.update_entity += 1,
+                Some(Payload::CreateRelation(_)) => op_counts.create_relation += 1,
+                Some(Payload::UpdateRelation(_)) => op_counts.update_relation += 1,
+                Some(Payload::DeleteRelation(_)) => op_counts.delete_relation += 1,
+                Some(Payload::CreateProperty(_)) => op_counts.create_property += 1,
+                Some(Payload::UnsetEntityValues(_)) => op_counts.unset_entity_values += 1,
+                Some(Payload::UnsetRelationFields(_)) => op_counts.unset_relation_fields += 1,
+                None => {}
+            }
+        }
+
+        Some(EditItem {
+            id: Uuid::from_bytes(edit_id_bytes),
+            name: edit.name.clone(),
+            content_uri: content_uri.to_string(),
+            space_id: *space_id,
+            edit_index: edit_index as i32,
+            block_number: block.block_number,
+            block_timestamp: block.timestamp.clone(),
+            authors: Self::map_authors(edit),
+            language,
+            op_counts,
+        })
+    }
+
+    /// Maps the ids of the edit's authors, skipping malformed ones.
+    pub fn map_authors(edit: &Edit) -> Vec<Uuid> {
+        edit.authors
+            .iter()
+            .filter_map(|author| match id::transform_id_bytes(author.clone()) {
+                Ok(bytes) => Some(Uuid::from_bytes(bytes)),
+                Err(_) => {
+                    tracing::error!(
+                        "[Edits] Could not transform Vec<u8> for edit.authors {:?}",
+                        author
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,92 @@
+use crate::models::edits::{EditsModel, OpCounts};
+use grc20::pb::grc20::{op::Payload, Edit, Entity, Op};
+use stream::utils::BlockMetadata;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_block() -> BlockMetadata {
+        BlockMetadata {
+            cursor: String::from("cursor"),
+            block_number: 42,
+            timestamp: String::from("1700000000"),
+        }
+    }
+
+    fn make_update_entity_op() -> Op {
+        Op {
+            payload: Some(Payload::UpdateEntity(Entity {
+                id: Uuid::new_v4().as_bytes().to_vec(),
+                values: vec![],
+            })),
+        }
+    }
+
+    #[test]
+    fn test_map_edit_to_edit_item() {
+        let edit_id = Uuid::new_v4();
+        let language = Uuid::new_v4();
+        let space_id = Uuid::new_v4();
+        let author = Uuid::new_v4();
+        let edit = Edit {
+            id: edit_id.as_bytes().to_vec(),
+            name: String::from("Add people"),
+            ops: vec![
+                make_update_entity_op(),
+                make_update_entity_op(),
+                Op {
+                    payload: Some(Payload::DeleteRelation(Uuid::new_v4().as_bytes().to_vec())),
+                },
+            ],
+            authors: vec![author.as_bytes().to_vec(), vec![0xab; 20]],
+            language: Some(language.as_bytes().to_vec()),
+        };
+
+        let item =
+            EditsModel::map_edit_to_edit_item(&edit, "ipfs://edit", &space_id, &make_block(), 2)
+                .unwrap();
+
+        assert_eq!(item.id, edit_id);
+        assert_eq!(item.name, "Add people");
+        assert_eq!(item.content_uri, "ipfs://edit");
+        assert_eq!(item.space_id, space_id);
+        assert_eq!(item.edit_index, 2);
+        assert_eq!(item.block_number, 42);
+        assert_eq!(item.block_timestamp, "1700000000");
+        // Malformed authors are skipped.
+        assert_eq!(item.authors, vec![author]);
+        assert_eq!(item.language, Some(language));
+        assert_eq!(
+            item.op_counts,
+            OpCounts {
+                update_entity: 2,
+                delete_relation: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(item.op_counts.total(), 3);
+    }
+
+    #[test]
+    fn test_skips_edit_with_malformed_id() {
+        let edit = Edit {
+            id: vec![1, 2, 3],
+            name: String::from("Edit"),
+            ops: vec![],
+            authors: vec![],
+            language: Some(vec![1, 2, 3]),
+        };
+
+        let item = EditsModel::map_edit_to_edit_item(
+            &edit,
+            "ipfs://edit",
+            &Uuid::new_v4(),
+            &make_block(),
+            0,
+        );
+
+        assert!(item.is_none());
+    }
+}
@@ -1,9 +1,15 @@
+pub mod edits;
 pub mod entities;
 pub mod membership;
 pub mod properties;
 pub mod relations;
 pub mod spaces;
+pub mod undo;
 pub mod values;
+pub mod versions;
+
+#[cfg(test)]
+mod edits_test;
 
 #[cfg(test)]
 mod membership_test;
@@ -11,5 +17,11 @@ mod membership_test;
 #[cfg(test)]
 mod relations_test;
 
+#[cfg(test)]
+mod undo_test;
+
 #[cfg(test)]
 mod values_test;
+
+#[cfg(test)]
+mod versions_test;
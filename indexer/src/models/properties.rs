@@ -1,6 +1,6 @@
 use grc20::pb::grc20::{op::Payload, DataType as PbDataType, Edit};
 use indexer_utils::id;
-use std::collections::HashMap;
+use indexmap::IndexMap;
 use std::fmt;
 use uuid::Uuid;
 
@@ -160,7 +160,7 @@ impl PropertiesModel {
 }
 
 fn squash_properties(properties: &Vec<PropertyItem>) -> Vec<PropertyItem> {
-    let mut hash = HashMap::new();
+    let mut hash = IndexMap::new();
 
     for property in properties {
         hash.insert(property.id.clone(), property.clone());
@@ -1,7 +1,6 @@
-use std::collections::HashMap;
-
 use grc20::pb::grc20::{op::Payload, Edit};
 use indexer_utils::id;
+use indexmap::IndexMap;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
@@ -429,7 +428,7 @@ impl RelationsModel {
 }
 
 fn squash_relations(relation_ops: &Vec<RelationItem>) -> Vec<RelationItem> {
-    let mut hash: HashMap<Uuid, RelationItem> = HashMap::new();
+    let mut hash: IndexMap<Uuid, RelationItem> = IndexMap::new();
 
     for op in relation_ops {
         let seen = hash.get(op.id());
@@ -0,0 +1,115 @@
+use std::collections::BTreeSet;
+
+use uuid::Uuid;
+
+use crate::{
+    models::{
+        entities::EntitiesModel,
+        membership::{EditorItem, MemberItem, MembershipModel},
+        properties::PropertiesModel,
+        relations::RelationsModel,
+        spaces::SpacesModel,
+        values::ValuesModel,
+    },
+    KgData,
+};
+
+/// The keys of every row a block may write to. Before a block is written
+/// we snapshot the current state of these rows into the undo log so the
+/// block can be reverted if the chain reorganizes.
+///
+/// Keys are a superset of what actually gets written, e.g., values that
+/// fail validation are still included. Snapshotting a row that isn't
+/// touched by the block is harmless since reverting it is a no-op.
+#[derive(Clone, Debug, Default)]
+pub struct BlockUndoKeys {
+    pub entity_ids: Vec<Uuid>,
+    pub property_ids: Vec<Uuid>,
+    pub value_ids: Vec<Uuid>,
+    pub relation_ids: Vec<Uuid>,
+    pub space_ids: Vec<Uuid>,
+    pub members: Vec<MemberItem>,
+    pub editors: Vec<EditorItem>,
+}
+
+impl BlockUndoKeys {
+    pub fn is_empty(&self) -> bool {
+        self.entity_ids.is_empty()
+            && self.property_ids.is_empty()
+            && self.value_ids.is_empty()
+            && self.relation_ids.is_empty()
+            && self.space_ids.is_empty()
+            && self.members.is_empty()
+            && self.editors.is_empty()
+    }
+}
+
+pub struct UndoModel;
+
+impl UndoModel {
+    /// Maps the block's data to the keys of every row the block handlers
+    /// may write to. This uses the same models as the block handlers so
+    /// the keys always line up with what gets written.
+    pub fn map_kg_data_to_keys(output: &KgData) -> BlockUndoKeys {
+        let mut entity_ids = BTreeSet::new();
+        let mut property_ids = BTreeSet::new();
+        let mut value_ids = BTreeSet::new();
+        let mut relation_ids = BTreeSet::new();
+
+        for preprocessed_edit in &output.edits {
+            if preprocessed_edit.is_errored {
+                continue;
+            }
+
+            let Some(edit) = &preprocessed_edit.edit else {
+                continue;
+            };
+
+            let space_id = preprocessed_edit.space_id;
+
+            for entity in EntitiesModel::map_edit_to_entities(edit, &output.block) {
+                entity_ids.insert(entity.id);
+            }
+
+            for property in PropertiesModel::map_edit_to_properties(edit) {
+                property_ids.insert(property.id);
+            }
+
+            let (created_values, deleted_values) = ValuesModel::map_edit_to_values(edit, &space_id);
+            value_ids.extend(created_values.iter().map(|value| value.id));
+            value_ids.extend(deleted_values);
+
+            let (created_relations, updated_relations, unset_relations, deleted_relation_ids) =
+                RelationsModel::map_edit_to_relations(edit, &space_id);
+            relation_ids.extend(created_relations.iter().map(|relation| relation.id));
+            relation_ids.extend(updated_relations.iter().map(|relation| relation.id));
+            relation_ids.extend(unset_relations.iter().map(|relation| relation.id));
+            relation_ids.extend(deleted_relation_ids);
+        }
+
+        let space_ids = SpacesModel::map_created_spaces(&output.spaces)
+            .into_iter()
+            .map(|space| space.id)
+            .collect();
+
+        let mut members = MembershipModel::map_added_members(&output.added_members);
+        members.extend(MembershipModel::map_removed_members(
+            &output.removed_members,
+        ));
+
+        let mut editors = MembershipModel::map_added_editors(&output.added_editors);
+        editors.extend(MembershipModel::map_removed_editors(
+            &output.removed_editors,
+        ));
+
+        BlockUndoKeys {
+            entity_ids: entity_ids.into_iter().collect(),
+            property_ids: property_ids.into_iter().collect(),
+            value_ids: value_ids.into_iter().collect(),
+            relation_ids: relation_ids.into_iter().collect(),
+            space_ids,
+            members,
+            editors,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::cache::PreprocessedEdit;
+use crate::models::undo::UndoModel;
+use crate::{AddedMember, KgData, RemovedMember};
+use grc20::pb::grc20::{op::Payload, Edit, Entity, Op, Value};
+use stream::utils::BlockMetadata;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_edit(ops: Vec<Op>) -> Edit {
+        Edit {
+            id: Uuid::parse_str("f47ac10b-58cc-4372-a567-0e02b2c3d479")
+                .unwrap()
+                .as_bytes()
+                .to_vec(),
+            name: "Test Edit".to_string(),
+            ops,
+            authors: vec![Uuid::parse_str("f47ac10b-58cc-4372-a567-0e02b2c3d480")
+                .unwrap()
+                .as_bytes()
+                .to_vec()],
+            language: None,
+        }
+    }
+
+    fn create_update_entity_op(entity_id: &Uuid, property_id: &Uuid) -> Op {
+        Op {
+            payload: Some(Payload::UpdateEntity(Entity {
+                id: entity_id.as_bytes().to_vec(),
+                values: vec![Value {
+                    property: property_id.as_bytes().to_vec(),
+                    value: "value".to_string(),
+                    options: None,
+                }],
+            })),
+        }
+    }
+
+    fn create_kg_data(edits: Vec<PreprocessedEdit>) -> KgData {
+        KgData {
+            block: BlockMetadata {
+                cursor: "1".to_string(),
+                block_number: 1,
+                timestamp: "1".to_string(),
+            },
+            edits,
+            spaces: vec![],
+            added_members: vec![],
+            removed_members: vec![],
+            added_editors: vec![],
+            removed_editors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_map_kg_data_to_keys_empty() {
+        let keys = UndoModel::map_kg_data_to_keys(&create_kg_data(vec![]));
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn test_map_kg_data_to_keys_edit() {
+        let entity_id = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440001").unwrap();
+        let property_id = Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c1").unwrap();
+
+        // The same entity is written twice but should only be snapshotted once
+        let edit = create_test_edit(vec![
+            create_update_entity_op(&entity_id, &property_id),
+            create_update_entity_op(&entity_id, &property_id),
+        ]);
+
+        let keys = UndoModel::map_kg_data_to_keys(&create_kg_data(vec![PreprocessedEdit {
+            content_uri: String::new(),
+            edit: Some(edit),
+            is_errored: false,
+            space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap(),
+        }]));
+
+        // Properties referenced by values are written as entities as well
+        assert_eq!(keys.entity_ids.len(), 2);
+        assert!(keys.entity_ids.contains(&entity_id));
+        assert!(keys.entity_ids.contains(&property_id));
+        assert_eq!(keys.value_ids.len(), 1);
+        assert!(keys.relation_ids.is_empty());
+    }
+
+    #[test]
+    fn test_map_kg_data_to_keys_skips_errored_edits() {
+        let keys = UndoModel::map_kg_data_to_keys(&create_kg_data(vec![PreprocessedEdit {
+            content_uri: String::new(),
+            edit: None,
+            is_errored: true,
+            space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap(),
+        }]));
+
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn test_map_kg_data_to_keys_membership() {
+        let dao_address = "0x1234567890123456789012345678901234567890";
+        let editor_address = "0xabcdefabcdefabcdefabcdefabcdefabcdefabcd";
+
+        let mut kg_data = create_kg_data(vec![]);
+        kg_data.added_members = vec![AddedMember {
+            dao_address: dao_address.to_string(),
+            editor_address: editor_address.to_string(),
+        }];
+        kg_data.removed_editors = vec![RemovedMember {
+            dao_address: dao_address.to_string(),
+            editor_address: editor_address.to_string(),
+        }];
+
+        let keys = UndoModel::map_kg_data_to_keys(&kg_data);
+
+        assert_eq!(keys.members.len(), 1);
+        assert_eq!(keys.editors.len(), 1);
+        assert_eq!(keys.members[0].space_id, keys.editors[0].space_id);
+    }
+}
@@ -1,10 +1,13 @@
 use grc20::pb::grc20::{op::Payload, options, Edit, Op};
 use indexer_utils::id;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use indexmap::IndexMap;
 use uuid::Uuid;
 
+use crate::{
+    models::properties::DataType,
+    validators::{ValidatedValue, ValidationError},
+};
+
 #[derive(Clone)]
 pub enum ValueChangeType {
     SET,
@@ -21,6 +24,46 @@ pub struct ValueOp {
     pub value: Option<String>,
     pub language: Option<String>,
     pub unit: Option<String>,
+    /// The value parsed according to its property's data type, set once the
+    /// value is validated. Only data types with a typed column, i.e., every
+    /// data type but Text and Relation, have one.
+    pub typed_value: Option<ValidatedValue>,
+}
+
+/// Why a value set by an edit was rejected instead of written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RejectionReason {
+    /// The value's property wasn't created before the value was set.
+    PropertyNotFound,
+    /// The value doesn't match its property's data type.
+    Invalid(ValidationError),
+}
+
+impl RejectionReason {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RejectionReason::PropertyNotFound => "PropertyNotFound",
+            RejectionReason::Invalid(error) => error.kind(),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectionReason::PropertyNotFound => write!(f, "Property not found"),
+            RejectionReason::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// A value that was rejected, along with the data type it was expected to
+/// have if its property is known.
+#[derive(Clone)]
+pub struct RejectedValue {
+    pub value: ValueOp,
+    pub data_type: Option<DataType>,
+    pub reason: RejectionReason,
 }
 
 pub struct ValuesModel;
@@ -52,7 +95,9 @@ impl ValuesModel {
 }
 
 fn squash_values(triple_ops: &Vec<ValueOp>) -> Vec<ValueOp> {
-    let mut hash = HashMap::new();
+    // Squashed ops keep the position of the first op on their id so values
+    // are written in the same order every time the edit is indexed.
+    let mut hash = IndexMap::new();
 
     for op in triple_ops {
         hash.insert(op.id, op.clone());
@@ -63,21 +108,6 @@ fn squash_values(triple_ops: &Vec<ValueOp>) -> Vec<ValueOp> {
     return result;
 }
 
-fn derive_value_id(entity_id: &Uuid, property_id: &Uuid, space_id: &Uuid) -> Uuid {
-    let mut hasher = DefaultHasher::new();
-    entity_id.hash(&mut hasher);
-    property_id.hash(&mut hasher);
-    space_id.hash(&mut hasher);
-    let hash_value = hasher.finish();
-
-    // Create a deterministic UUID from the hash
-    let mut bytes = [0u8; 16];
-    bytes[0..8].copy_from_slice(&hash_value.to_be_bytes());
-    bytes[8..16].copy_from_slice(&hash_value.to_be_bytes());
-
-    Uuid::from_bytes(bytes)
-}
-
 fn value_op_from_op(op: &Op, space_id: &Uuid) -> Vec<ValueOp> {
     let mut values = Vec::new();
 
@@ -106,7 +136,7 @@ fn value_op_from_op(op: &Op, space_id: &Uuid) -> Vec<ValueOp> {
                             let (language, unit) = extract_options(&value.options);
 
                             values.push(ValueOp {
-                                id: derive_value_id(&entity_id, &property_id, space_id),
+                                id: id::derive_value_id(&entity_id, &property_id, space_id),
                                 change_type: ValueChangeType::SET,
                                 property_id,
                                 entity_id,
@@ -114,6 +144,7 @@ fn value_op_from_op(op: &Op, space_id: &Uuid) -> Vec<ValueOp> {
                                 value: Some(value.value.clone()),
                                 language,
                                 unit,
+                                typed_value: None,
                             });
                         }
                     }
@@ -146,7 +177,7 @@ fn value_op_from_op(op: &Op, space_id: &Uuid) -> Vec<ValueOp> {
                                 Uuid::from_bytes(property_id_bytes.unwrap());
 
                             values.push(ValueOp {
-                                id: derive_value_id(&entity_id, &property_id, space_id),
+                                id: id::derive_value_id(&entity_id, &property_id, space_id),
                                 change_type: ValueChangeType::DELETE,
                                 property_id,
                                 entity_id,
@@ -154,6 +185,7 @@ fn value_op_from_op(op: &Op, space_id: &Uuid) -> Vec<ValueOp> {
                                 value: None,
                                 language: None,
                                 unit: None,
+                                typed_value: None,
                             });
                         }
                     },
@@ -0,0 +1,55 @@
+use grc20::pb::grc20::Edit;
+use indexer_utils::id;
+use stream::utils::BlockMetadata;
+use uuid::Uuid;
+
+use crate::models::{edits::EditsModel, relations::SetRelationItem, values::ValueOp};
+
+/// The edit values and relations are versioned by. Versions are ordered by
+/// the block they were written in and the position of their edit within the
+/// block, which is the order edits are applied in.
+#[derive(Clone, Debug)]
+pub struct EditVersion {
+    pub edit_id: Uuid,
+    pub edit_index: i32,
+    pub block_number: u64,
+    pub block_timestamp: String,
+    pub authors: Vec<Uuid>,
+}
+
+/// The values and relations of an entity as of a given block or edit.
+#[derive(Clone)]
+pub struct EntityVersion {
+    pub id: Uuid,
+    pub values: Vec<ValueOp>,
+    pub relations: Vec<SetRelationItem>,
+}
+
+pub struct VersionsModel;
+
+impl VersionsModel {
+    /// Maps the edit at `edit_index` in the block's edits to the version its
+    /// writes are recorded under. Edits with a malformed id can't be
+    /// versioned, like they can't be recorded by `EditsModel`.
+    pub fn map_edit_to_version(
+        edit: &Edit,
+        block: &BlockMetadata,
+        edit_index: usize,
+    ) -> Option<EditVersion> {
+        let Ok(edit_id_bytes) = id::transform_id_bytes(edit.id.clone()) else {
+            tracing::error!(
+                "[Versions] Could not transform Vec<u8> for edit.id {:?}",
+                &edit.id
+            );
+            return None;
+        };
+
+        Some(EditVersion {
+            edit_id: Uuid::from_bytes(edit_id_bytes),
+            edit_index: edit_index as i32,
+            block_number: block.block_number,
+            block_timestamp: block.timestamp.clone(),
+            authors: EditsModel::map_authors(edit),
+        })
+    }
+}
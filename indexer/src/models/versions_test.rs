@@ -0,0 +1,54 @@
+use crate::models::versions::VersionsModel;
+use grc20::pb::grc20::Edit;
+use stream::utils::BlockMetadata;
+use uuid::Uuid;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_edit_to_version() {
+        let edit_id = Uuid::new_v4();
+        let author = Uuid::new_v4();
+        let edit = Edit {
+            id: edit_id.as_bytes().to_vec(),
+            name: String::from("Edit"),
+            ops: vec![],
+            authors: vec![author.as_bytes().to_vec(), vec![1, 2, 3]],
+            language: None,
+        };
+        let block = BlockMetadata {
+            cursor: String::from("cursor"),
+            block_number: 42,
+            timestamp: String::from("1700000000"),
+        };
+
+        let version = VersionsModel::map_edit_to_version(&edit, &block, 3).unwrap();
+
+        assert_eq!(version.edit_id, edit_id);
+        assert_eq!(version.edit_index, 3);
+        assert_eq!(version.block_number, 42);
+        assert_eq!(version.block_timestamp, "1700000000");
+        // Malformed authors are skipped
+        assert_eq!(version.authors, vec![author]);
+    }
+
+    #[test]
+    fn test_map_edit_to_version_skips_malformed_edit_ids() {
+        let edit = Edit {
+            id: vec![1, 2, 3],
+            name: String::from("Edit"),
+            ops: vec![],
+            authors: vec![],
+            language: None,
+        };
+        let block = BlockMetadata {
+            cursor: String::from("cursor"),
+            block_number: 42,
+            timestamp: String::from("1700000000"),
+        };
+
+        assert!(VersionsModel::map_edit_to_version(&edit, &block, 0).is_none());
+    }
+}
@@ -1,10 +1,8 @@
-use futures::future::join_all;
 use grc20::pb::chain::GeoOutput;
 use indexer_utils::get_blocklist;
-use prost::Message;
 use std::{collections::HashSet, sync::Arc};
-use stream::pb::sf::substreams::rpc::v2::BlockScopedData;
-use tokio::{sync::Mutex, task};
+use stream::utils::BlockMetadata;
+use tokio::task;
 use tokio_retry::{
     strategy::{jitter, ExponentialBackoff},
     Retry,
@@ -13,7 +11,7 @@ use tokio_retry::{
 use crate::{
     cache::{postgres::PostgresCache, CacheBackend, PreprocessedEdit},
     error::IndexingError,
-    AddedMember, CreatedSpace, KgData, PersonalSpace, PublicSpace,
+    metrics, AddedMember, CreatedSpace, KgData, PersonalSpace, PublicSpace,
 };
 
 /// Matches spaces with their corresponding plugins based on DAO address
@@ -93,17 +91,13 @@ pub fn map_members_added(members: &[grc20::pb::chain::MemberAdded]) -> Vec<Added
         .collect()
 }
 
-/// Preprocesses block scoped data from the substream
-pub async fn preprocess_block_scoped_data(
-    block_data: &BlockScopedData,
+/// Preprocesses the decoded output of a block from the substream
+pub async fn preprocess_geo_output(
+    block_metadata: BlockMetadata,
+    geo: GeoOutput,
     ipfs_cache: &Arc<PostgresCache>,
 ) -> Result<KgData, IndexingError> {
-    let output = stream::utils::output(block_data);
-    let block_metadata = stream::utils::block_metadata(block_data);
-    let geo = GeoOutput::decode(output.value.as_slice())?;
     let cache = ipfs_cache;
-    let edits = Arc::new(Mutex::new(Vec::<PreprocessedEdit>::new()));
-
     let mut handles = Vec::new();
 
     // @TODO: We can separate this cache reading step into a separate module
@@ -116,7 +110,6 @@ pub async fn preprocess_block_scoped_data(
         }
 
         let cache = cache.clone();
-        let edits_clone = edits.clone();
 
         let handle = task::spawn(async move {
             // We retry requests to the cache in the case that the cache is
@@ -126,27 +119,32 @@ pub async fn preprocess_block_scoped_data(
                 .factor(2)
                 .max_delay(std::time::Duration::from_secs(5))
                 .map(jitter);
-            let cached_edit_entry =
-                Retry::spawn(retry, async || cache.get(&chain_edit.content_uri).await).await?;
-
-            {
-                let mut edits_guard = edits_clone.lock().await;
-                edits_guard.push(cached_edit_entry);
-            }
+            let (cache, uri) = (&cache, &chain_edit.content_uri);
+            let mut attempts = 0;
+            let cached_edit_entry = Retry::spawn(retry, || {
+                let attempt = attempts;
+                attempts += 1;
+
+                async move {
+                    let result = cache.get(uri).await;
+                    metrics::record_ipfs_cache_read(attempt, result.is_ok());
+                    result
+                }
+            })
+            .await?;
 
-            Ok::<(), IndexingError>(())
+            Ok::<PreprocessedEdit, IndexingError>(cached_edit_entry)
         });
 
         handles.push(handle);
     }
 
-    join_all(handles).await;
-
-    // Extract the edits from the Arc<Mutex<>> for further processing
-    let final_edits = {
-        let edits_guard = edits.lock().await;
-        edits_guard.clone() // Clone the vector to move it out of the mutex
-    };
+    // Edits are read from the cache concurrently but collected in the order
+    // they were published on chain, which is the order they're written in.
+    let mut final_edits = Vec::with_capacity(handles.len());
+    for handle in handles {
+        final_edits.push(handle.await??);
+    }
 
     let created_spaces = match_spaces_with_plugins(
         &geo.spaces_created,
@@ -609,7 +607,7 @@ mod tests {
         let added_editors = map_editors_added(&editors);
         let mut added_members = map_members_added(&members);
 
-        // Simulate the logic from preprocess_block_scoped_data
+        // Simulate the logic from preprocess_geo_output
         let created_space_dao_addresses: std::collections::HashSet<String> = created_spaces
             .iter()
             .map(|space| match space {
@@ -0,0 +1,182 @@
+use std::{future::Future, sync::Arc};
+
+use grc20::pb::chain::GeoOutput;
+use stream::{
+    cursor::{Cursor, CursorStore, PostgresCursorStore},
+    pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+    utils::BlockMetadata,
+    DecodedPreprocessedSink,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    block_handler::{root_handler, undo_handler},
+    cache::{
+        postgres::PostgresCache,
+        properties_cache::{BlockPropertiesCache, PropertiesCache},
+    },
+    error::IndexingError,
+    preprocess,
+    storage::{postgres::PostgresStorage, StorageBackend, StorageError},
+    KgData,
+};
+
+/// The name the indexer's cursor is persisted under.
+pub const SINK_NAME: &str = "kg_indexer";
+
+/// Indexes the knowledge graph from the decoded output of the geo substream.
+pub struct KgIndexer {
+    storage: Arc<PostgresStorage>,
+    ipfs_cache: Arc<PostgresCache>,
+    properties_cache: Arc<PropertiesCache>,
+    cursor_store: PostgresCursorStore,
+    /// The block processed last, committed along with its cursor once it
+    /// is persisted.
+    processed_block: Mutex<Option<ProcessedBlock>>,
+}
+
+/// A block that was written but not committed yet.
+struct ProcessedBlock {
+    storage: PostgresStorage,
+    properties: BlockPropertiesCache,
+}
+
+impl KgIndexer {
+    pub fn new(
+        storage: PostgresStorage,
+        ipfs_cache: PostgresCache,
+        properties_cache: PropertiesCache,
+    ) -> Self {
+        let cursor_store = PostgresCursorStore::new(storage.pool.clone());
+
+        KgIndexer {
+            storage: Arc::new(storage),
+            ipfs_cache: Arc::new(ipfs_cache),
+            properties_cache: Arc::new(properties_cache),
+            cursor_store,
+            processed_block: Mutex::new(None),
+        }
+    }
+
+    /// Writes a block in its own unit of work, keeping it to be committed
+    /// with the block's cursor. A block that fails is rolled back, along
+    /// with the changes it made to the properties cache.
+    async fn write_block<F, Fut>(&self, write: F) -> Result<(), IndexingError>
+    where
+        F: FnOnce(Arc<PostgresStorage>, Arc<BlockPropertiesCache>) -> Fut,
+        Fut: Future<Output = Result<(), IndexingError>>,
+    {
+        let mut processed_block = self.processed_block.lock().await;
+        if processed_block.is_some() {
+            return Err(StorageError::BlockNotCommitted.into());
+        }
+
+        let storage = Arc::new(self.storage.begin_block().await?);
+        let properties = Arc::new(BlockPropertiesCache::new(self.properties_cache.clone()));
+        let result = write(storage.clone(), properties.clone()).await;
+
+        let (Some(storage), Some(properties)) =
+            (Arc::into_inner(storage), Arc::into_inner(properties))
+        else {
+            return Err(StorageError::BlockInUse.into());
+        };
+
+        match result {
+            Ok(()) => {
+                *processed_block = Some(ProcessedBlock {
+                    storage,
+                    properties,
+                });
+                Ok(())
+            }
+            Err(error) => {
+                properties.rollback().await;
+                storage.rollback_block().await?;
+                Err(error)
+            }
+        }
+    }
+}
+
+impl DecodedPreprocessedSink<KgData> for KgIndexer {
+    type Output = GeoOutput;
+    type Error = IndexingError;
+
+    async fn load_persisted_cursor(
+        &self,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, Self::Error> {
+        Ok(self
+            .cursor_store
+            .load_checked(SINK_NAME, module_hash)
+            .await?)
+    }
+
+    /// Commits the block's writes together with its cursor, so a crash
+    /// either keeps both or resumes from the previous block with neither.
+    async fn persist_cursor(&self, module_hash: &str, cursor: Cursor) -> Result<(), Self::Error> {
+        let processed_block = self.processed_block.lock().await.take();
+
+        match processed_block {
+            Some(ProcessedBlock {
+                storage,
+                properties,
+            }) => {
+                if let Err(error) = storage.commit_block(SINK_NAME, module_hash, &cursor).await {
+                    properties.rollback().await;
+                    return Err(error.into());
+                }
+            }
+            None => {
+                self.cursor_store
+                    .persist(SINK_NAME, module_hash, &cursor)
+                    .await?
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+    We can pre-process any edits we care about in the chain in this separate function.
+    There's lots of decoding steps and filtering done to the Knowledge Graphs events
+    so it's helpful to do this decoding/filtering/data-fetching ahead of time so the
+    process steps can focus purely on mapping and writing data to the sink.
+    */
+    async fn preprocess_decoded_block(
+        &self,
+        block: BlockMetadata,
+        output: GeoOutput,
+    ) -> Result<KgData, Self::Error> {
+        let kg_data = preprocess::preprocess_geo_output(block, output, &self.ipfs_cache).await?;
+
+        Ok(kg_data)
+    }
+
+    async fn process_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+        decoded_data: KgData,
+    ) -> Result<(), Self::Error> {
+        self.write_block(|block, properties| async move {
+            root_handler::run(&decoded_data, &decoded_data.block, &block, &properties).await?;
+
+            // Blocks at or below the final block height can't be reorganized
+            // anymore so their undo log entries are no longer needed.
+            block.prune_undo_log(block_data.final_block_height).await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> Result<(), Self::Error> {
+        self.write_block(|block, properties| async move {
+            undo_handler::run(undo_signal, &block, &properties).await
+        })
+        .await
+    }
+}
@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use stream::cursor::{Cursor, CursorError};
 use uuid::Uuid;
 
 pub mod postgres;
@@ -6,18 +7,30 @@ pub mod postgres;
 use thiserror::Error;
 
 use crate::models::{
+    edits::EditItem,
     entities::EntityItem,
     membership::{EditorItem, MemberItem},
     properties::PropertyItem,
     relations::{SetRelationItem, UnsetRelationItem, UpdateRelationItem},
     spaces::SpaceItem,
-    values::ValueOp,
+    undo::BlockUndoKeys,
+    values::{RejectedValue, ValueOp},
+    versions::EditVersion,
 };
 
 #[derive(Error, Debug)]
 pub enum StorageError {
     #[error("Storage error: {0}")]
     Database(#[from] sqlx::Error),
+
+    #[error("Storage error: {0}")]
+    Cursor(#[from] CursorError),
+
+    #[error("Storage error: a block was written before the previous block was committed")]
+    BlockNotCommitted,
+
+    #[error("Storage error: a block's storage was still in use after its handlers finished")]
+    BlockInUse,
 }
 
 #[async_trait]
@@ -49,4 +62,89 @@ pub trait StorageBackend: Send + Sync {
     async fn remove_members(&self, members: &Vec<MemberItem>) -> Result<(), StorageError>;
     async fn insert_editors(&self, editors: &Vec<EditorItem>) -> Result<(), StorageError>;
     async fn remove_editors(&self, editors: &Vec<EditorItem>) -> Result<(), StorageError>;
+
+    /// Records an edit. Edits already recorded to the same space, e.g.,
+    /// because the same edit was published again, keep their first record.
+    async fn insert_edit(&self, edit: &EditItem) -> Result<(), StorageError>;
+
+    /// Links the values and relations an edit wrote to it, as the edit that
+    /// last touched them. Without an edit id the links are cleared.
+    async fn link_edit(
+        &self,
+        edit_id: Option<&Uuid>,
+        value_ids: &[Uuid],
+        relation_ids: &[Uuid],
+    ) -> Result<(), StorageError>;
+
+    /// Records the values `version` set that were rejected instead of
+    /// written, along with the URI of the edit they were published in.
+    async fn insert_rejected_values(
+        &self,
+        version: &EditVersion,
+        content_uri: &str,
+        rejected_values: &[RejectedValue],
+    ) -> Result<(), StorageError>;
+
+    /// Records the state `version` left the values it set in, and the last
+    /// state of the values it deleted. Must be called after the values are
+    /// written and before the deleted values are removed.
+    async fn insert_value_versions(
+        &self,
+        version: &EditVersion,
+        value_ids: &[Uuid],
+        deleted_value_ids: &[Uuid],
+    ) -> Result<(), StorageError>;
+
+    /// Records the state `version` left the relations it created or updated
+    /// in, and the last state of the relations it deleted. Must be called
+    /// after the relations are written and before the deleted relations are
+    /// removed.
+    async fn insert_relation_versions(
+        &self,
+        version: &EditVersion,
+        relation_ids: &[Uuid],
+        deleted_relation_ids: &[Uuid],
+    ) -> Result<(), StorageError>;
+
+    /// Snapshots the current state of every row in `keys` into the undo log
+    /// for `block_number`. Must be called before the block is written.
+    async fn snapshot_block(
+        &self,
+        block_number: u64,
+        keys: &BlockUndoKeys,
+    ) -> Result<(), StorageError>;
+
+    /// Reverts every block after `block_number` using the undo log. Returns
+    /// the ids of properties that no longer exist after the revert so any
+    /// in-memory caches can be updated.
+    async fn revert_blocks_after(&self, block_number: u64) -> Result<Vec<Uuid>, StorageError>;
+
+    /// Drops undo log entries for blocks that are final and can no longer
+    /// be reverted.
+    async fn prune_undo_log(&self, final_block_number: u64) -> Result<(), StorageError>;
+
+    /// Starts the unit of work of a block. Writes made through the returned
+    /// storage are made in a single transaction until it is committed, so a
+    /// block is either applied as a whole or not at all. Dropping it without
+    /// committing discards its writes.
+    async fn begin_block(&self) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+
+    /// Persists the sink's cursor along with the writes of the block and
+    /// commits them. Without a unit of work the cursor is persisted on its
+    /// own.
+    async fn commit_block(
+        self,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> Result<(), StorageError>
+    where
+        Self: Sized;
+
+    /// Discards the writes of the block.
+    async fn rollback_block(self) -> Result<(), StorageError>
+    where
+        Self: Sized;
 }
@@ -1,9 +1,18 @@
 use async_trait::async_trait;
+use std::ops::{Deref, DerefMut};
 
-use sqlx::{postgres::PgPoolOptions, Postgres, QueryBuilder, Row};
+use sqlx::{
+    pool::PoolConnection, postgres::PgPoolOptions, Connection, PgConnection, Postgres,
+    QueryBuilder, Row, Transaction,
+};
+use stream::cursor::{Cursor, PostgresCursorStore};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::instrument;
 use uuid::Uuid;
 
+use crate::metrics;
 use crate::models::{
+    edits::{EditItem, OpCounts},
     entities::EntityItem,
     membership::{EditorItem, MemberItem},
     properties::{
@@ -12,11 +21,27 @@ use crate::models::{
     },
     relations::{SetRelationItem, UnsetRelationItem, UpdateRelationItem},
     spaces::{SpaceItem, SpaceType},
-    values::{ValueChangeType, ValueOp},
+    undo::BlockUndoKeys,
+    values::{RejectedValue, ValueChangeType, ValueOp},
+    versions::{EditVersion, EntityVersion},
 };
+use crate::validators::ValidatedValue;
 
 use super::{StorageBackend, StorageError};
 
+/// Tables written by the block handlers along with the columns making up
+/// their primary key. Every row written to these tables is tracked by the
+/// block undo log.
+const UNDO_TABLES: [(&str, &[&str]); 7] = [
+    ("entities", &["id"]),
+    ("properties", &["id"]),
+    ("values", &["id"]),
+    ("relations", &["id"]),
+    ("spaces", &["id"]),
+    ("members", &["address", "space_id"]),
+    ("editors", &["address", "space_id"]),
+];
+
 #[derive(sqlx::FromRow)]
 struct EntityRow {
     id: Uuid,
@@ -55,6 +80,36 @@ struct RelationRow {
 
 pub struct PostgresStorage {
     pub pool: sqlx::Pool<Postgres>,
+    /// The transaction of the block this storage writes, see `begin_block`.
+    /// Without one every write is made on its own.
+    block: Option<Mutex<Transaction<'static, Postgres>>>,
+}
+
+/// The connection a write runs on: the block's transaction, if any, or a
+/// connection from the pool.
+enum StorageConnection<'a> {
+    Block(MutexGuard<'a, Transaction<'static, Postgres>>),
+    Pool(PoolConnection<Postgres>),
+}
+
+impl Deref for StorageConnection<'_> {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match self {
+            StorageConnection::Block(block) => block,
+            StorageConnection::Pool(connection) => connection,
+        }
+    }
+}
+
+impl DerefMut for StorageConnection<'_> {
+    fn deref_mut(&mut self) -> &mut PgConnection {
+        match self {
+            StorageConnection::Block(block) => block,
+            StorageConnection::Pool(connection) => connection,
+        }
+    }
 }
 
 impl PostgresStorage {
@@ -64,7 +119,16 @@ impl PostgresStorage {
             .connect(database_url.as_str())
             .await?;
 
-        return Ok(PostgresStorage { pool });
+        return Ok(PostgresStorage { pool, block: None });
+    }
+
+    /// Returns the connection to write with. Writes of a block share its
+    /// transaction, so they are serialized on the block's connection.
+    async fn connection(&self) -> Result<StorageConnection<'_>, StorageError> {
+        match &self.block {
+            Some(block) => Ok(StorageConnection::Block(block.lock().await)),
+            None => Ok(StorageConnection::Pool(self.pool.acquire().await?)),
+        }
     }
 
     pub async fn get_entity(&self, entity_id: &String) -> Result<EntityItem, StorageError> {
@@ -113,6 +177,7 @@ impl PostgresStorage {
             value: query.value,
             language: query.language,
             unit: query.unit,
+            typed_value: None,
             change_type: ValueChangeType::SET,
         })
     }
@@ -172,6 +237,44 @@ impl PostgresStorage {
         })
     }
 
+    /// Reads a page of properties ordered by id, starting after `after_id`.
+    /// Properties are paged by id so they can be read in full without
+    /// holding them all in memory at once.
+    pub async fn get_properties_page(
+        &self,
+        after_id: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<PropertyItem>, StorageError> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, type::text as type FROM properties
+            WHERE $1::uuid IS NULL OR id > $1
+            ORDER BY id
+            LIMIT $2
+            "#,
+        )
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let type_value: String = row.get("type");
+                let data_type = string_to_data_type(&type_value).ok_or_else(|| {
+                    sqlx::Error::Decode(
+                        format!("Invalid enum value '{}' for dataTypes enum", type_value).into(),
+                    )
+                })?;
+
+                Ok(PropertyItem {
+                    id: row.get("id"),
+                    data_type,
+                })
+            })
+            .collect()
+    }
+
     pub async fn get_member(&self, address: &str, space_id: &Uuid) -> Result<MemberItem, StorageError> {
         let query = sqlx::query!(
             "SELECT address, space_id FROM members WHERE address = $1 AND space_id = $2",
@@ -201,10 +304,281 @@ impl PostgresStorage {
             space_id: query.space_id,
         })
     }
+
+    /// Re-keys values written before value IDs were derived with
+    /// `indexer_utils::id::derive_value_id`, along with their entries in the
+    /// undo log, returning how many values were re-keyed. Values already
+    /// keyed by their derived ID are left untouched, so this can safely run
+    /// more than once. The indexer must not be running while it does.
+    #[instrument(skip(self))]
+    pub async fn migrate_value_ids(&self) -> Result<u64, StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(DERIVE_VALUE_ID_FUNCTION)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TEMP TABLE value_id_migration ON COMMIT DROP AS
+            SELECT old_id, new_id FROM (
+                SELECT id AS old_id,
+                    pg_temp.derive_value_id(entity_id, property_id, space_id::uuid) AS new_id
+                FROM values
+            ) AS derived
+            WHERE old_id <> new_id
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // Snapshots of values that existed before a block carry everything
+        // their ID is derived from. Values created by a block have no
+        // snapshot and are re-keyed like the row they created.
+        sqlx::query(
+            r#"
+            UPDATE block_undo_log SET
+                row_key = jsonb_build_object('id', derived.new_id),
+                snapshot = jsonb_set(snapshot, '{id}', to_jsonb(derived.new_id))
+            FROM (
+                SELECT id, pg_temp.derive_value_id(
+                    (snapshot->>'entity_id')::uuid,
+                    (snapshot->>'property_id')::uuid,
+                    (snapshot->>'space_id')::uuid
+                ) AS new_id
+                FROM block_undo_log
+                WHERE table_name = 'values' AND snapshot IS NOT NULL
+            ) AS derived
+            WHERE block_undo_log.id = derived.id
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE block_undo_log SET row_key = jsonb_build_object('id', m.new_id)
+            FROM value_id_migration m
+            WHERE table_name = 'values'
+                AND snapshot IS NULL
+                AND row_key->>'id' = m.old_id
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE values SET id = m.new_id
+            FROM value_id_migration m
+            WHERE values.id = m.old_id
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_edit(
+        &self,
+        edit_id: &Uuid,
+        space_id: &Uuid,
+    ) -> Result<EditItem, StorageError> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, name, content_uri, space_id, block_number, edit_index,
+                block_timestamp, authors, language, op_counts
+            FROM edits WHERE id = $1 AND space_id = $2
+            "#,
+        )
+        .bind(edit_id)
+        .bind(space_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let op_counts: OpCounts = serde_json::from_value(row.get("op_counts"))
+            .map_err(|e| sqlx::Error::Decode(format!("Invalid op counts: {}", e).into()))?;
+
+        Ok(EditItem {
+            id: row.get("id"),
+            name: row.get("name"),
+            content_uri: row.get("content_uri"),
+            space_id: row.get("space_id"),
+            edit_index: row.get("edit_index"),
+            block_number: row.get::<i64, _>("block_number") as u64,
+            block_timestamp: row.get("block_timestamp"),
+            authors: row.get("authors"),
+            language: row.get("language"),
+            op_counts,
+        })
+    }
+
+    /// Reads an entity's values and relations as they were once `block_number`
+    /// was indexed.
+    pub async fn get_entity_at_block(
+        &self,
+        entity_id: &Uuid,
+        block_number: u64,
+    ) -> Result<EntityVersion, StorageError> {
+        self.get_entity_version(entity_id, block_number as i64, i32::MAX)
+            .await
+    }
+
+    /// Reads an entity's values and relations as they were once `edit_id`
+    /// was applied to `space_id`. Fails if the edit wasn't indexed.
+    pub async fn get_entity_at_edit(
+        &self,
+        entity_id: &Uuid,
+        edit_id: &Uuid,
+        space_id: &Uuid,
+    ) -> Result<EntityVersion, StorageError> {
+        let (block_number, edit_index): (i64, i32) = sqlx::query_as(
+            "SELECT block_number, edit_index FROM edits WHERE id = $1 AND space_id = $2",
+        )
+        .bind(edit_id)
+        .bind(space_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.get_entity_version(entity_id, block_number, edit_index)
+            .await
+    }
+
+    /// Reads the latest version of each of the entity's values and relations
+    /// up to and including the edit at `edit_index` in `block_number`,
+    /// leaving out the ones deleted by then.
+    async fn get_entity_version(
+        &self,
+        entity_id: &Uuid,
+        block_number: i64,
+        edit_index: i32,
+    ) -> Result<EntityVersion, StorageError> {
+        let value_rows = sqlx::query(
+            r#"
+            SELECT * FROM (
+                SELECT DISTINCT ON (value_id)
+                    value_id, property_id, entity_id, space_id, value, language, unit, deleted
+                FROM value_versions
+                WHERE entity_id = $1 AND (block_number, edit_index) <= ($2, $3)
+                ORDER BY value_id, block_number DESC, edit_index DESC
+            ) AS latest
+            WHERE NOT deleted
+            ORDER BY value_id
+            "#,
+        )
+        .bind(entity_id)
+        .bind(block_number)
+        .bind(edit_index)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let values = value_rows
+            .iter()
+            .map(|row| {
+                let id = Uuid::parse_str(row.get("value_id")).map_err(|e| {
+                    sqlx::Error::Decode(format!("Invalid UUID format for id: {}", e).into())
+                })?;
+                let space_id = Uuid::parse_str(row.get("space_id")).map_err(|e| {
+                    sqlx::Error::Decode(format!("Invalid UUID format for space_id: {}", e).into())
+                })?;
+
+                Ok(ValueOp {
+                    id,
+                    property_id: row.get("property_id"),
+                    entity_id: row.get("entity_id"),
+                    space_id,
+                    value: row.get("value"),
+                    language: row.get("language"),
+                    unit: row.get("unit"),
+                    typed_value: None,
+                    change_type: ValueChangeType::SET,
+                })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        let relation_rows = sqlx::query(
+            r#"
+            SELECT * FROM (
+                SELECT DISTINCT ON (relation_id)
+                    relation_id, entity_id, type_id, from_entity_id, from_space_id,
+                    from_version_id, to_entity_id, to_space_id, to_version_id, position,
+                    space_id, verified, deleted
+                FROM relation_versions
+                WHERE from_entity_id = $1 AND (block_number, edit_index) <= ($2, $3)
+                ORDER BY relation_id, block_number DESC, edit_index DESC
+            ) AS latest
+            WHERE NOT deleted
+            ORDER BY relation_id
+            "#,
+        )
+        .bind(entity_id)
+        .bind(block_number)
+        .bind(edit_index)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let relations = relation_rows
+            .iter()
+            .map(|row| SetRelationItem {
+                id: row.get("relation_id"),
+                entity_id: row.get("entity_id"),
+                type_id: row.get("type_id"),
+                from_id: row.get("from_entity_id"),
+                from_space_id: row
+                    .get::<Option<Uuid>, _>("from_space_id")
+                    .map(|id| id.to_string()),
+                from_version_id: row
+                    .get::<Option<Uuid>, _>("from_version_id")
+                    .map(|id| id.to_string()),
+                to_id: row.get("to_entity_id"),
+                to_space_id: row
+                    .get::<Option<Uuid>, _>("to_space_id")
+                    .map(|id| id.to_string()),
+                to_version_id: row
+                    .get::<Option<Uuid>, _>("to_version_id")
+                    .map(|id| id.to_string()),
+                position: row.get("position"),
+                space_id: row.get("space_id"),
+                verified: row.get("verified"),
+            })
+            .collect();
+
+        Ok(EntityVersion {
+            id: *entity_id,
+            values,
+            relations,
+        })
+    }
 }
 
+/// Derives value IDs in SQL the same way `indexer_utils::id::derive_value_id`
+/// does, for migrating existing rows.
+const DERIVE_VALUE_ID_FUNCTION: &str = r#"
+    CREATE OR REPLACE FUNCTION pg_temp.derive_value_id(entity_id uuid, property_id uuid, space_id uuid)
+    RETURNS text AS $$
+        SELECT encode(
+            set_byte(
+                set_byte(hash, 6, (get_byte(hash, 6) & 15) | 64),
+                8,
+                (get_byte(hash, 8) & 63) | 128
+            ),
+            'hex'
+        )::uuid::text
+        FROM (
+            SELECT substring(
+                sha256(uuid_send(entity_id) || uuid_send(property_id) || uuid_send(space_id))
+                FROM 1 FOR 16
+            ) AS hash
+        ) AS hashed
+    $$ LANGUAGE sql IMMUTABLE
+"#;
+
 #[async_trait]
 impl StorageBackend for PostgresStorage {
+    #[instrument(skip_all, fields(rows = entities.len()))]
     async fn insert_entities(&self, entities: &Vec<EntityItem>) -> Result<(), StorageError> {
         let ids: Vec<Uuid> = entities.iter().map(|x| x.id).collect();
         let created_ats: Vec<String> = entities.iter().map(|x| x.created_at.clone()).collect();
@@ -218,7 +592,8 @@ impl StorageBackend for PostgresStorage {
             .map(|x| x.updated_at_block.clone())
             .collect();
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             INSERT INTO entities (id, created_at, created_at_block, updated_at, updated_at_block)
             SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[], $5::text[])
@@ -231,12 +606,14 @@ impl StorageBackend for PostgresStorage {
             &updated_ats,
             &updated_at_blocks
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("entities", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = values.len()))]
     async fn insert_values(&self, values: &Vec<ValueOp>) -> Result<(), StorageError> {
         if values.is_empty() {
             return Ok(());
@@ -250,6 +627,11 @@ impl StorageBackend for PostgresStorage {
         let mut value_values = Vec::with_capacity(values.len());
         let mut languages = Vec::with_capacity(values.len());
         let mut units = Vec::with_capacity(values.len());
+        let mut numbers = Vec::with_capacity(values.len());
+        let mut booleans = Vec::with_capacity(values.len());
+        let mut times = Vec::with_capacity(values.len());
+        let mut xs = Vec::with_capacity(values.len());
+        let mut ys = Vec::with_capacity(values.len());
 
         for prop in values {
             ids.push(prop.id.to_string());
@@ -259,11 +641,21 @@ impl StorageBackend for PostgresStorage {
             value_values.push(prop.value.as_deref().unwrap_or(""));
             languages.push(&prop.language);
             units.push(&prop.unit);
+
+            // The typed columns hold the normalized value of the data type
+            // the value was validated against, and are null otherwise.
+            let typed = TypedColumns::from(prop.typed_value.as_ref());
+            numbers.push(typed.number);
+            booleans.push(typed.boolean);
+            times.push(typed.time);
+            xs.push(typed.x);
+            ys.push(typed.y);
         }
 
         let query = r#"
                 INSERT INTO values (
-                    id, entity_id, property_id, space_id, value, language, unit
+                    id, entity_id, property_id, space_id, value, language, unit,
+                    number, boolean, time, x, y
                 )
                 SELECT * FROM UNNEST(
                     $1::text[],
@@ -272,15 +664,26 @@ impl StorageBackend for PostgresStorage {
                     $4::text[],
                     $5::text[],
                     $6::text[],
-                    $7::text[]
+                    $7::text[],
+                    $8::float8[]::numeric[],
+                    $9::boolean[],
+                    $10::text[]::timestamptz[],
+                    $11::float8[],
+                    $12::float8[]
                 )
                 ON CONFLICT (id) DO UPDATE SET
                     value = EXCLUDED.value,
                     language = EXCLUDED.language,
-                    unit = EXCLUDED.unit
+                    unit = EXCLUDED.unit,
+                    number = EXCLUDED.number,
+                    boolean = EXCLUDED.boolean,
+                    time = EXCLUDED.time,
+                    x = EXCLUDED.x,
+                    y = EXCLUDED.y
             "#;
 
-        sqlx::query(query)
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(query)
             .bind(&ids)
             .bind(&entity_ids)
             .bind(&property_ids)
@@ -288,12 +691,19 @@ impl StorageBackend for PostgresStorage {
             .bind(&value_values)
             .bind(&languages)
             .bind(&units)
-            .execute(&self.pool)
+            .bind(&numbers)
+            .bind(&booleans)
+            .bind(&times)
+            .bind(&xs)
+            .bind(&ys)
+            .execute(&mut *connection)
             .await?;
+        metrics::record_rows_written("values", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = property_ids.len(), space_id = %space_id))]
     async fn delete_values(
         &self,
         property_ids: &Vec<Uuid>,
@@ -306,19 +716,22 @@ impl StorageBackend for PostgresStorage {
         let ids: Vec<String> = property_ids.iter().map(|id| id.to_string()).collect();
         let space_id_str = space_id.to_string();
 
-        sqlx::query(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
             "DELETE FROM values
                      WHERE space_id = $1 AND id IN
                      (SELECT * FROM UNNEST($2::text[]))",
         )
         .bind(&space_id_str)
         .bind(&ids)
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("values", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = relations.len()))]
     async fn insert_relations(&self, relations: &Vec<SetRelationItem>) -> Result<(), StorageError> {
         if relations.is_empty() {
             return Ok(());
@@ -365,7 +778,8 @@ impl StorageBackend for PostgresStorage {
                     verified = EXCLUDED.verified
             "#;
 
-        sqlx::query(query)
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(query)
             .bind(&ids)
             .bind(&space_ids)
             .bind(&entity_ids)
@@ -376,12 +790,14 @@ impl StorageBackend for PostgresStorage {
             .bind(&type_ids)
             .bind(&positions)
             .bind(&verified)
-            .execute(&self.pool)
+            .execute(&mut *connection)
             .await?;
+        metrics::record_rows_written("relations", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = relations.len()))]
     async fn update_relations(
         &self,
         relations: &Vec<UpdateRelationItem>,
@@ -390,34 +806,43 @@ impl StorageBackend for PostgresStorage {
             return Ok(());
         }
 
-        // @TODO:
-        // This is tricky since we only want to update if the values are actually set,
-        // not if they're None
-
-        // Create a query builder for PostgreSQL
+        // Only the fields set in the update are written, the others keep
+        // their current value.
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
-            "UPDATE relations (id, from_space_id, to_space_id, position, verified) ",
+            "UPDATE relations SET
+             from_space_id = COALESCE(v.from_space_id::uuid, relations.from_space_id),
+             from_version_id = COALESCE(v.from_version_id::uuid, relations.from_version_id),
+             to_space_id = COALESCE(v.to_space_id::uuid, relations.to_space_id),
+             to_version_id = COALESCE(v.to_version_id::uuid, relations.to_version_id),
+             position = COALESCE(v.position, relations.position),
+             verified = COALESCE(v.verified, relations.verified)
+             FROM (",
         );
 
-        // Start the VALUES section
         query_builder.push_values(relations, |mut b, relation| {
-            b.push_bind(&relation.id);
+            b.push_bind(relation.id);
             b.push_bind(&relation.from_space_id);
+            b.push_bind(&relation.from_version_id);
             b.push_bind(&relation.to_space_id);
+            b.push_bind(&relation.to_version_id);
             b.push_bind(&relation.position);
-            b.push_bind(&relation.verified);
+            b.push_bind(relation.verified);
         });
 
-        // Execute the query
-        let result = query_builder.build().execute(&self.pool).await;
+        query_builder.push(
+            ") AS v(id, from_space_id, from_version_id, to_space_id, to_version_id,
+                    position, verified)
+              WHERE relations.id = v.id",
+        );
 
-        if let Err(error) = result {
-            println!("Error writing relations {}", error);
-        }
+        let mut connection = self.connection().await?;
+        let result = query_builder.build().execute(&mut *connection).await?;
+        metrics::record_rows_written("relations", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = relations.len()))]
     async fn unset_relation_fields(
         &self,
         relations: &Vec<UnsetRelationItem>,
@@ -434,25 +859,17 @@ impl StorageBackend for PostgresStorage {
               to_version_id = CASE WHEN v.unset_to_version_id THEN NULL ELSE to_version_id END,
               position = CASE WHEN v.unset_position THEN NULL ELSE position END,
               verified = CASE WHEN v.unset_verified THEN NULL ELSE verified END
-              FROM (VALUES "
+              FROM ("
          );
 
         query_builder.push_values(relations, |mut b, relation| {
-            b.push("(");
-            b.push_bind(&relation.id);
-            b.push(", ");
+            b.push_bind(relation.id);
             b.push_bind(relation.from_space_id.unwrap_or(false));
-            b.push(", ");
             b.push_bind(relation.from_version_id.unwrap_or(false));
-            b.push(", ");
             b.push_bind(relation.to_space_id.unwrap_or(false));
-            b.push(", ");
             b.push_bind(relation.to_version_id.unwrap_or(false));
-            b.push(", ");
             b.push_bind(relation.position.unwrap_or(false));
-            b.push(", ");
             b.push_bind(relation.verified.unwrap_or(false));
-            b.push(")");
         });
 
         query_builder.push(
@@ -461,11 +878,14 @@ impl StorageBackend for PostgresStorage {
               WHERE relations.id = v.id",
         );
 
-        query_builder.build().execute(&self.pool).await?;
+        let mut connection = self.connection().await?;
+        let result = query_builder.build().execute(&mut *connection).await?;
+        metrics::record_rows_written("relations", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = relation_ids.len(), space_id = %space_id))]
     async fn delete_relations(
         &self,
         relation_ids: &Vec<Uuid>,
@@ -475,15 +895,17 @@ impl StorageBackend for PostgresStorage {
             return Ok(());
         }
 
-        sqlx::query(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
             "DELETE FROM relations
                      WHERE space_id = $1 AND id IN
                      (SELECT * FROM UNNEST($2::uuid[]))",
         )
         .bind(space_id)
         .bind(relation_ids)
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("relations", result.rows_affected());
 
         Ok(())
     }
@@ -499,6 +921,7 @@ impl StorageBackend for PostgresStorage {
     /// The knowledge graph engine validates that all values associated with
     /// a property correctly conform to the property's Data Type. Additionally,
     /// changing the Property's Data Type is not allowed.
+    #[instrument(skip_all, fields(rows = properties.len()))]
     async fn insert_properties(&self, properties: &Vec<PropertyItem>) -> Result<(), StorageError> {
         if properties.is_empty() {
             return Ok(());
@@ -525,15 +948,18 @@ impl StorageBackend for PostgresStorage {
                 ON CONFLICT (id) DO NOTHING
             "#;
 
-        sqlx::query(query)
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(query)
             .bind(&ids)
             .bind(&types)
-            .execute(&self.pool)
+            .execute(&mut *connection)
             .await?;
+        metrics::record_rows_written("properties", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = spaces.len()))]
     async fn insert_spaces(&self, spaces: &Vec<SpaceItem>) -> Result<(), StorageError> {
         if spaces.is_empty() {
             return Ok(());
@@ -560,7 +986,8 @@ impl StorageBackend for PostgresStorage {
             personal_addresses.push(space.personal_address.clone());
         }
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             INSERT INTO spaces (id, type, dao_address, space_address, main_voting_address, membership_address, personal_address)
             SELECT id, type::"spaceTypes", dao_address, space_address, main_voting_address, membership_address, personal_address
@@ -576,12 +1003,14 @@ impl StorageBackend for PostgresStorage {
             &membership_addresses as &[Option<String>],
             &personal_addresses as &[Option<String>]
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("spaces", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = members.len()))]
     async fn insert_members(&self, members: &Vec<MemberItem>) -> Result<(), StorageError> {
         if members.is_empty() {
             return Ok(());
@@ -595,7 +1024,8 @@ impl StorageBackend for PostgresStorage {
             space_ids.push(member.space_id);
         }
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             INSERT INTO members (address, space_id)
             SELECT address, space_id
@@ -606,12 +1036,14 @@ impl StorageBackend for PostgresStorage {
             &addresses,
             &space_ids
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("members", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = members.len()))]
     async fn remove_members(&self, members: &Vec<MemberItem>) -> Result<(), StorageError> {
         if members.is_empty() {
             return Ok(());
@@ -625,7 +1057,8 @@ impl StorageBackend for PostgresStorage {
             space_ids.push(member.space_id);
         }
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             DELETE FROM members
             WHERE (address, space_id) IN (
@@ -637,12 +1070,14 @@ impl StorageBackend for PostgresStorage {
             &addresses,
             &space_ids
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("members", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = editors.len()))]
     async fn insert_editors(&self, editors: &Vec<EditorItem>) -> Result<(), StorageError> {
         if editors.is_empty() {
             return Ok(());
@@ -656,7 +1091,8 @@ impl StorageBackend for PostgresStorage {
             space_ids.push(editor.space_id);
         }
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             INSERT INTO editors (address, space_id)
             SELECT address, space_id
@@ -667,12 +1103,14 @@ impl StorageBackend for PostgresStorage {
             &addresses,
             &space_ids
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
         .await?;
+        metrics::record_rows_written("editors", result.rows_affected());
 
         Ok(())
     }
 
+    #[instrument(skip_all, fields(rows = editors.len()))]
     async fn remove_editors(&self, editors: &Vec<EditorItem>) -> Result<(), StorageError> {
         if editors.is_empty() {
             return Ok(());
@@ -686,7 +1124,8 @@ impl StorageBackend for PostgresStorage {
             space_ids.push(editor.space_id);
         }
 
-        sqlx::query!(
+        let mut connection = self.connection().await?;
+        let result = sqlx::query!(
             r#"
             DELETE FROM editors
             WHERE (address, space_id) IN (
@@ -698,11 +1137,573 @@ impl StorageBackend for PostgresStorage {
             &addresses,
             &space_ids
         )
-        .execute(&self.pool)
+        .execute(&mut *connection)
+        .await?;
+        metrics::record_rows_written("editors", result.rows_affected());
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(edit_id = %edit.id))]
+    async fn insert_edit(&self, edit: &EditItem) -> Result<(), StorageError> {
+        let op_counts = serde_json::to_value(&edit.op_counts)
+            .map_err(|e| sqlx::Error::Encode(format!("Invalid op counts: {}", e).into()))?;
+
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO edits (
+                id, name, content_uri, space_id, block_number, edit_index,
+                block_timestamp, authors, language, op_count, op_counts
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (id, space_id) DO NOTHING
+            "#,
+        )
+        .bind(edit.id)
+        .bind(&edit.name)
+        .bind(&edit.content_uri)
+        .bind(edit.space_id)
+        .bind(edit.block_number as i64)
+        .bind(edit.edit_index)
+        .bind(&edit.block_timestamp)
+        .bind(&edit.authors)
+        .bind(edit.language)
+        .bind(edit.op_counts.total())
+        .bind(op_counts)
+        .execute(&mut *connection)
+        .await?;
+        metrics::record_rows_written("edits", result.rows_affected());
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(values = value_ids.len(), relations = relation_ids.len()))]
+    async fn link_edit(
+        &self,
+        edit_id: Option<&Uuid>,
+        value_ids: &[Uuid],
+        relation_ids: &[Uuid],
+    ) -> Result<(), StorageError> {
+        let mut connection = self.connection().await?;
+
+        // The rows were just written and counted by the edit's other writes,
+        // so linking them isn't counted again.
+        if !value_ids.is_empty() {
+            let ids: Vec<String> = value_ids.iter().map(|id| id.to_string()).collect();
+            sqlx::query("UPDATE values SET edit_id = $1 WHERE id = ANY($2)")
+                .bind(edit_id)
+                .bind(&ids)
+                .execute(&mut *connection)
+                .await?;
+        }
+
+        if !relation_ids.is_empty() {
+            sqlx::query("UPDATE relations SET edit_id = $1 WHERE id = ANY($2)")
+                .bind(edit_id)
+                .bind(relation_ids)
+                .execute(&mut *connection)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(rows = rejected_values.len()))]
+    async fn insert_rejected_values(
+        &self,
+        version: &EditVersion,
+        content_uri: &str,
+        rejected_values: &[RejectedValue],
+    ) -> Result<(), StorageError> {
+        if rejected_values.is_empty() {
+            return Ok(());
+        }
+
+        let mut value_ids = Vec::with_capacity(rejected_values.len());
+        let mut entity_ids = Vec::with_capacity(rejected_values.len());
+        let mut property_ids = Vec::with_capacity(rejected_values.len());
+        let mut space_ids = Vec::with_capacity(rejected_values.len());
+        let mut value_values = Vec::with_capacity(rejected_values.len());
+        let mut data_types = Vec::with_capacity(rejected_values.len());
+        let mut reasons = Vec::with_capacity(rejected_values.len());
+        let mut messages = Vec::with_capacity(rejected_values.len());
+
+        for rejected in rejected_values {
+            value_ids.push(rejected.value.id.to_string());
+            entity_ids.push(rejected.value.entity_id);
+            property_ids.push(rejected.value.property_id);
+            space_ids.push(rejected.value.space_id);
+            value_values.push(rejected.value.value.as_deref().unwrap_or(""));
+            data_types.push(
+                rejected
+                    .data_type
+                    .as_ref()
+                    .map(|data_type| data_type.as_ref()),
+            );
+            reasons.push(rejected.reason.kind());
+            messages.push(rejected.reason.to_string());
+        }
+
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO rejected_values (
+                value_id, block_number, edit_index, entity_id, property_id, space_id, value,
+                data_type, reason, message, content_uri, edit_id
+            )
+            SELECT k.value_id, $9, $10, k.entity_id, k.property_id, k.space_id, k.value,
+                k.data_type::"dataTypes", k.reason, k.message, $11, $12
+            FROM UNNEST(
+                $1::text[], $2::uuid[], $3::uuid[], $4::uuid[], $5::text[], $6::text[],
+                $7::text[], $8::text[]
+            ) AS k(value_id, entity_id, property_id, space_id, value, data_type, reason, message)
+            ON CONFLICT (value_id, block_number, edit_index) DO UPDATE SET
+                entity_id = EXCLUDED.entity_id,
+                property_id = EXCLUDED.property_id,
+                space_id = EXCLUDED.space_id,
+                value = EXCLUDED.value,
+                data_type = EXCLUDED.data_type,
+                reason = EXCLUDED.reason,
+                message = EXCLUDED.message,
+                content_uri = EXCLUDED.content_uri,
+                edit_id = EXCLUDED.edit_id
+            "#,
+        )
+        .bind(&value_ids)
+        .bind(&entity_ids)
+        .bind(&property_ids)
+        .bind(&space_ids)
+        .bind(&value_values)
+        .bind(&data_types)
+        .bind(&reasons)
+        .bind(&messages)
+        .bind(version.block_number as i64)
+        .bind(version.edit_index)
+        .bind(content_uri)
+        .bind(version.edit_id)
+        .execute(&mut *connection)
+        .await?;
+        metrics::record_rows_written("rejected_values", result.rows_affected());
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(rows = value_ids.len() + deleted_value_ids.len()))]
+    async fn insert_value_versions(
+        &self,
+        version: &EditVersion,
+        value_ids: &[Uuid],
+        deleted_value_ids: &[Uuid],
+    ) -> Result<(), StorageError> {
+        if value_ids.is_empty() && deleted_value_ids.is_empty() {
+            return Ok(());
+        }
+
+        let (ids, deleted) = version_keys(value_ids, deleted_value_ids);
+
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO value_versions (
+                value_id, block_number, edit_index, edit_id, block_timestamp, authors,
+                property_id, entity_id, space_id, value, language, unit, deleted
+            )
+            SELECT v.id, $3, $4, $5, $6, $7,
+                v.property_id, v.entity_id, v.space_id, v.value, v.language, v.unit, k.deleted
+            FROM UNNEST($1::text[], $2::bool[]) AS k(id, deleted)
+            JOIN values v ON v.id = k.id
+            ON CONFLICT (value_id, block_number, edit_index) DO UPDATE SET
+                edit_id = EXCLUDED.edit_id,
+                block_timestamp = EXCLUDED.block_timestamp,
+                authors = EXCLUDED.authors,
+                property_id = EXCLUDED.property_id,
+                entity_id = EXCLUDED.entity_id,
+                space_id = EXCLUDED.space_id,
+                value = EXCLUDED.value,
+                language = EXCLUDED.language,
+                unit = EXCLUDED.unit,
+                deleted = EXCLUDED.deleted
+            "#,
+        )
+        .bind(ids.iter().map(|id| id.to_string()).collect::<Vec<_>>())
+        .bind(&deleted)
+        .bind(version.block_number as i64)
+        .bind(version.edit_index)
+        .bind(version.edit_id)
+        .bind(&version.block_timestamp)
+        .bind(&version.authors)
+        .execute(&mut *connection)
+        .await?;
+        metrics::record_rows_written("value_versions", result.rows_affected());
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(rows = relation_ids.len() + deleted_relation_ids.len()))]
+    async fn insert_relation_versions(
+        &self,
+        version: &EditVersion,
+        relation_ids: &[Uuid],
+        deleted_relation_ids: &[Uuid],
+    ) -> Result<(), StorageError> {
+        if relation_ids.is_empty() && deleted_relation_ids.is_empty() {
+            return Ok(());
+        }
+
+        let (ids, deleted) = version_keys(relation_ids, deleted_relation_ids);
+
+        let mut connection = self.connection().await?;
+        let result = sqlx::query(
+            r#"
+            INSERT INTO relation_versions (
+                relation_id, block_number, edit_index, edit_id, block_timestamp, authors,
+                entity_id, type_id, from_entity_id, from_space_id, from_version_id,
+                to_entity_id, to_space_id, to_version_id, position, space_id, verified, deleted
+            )
+            SELECT r.id, $3, $4, $5, $6, $7,
+                r.entity_id, r.type_id, r.from_entity_id, r.from_space_id, r.from_version_id,
+                r.to_entity_id, r.to_space_id, r.to_version_id, r.position, r.space_id,
+                r.verified, k.deleted
+            FROM UNNEST($1::uuid[], $2::bool[]) AS k(id, deleted)
+            JOIN relations r ON r.id = k.id
+            ON CONFLICT (relation_id, block_number, edit_index) DO UPDATE SET
+                edit_id = EXCLUDED.edit_id,
+                block_timestamp = EXCLUDED.block_timestamp,
+                authors = EXCLUDED.authors,
+                entity_id = EXCLUDED.entity_id,
+                type_id = EXCLUDED.type_id,
+                from_entity_id = EXCLUDED.from_entity_id,
+                from_space_id = EXCLUDED.from_space_id,
+                from_version_id = EXCLUDED.from_version_id,
+                to_entity_id = EXCLUDED.to_entity_id,
+                to_space_id = EXCLUDED.to_space_id,
+                to_version_id = EXCLUDED.to_version_id,
+                position = EXCLUDED.position,
+                space_id = EXCLUDED.space_id,
+                verified = EXCLUDED.verified,
+                deleted = EXCLUDED.deleted
+            "#,
+        )
+        .bind(&ids)
+        .bind(&deleted)
+        .bind(version.block_number as i64)
+        .bind(version.edit_index)
+        .bind(version.edit_id)
+        .bind(&version.block_timestamp)
+        .bind(&version.authors)
+        .execute(&mut *connection)
+        .await?;
+        metrics::record_rows_written("relation_versions", result.rows_affected());
+
+        Ok(())
+    }
+
+    /// Every row a block may write to is snapshotted as jsonb before the
+    /// block is written. Rows that don't exist yet are recorded with a null
+    /// snapshot so reverting the block deletes them again.
+    #[instrument(skip(self, keys))]
+    async fn snapshot_block(
+        &self,
+        block_number: u64,
+        keys: &BlockUndoKeys,
+    ) -> Result<(), StorageError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let block_number = block_number as i64;
+        let mut connection = self.connection().await?;
+        let mut tx = connection.begin().await?;
+
+        let id_keys = [
+            ("entities", "uuid", &keys.entity_ids),
+            ("properties", "uuid", &keys.property_ids),
+            ("values", "text", &keys.value_ids),
+            ("relations", "uuid", &keys.relation_ids),
+            ("spaces", "uuid", &keys.space_ids),
+        ];
+
+        for (table, id_type, ids) in id_keys {
+            let ids = ids.iter().map(|id| id.to_string()).collect();
+            snapshot_rows_by_id(&mut tx, block_number, table, id_type, ids).await?;
+        }
+
+        let members = keys
+            .members
+            .iter()
+            .map(|member| (member.address.clone(), member.space_id))
+            .collect();
+
+        let editors = keys
+            .editors
+            .iter()
+            .map(|editor| (editor.address.clone(), editor.space_id))
+            .collect();
+
+        snapshot_rows_by_membership(&mut tx, block_number, "members", members).await?;
+        snapshot_rows_by_membership(&mut tx, block_number, "editors", editors).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// A row may be snapshotted by several of the reverted blocks. Only the
+    /// earliest snapshot reflects the row's state as of `block_number`, so
+    /// every touched row is deleted and then restored from that snapshot.
+    #[instrument(skip(self))]
+    async fn revert_blocks_after(&self, block_number: u64) -> Result<Vec<Uuid>, StorageError> {
+        let block_number = block_number as i64;
+        let mut connection = self.connection().await?;
+        let mut tx = connection.begin().await?;
+
+        let removed_property_ids: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT row_key->>'id' FROM (
+                SELECT DISTINCT ON (row_key) row_key, snapshot
+                FROM block_undo_log
+                WHERE block_number > $1 AND table_name = 'properties'
+                ORDER BY row_key, id
+            ) AS earliest
+            WHERE snapshot IS NULL
+            "#,
+        )
+        .bind(block_number)
+        .fetch_all(&mut *tx)
         .await?;
 
+        for (table, key_columns) in UNDO_TABLES {
+            let key_condition = key_columns
+                .iter()
+                .map(|column| format!("t.{column} = k.{column}"))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+
+            sqlx::query(&format!(
+                r#"
+                DELETE FROM "{table}" t
+                USING (
+                    SELECT DISTINCT row_key FROM block_undo_log
+                    WHERE block_number > $1 AND table_name = $2
+                ) AS l,
+                LATERAL jsonb_populate_record(NULL::"{table}", l.row_key) AS k
+                WHERE {key_condition}
+                "#
+            ))
+            .bind(block_number)
+            .bind(table)
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query(&format!(
+                r#"
+                INSERT INTO "{table}"
+                SELECT (jsonb_populate_record(NULL::"{table}", earliest.snapshot)).*
+                FROM (
+                    SELECT DISTINCT ON (row_key) row_key, snapshot
+                    FROM block_undo_log
+                    WHERE block_number > $1 AND table_name = $2
+                    ORDER BY row_key, id
+                ) AS earliest
+                WHERE earliest.snapshot IS NOT NULL
+                "#
+            ))
+            .bind(block_number)
+            .bind(table)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // Edits, versions and rejected values are only ever appended so the
+        // ones written by the reverted blocks are dropped rather than
+        // restored.
+        for table in [
+            "edits",
+            "value_versions",
+            "relation_versions",
+            "rejected_values",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE block_number > $1"))
+                .bind(block_number)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query("DELETE FROM block_undo_log WHERE block_number > $1")
+            .bind(block_number)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        removed_property_ids
+            .iter()
+            .map(|id| {
+                Uuid::parse_str(id).map_err(|e| {
+                    StorageError::Database(sqlx::Error::Decode(
+                        format!("Invalid UUID format: {}", e).into(),
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn prune_undo_log(&self, final_block_number: u64) -> Result<(), StorageError> {
+        let mut connection = self.connection().await?;
+        sqlx::query("DELETE FROM block_undo_log WHERE block_number <= $1")
+            .bind(final_block_number as i64)
+            .execute(&mut *connection)
+            .await?;
+
         Ok(())
     }
+
+    async fn begin_block(&self) -> Result<Self, StorageError> {
+        Ok(PostgresStorage {
+            pool: self.pool.clone(),
+            block: Some(Mutex::new(self.pool.begin().await?)),
+        })
+    }
+
+    #[instrument(skip(self, cursor), fields(block_number = cursor.block_number))]
+    async fn commit_block(
+        self,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> Result<(), StorageError> {
+        let Some(block) = self.block else {
+            PostgresCursorStore::persist_with(&self.pool, sink_name, module_hash, cursor).await?;
+            return Ok(());
+        };
+
+        let mut transaction = block.into_inner();
+        PostgresCursorStore::persist_with(&mut *transaction, sink_name, module_hash, cursor)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn rollback_block(self) -> Result<(), StorageError> {
+        if let Some(block) = self.block {
+            block.into_inner().rollback().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Flattens the ids an edit wrote and deleted into the ids to version and
+/// whether each of them was deleted.
+fn version_keys(ids: &[Uuid], deleted_ids: &[Uuid]) -> (Vec<Uuid>, Vec<bool>) {
+    let keys: Vec<(Uuid, bool)> = ids
+        .iter()
+        .map(|id| (*id, false))
+        .chain(deleted_ids.iter().map(|id| (*id, true)))
+        .collect();
+
+    keys.into_iter().unzip()
+}
+
+async fn snapshot_rows_by_id(
+    connection: &mut PgConnection,
+    block_number: i64,
+    table: &str,
+    id_type: &str,
+    ids: Vec<String>,
+) -> Result<(), StorageError> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO block_undo_log (block_number, table_name, row_key, snapshot)
+        SELECT $1, $2, jsonb_build_object('id', k.id),
+            CASE WHEN t.id IS NULL THEN NULL ELSE to_jsonb(t) END
+        FROM UNNEST($3::text[]) AS k(id)
+        LEFT JOIN "{table}" t ON t.id = k.id::{id_type}
+        "#
+    ))
+    .bind(block_number)
+    .bind(table)
+    .bind(&ids)
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+async fn snapshot_rows_by_membership(
+    connection: &mut PgConnection,
+    block_number: i64,
+    table: &str,
+    keys: Vec<(String, Uuid)>,
+) -> Result<(), StorageError> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let (addresses, space_ids): (Vec<String>, Vec<Uuid>) = keys.into_iter().unzip();
+
+    sqlx::query(&format!(
+        r#"
+        INSERT INTO block_undo_log (block_number, table_name, row_key, snapshot)
+        SELECT $1, $2, jsonb_build_object('address', k.address, 'space_id', k.space_id),
+            CASE WHEN t.address IS NULL THEN NULL ELSE to_jsonb(t) END
+        FROM UNNEST($3::text[], $4::uuid[]) AS k(address, space_id)
+        LEFT JOIN "{table}" t ON t.address = k.address AND t.space_id = k.space_id
+        "#
+    ))
+    .bind(block_number)
+    .bind(table)
+    .bind(&addresses)
+    .bind(&space_ids)
+    .execute(connection)
+    .await?;
+
+    Ok(())
+}
+
+/// The typed columns of a value. Times are written as RFC 3339 strings and
+/// cast by Postgres, so the indexer doesn't need chrono support in sqlx.
+#[derive(Default)]
+struct TypedColumns {
+    number: Option<f64>,
+    boolean: Option<bool>,
+    time: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+}
+
+impl From<Option<&ValidatedValue>> for TypedColumns {
+    fn from(value: Option<&ValidatedValue>) -> Self {
+        match value {
+            Some(ValidatedValue::Number(number)) => TypedColumns {
+                number: Some(*number),
+                ..Default::default()
+            },
+            Some(ValidatedValue::Checkbox(boolean)) => TypedColumns {
+                boolean: Some(*boolean),
+                ..Default::default()
+            },
+            Some(ValidatedValue::Time(time)) => TypedColumns {
+                time: Some(time.to_rfc3339()),
+                ..Default::default()
+            },
+            // Points don't specify a coordinate system, so they're kept as
+            // the coordinates they were published with.
+            Some(ValidatedValue::Point(point)) => TypedColumns {
+                x: Some(point.x),
+                y: Some(point.y),
+                ..Default::default()
+            },
+            Some(ValidatedValue::Text(_)) | None => TypedColumns::default(),
+        }
+    }
 }
 
 fn string_to_data_type(s: &str) -> Option<DataType> {
@@ -157,6 +157,33 @@ impl TestStorage {
 
         Ok(())
     }
+
+    /// Test helper: Empty a table, including the space its rows took up, so
+    /// rows written afterwards are laid out from the start of the table
+    pub async fn truncate_table(&self, table_name: &str) -> Result<(), IndexingError> {
+        let query = format!("TRUNCATE {}", table_name);
+        sqlx::query(&query)
+            .execute(self.get_pool())
+            .await
+            .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+
+        Ok(())
+    }
+
+    /// Test helper: Dump every row of a table as JSON in the order the rows
+    /// are stored in
+    pub async fn dump_table(&self, table_name: &str) -> Result<Vec<String>, IndexingError> {
+        let query = format!(
+            "SELECT row_to_json(t)::text AS row FROM {} t ORDER BY ctid",
+            table_name
+        );
+        let rows = sqlx::query(&query)
+            .fetch_all(self.get_pool())
+            .await
+            .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+
+        Ok(rows.into_iter().map(|row| row.get("row")).collect())
+    }
 }
 
 /// Test data structures for database row verification
@@ -24,6 +24,20 @@ pub enum ValidationError {
     IncorrectDecimalPlaces(usize, usize), // (expected, found)
 }
 
+impl ValidationError {
+    /// The name of the variant, without the details some variants carry.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ValidationError::EmptyInput => "EmptyInput",
+            ValidationError::InvalidCharacters => "InvalidCharacters",
+            ValidationError::MultipleDecimalPoints => "MultipleDecimalPoints",
+            ValidationError::ParseFailure => "ParseFailure",
+            ValidationError::MissingDecimalPoint => "MissingDecimalPoint",
+            ValidationError::IncorrectDecimalPlaces(_, _) => "IncorrectDecimalPlaces",
+        }
+    }
+}
+
 impl fmt::Display for ValidationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -8,21 +8,36 @@ use std::{
     hash::{Hash, Hasher},
     sync::Arc,
 };
-use stream::utils::BlockMetadata;
+use stream::{
+    cursor::{Cursor, CursorStore, PostgresCursorStore},
+    pb::sf::substreams::{rpc::v2::BlockUndoSignal, v1::BlockRef},
+    utils::BlockMetadata,
+};
 use uuid::Uuid;
 
 use dotenv::dotenv;
 use indexer::{
-    block_handler::root_handler,
-    cache::{properties_cache::PropertiesCache, PreprocessedEdit},
+    block_handler::{root_handler, undo_handler},
+    cache::{
+        properties_cache::{ImmutableCache, PropertiesCache},
+        PreprocessedEdit,
+    },
     error::IndexingError,
-    models::properties::DataType,
-    storage::{postgres::PostgresStorage, StorageError},
+    models::{
+        properties::{DataType, PropertyItem},
+        versions::EntityVersion,
+    },
+    storage::{postgres::PostgresStorage, StorageBackend, StorageError},
     test_utils::TestStorage,
     AddedMember, RemovedMember, CreatedSpace, PersonalSpace, PublicSpace, KgData,
 };
 use serial_test::serial;
-use indexer_utils::{checksum_address, id::derive_space_id, network_ids::GEO};
+use sqlx::Row;
+use indexer_utils::{
+    checksum_address,
+    id::{derive_space_id, derive_value_id},
+    network_ids::GEO,
+};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::sync::atomic::{AtomicU64, Ordering};
 
@@ -52,6 +67,10 @@ impl TestIndexer {
 
         Ok(())
     }
+
+    pub async fn undo(&self, undo_signal: &BlockUndoSignal) -> Result<(), IndexingError> {
+        undo_handler::run(undo_signal, &self.storage, &self.properties_cache).await
+    }
 }
 
 // @TODO: Different test for the cache preprocessing
@@ -63,6 +82,7 @@ async fn main() -> Result<(), IndexingError> {
     let storage = Arc::new(PostgresStorage::new(&database_url).await?);
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440007").unwrap(),
         is_errored: false,
         edit: Some(make_edit(
@@ -304,6 +324,7 @@ async fn test_validation_rejects_invalid_number() -> Result<(), IndexingError> {
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("55555555-5555-5555-5555-555555555555").unwrap(),
@@ -362,6 +383,7 @@ async fn test_validation_rejects_invalid_checkbox() -> Result<(), IndexingError>
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("aaaaaaaa-aaaa-aaaa-aaaa-aaaaaaaaaaaa").unwrap(),
@@ -420,6 +442,7 @@ async fn test_validation_rejects_invalid_time() -> Result<(), IndexingError> {
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("ffffffff-ffff-ffff-ffff-ffffffffffff").unwrap(),
@@ -478,6 +501,7 @@ async fn test_validation_rejects_invalid_point() -> Result<(), IndexingError> {
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("56789012-5678-5678-5678-567890123456").unwrap(),
@@ -561,6 +585,7 @@ async fn test_validation_allows_valid_data_mixed_with_invalid() -> Result<(), In
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("21098765-2109-2109-2109-210987654321").unwrap(),
@@ -618,21 +643,6 @@ async fn test_validation_allows_valid_data_mixed_with_invalid() -> Result<(), In
     Ok(())
 }
 
-fn derive_value_id(entity_id: &Uuid, property_id: &Uuid, space_id: &Uuid) -> Uuid {
-    let mut hasher = DefaultHasher::new();
-    entity_id.hash(&mut hasher);
-    property_id.hash(&mut hasher);
-    space_id.hash(&mut hasher);
-    let hash_value = hasher.finish();
-
-    // Create a deterministic UUID from the hash
-    let mut bytes = [0u8; 16];
-    bytes[0..8].copy_from_slice(&hash_value.to_be_bytes());
-    bytes[8..16].copy_from_slice(&hash_value.to_be_bytes());
-
-    Uuid::from_bytes(bytes)
-}
-
 #[tokio::test]
 #[serial]
 async fn test_property_no_overwrite() -> Result<(), IndexingError> {
@@ -642,6 +652,7 @@ async fn test_property_no_overwrite() -> Result<(), IndexingError> {
 
     // First edit - create property with Text type
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440005").unwrap(),
         edit: Some(make_edit(
             "f47ac10b-58cc-4372-a567-0e02b2c3d481",
@@ -657,6 +668,7 @@ async fn test_property_no_overwrite() -> Result<(), IndexingError> {
 
     // Second edit - attempt to create same property with Number type
     let second_edit = PreprocessedEdit {
+        content_uri: String::new(),
         space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440006").unwrap(),
         edit: Some(make_edit(
             "f47ac10b-58cc-4372-a567-0e02b2c3d482",
@@ -743,6 +755,7 @@ async fn test_property_squashing() -> Result<(), IndexingError> {
 
     // Single edit with multiple CreateProperty ops for the same property ID
     let edit_with_duplicate_properties = PreprocessedEdit {
+        content_uri: String::new(),
         space_id: Uuid::parse_str("550e8400-e29b-41d4-a716-446655440008").unwrap(),
         edit: Some(make_edit(
             "f47ac10b-58cc-4372-a567-0e02b2c3d483",
@@ -1565,6 +1578,7 @@ async fn test_space_indexing_with_edits() -> Result<(), IndexingError> {
     );
 
     let item = PreprocessedEdit {
+        content_uri: String::new(),
         edit: Some(edit),
         is_errored: false,
         space_id: Uuid::parse_str("3cc6995f-6cc2-4c7a-9592-1466bf95f6be").unwrap(),
@@ -1584,3 +1598,1173 @@ async fn test_space_indexing_with_edits() -> Result<(), IndexingError> {
 
     Ok(())
 }
+
+// Reorg tests index blocks far above the block numbers used by the other
+// tests so reverting them never touches rows written by other tests.
+const REORG_BASE_BLOCK: u64 = 1_000_000_000;
+
+fn make_undo_signal(last_valid_block: u64) -> BlockUndoSignal {
+    BlockUndoSignal {
+        last_valid_block: Some(BlockRef {
+            id: format!("block-{}", last_valid_block),
+            number: last_valid_block,
+        }),
+        last_valid_cursor: last_valid_block.to_string(),
+    }
+}
+
+async fn clear_reorg_undo_log(test_storage: &TestStorage) -> Result<(), IndexingError> {
+    sqlx::query("DELETE FROM block_undo_log WHERE block_number > $1")
+        .bind(REORG_BASE_BLOCK as i64)
+        .execute(test_storage.get_pool())
+        .await
+        .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reorg_reverts_blocks_after_last_valid_block() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let properties_cache = Arc::new(PropertiesCache::new());
+    let indexer = TestIndexer::new(storage.clone(), properties_cache.clone());
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let property_id = Uuid::new_v4();
+    let relation_id = Uuid::new_v4();
+    let dao_address = generate_unique_address("reorg_test_dao");
+    let member_address = generate_unique_address("reorg_test_member");
+    let editor_address = generate_unique_address("reorg_test_editor");
+
+    let first_block = KgData {
+        edits: vec![PreprocessedEdit {
+            content_uri: String::new(),
+            space_id,
+            is_errored: false,
+            edit: Some(make_edit(
+                &Uuid::new_v4().to_string(),
+                "First",
+                &Uuid::new_v4().to_string(),
+                vec![
+                    make_property_op(&property_id.to_string(), PbDataType::Text),
+                    make_entity_op(
+                        TestEntityOpType::UPDATE,
+                        &entity_id.to_string(),
+                        vec![TestValue {
+                            property_id: property_id.to_string(),
+                            value: Some("first".to_string()),
+                        }],
+                    ),
+                    make_relation_op(
+                        TestRelationOpType::CREATE,
+                        &relation_id.to_string(),
+                        &Uuid::new_v4().to_string(),
+                        &Uuid::new_v4().to_string(),
+                        &entity_id.to_string(),
+                        &Uuid::new_v4().to_string(),
+                    ),
+                ],
+            )),
+        }],
+        spaces: vec![make_personal_space(&dao_address)],
+        added_members: vec![make_added_member(&dao_address, &member_address)],
+        added_editors: vec![make_added_member(&dao_address, &editor_address)],
+        ..make_kg_data_with_spaces(REORG_BASE_BLOCK + 1, vec![], vec![])
+    };
+
+    let second_block = KgData {
+        edits: vec![PreprocessedEdit {
+            content_uri: String::new(),
+            space_id,
+            is_errored: false,
+            edit: Some(make_edit(
+                &Uuid::new_v4().to_string(),
+                "Second",
+                &Uuid::new_v4().to_string(),
+                vec![
+                    make_entity_op(
+                        TestEntityOpType::UPDATE,
+                        &entity_id.to_string(),
+                        vec![TestValue {
+                            property_id: property_id.to_string(),
+                            value: Some("second".to_string()),
+                        }],
+                    ),
+                    make_relation_op(
+                        TestRelationOpType::DELETE,
+                        &relation_id.to_string(),
+                        &Uuid::new_v4().to_string(),
+                        &Uuid::new_v4().to_string(),
+                        &entity_id.to_string(),
+                        &Uuid::new_v4().to_string(),
+                    ),
+                ],
+            )),
+        }],
+        removed_members: vec![make_removed_member(&dao_address, &member_address)],
+        ..make_kg_data_with_spaces(REORG_BASE_BLOCK + 2, vec![], vec![])
+    };
+
+    indexer.run(&vec![first_block, second_block]).await?;
+
+    let value_id = derive_value_id(&entity_id, &property_id, &space_id).to_string();
+    let membership_space_id = derive_space_id(GEO, &checksum_address(dao_address.clone()));
+    let member_address = checksum_address(member_address);
+    let editor_address = checksum_address(editor_address);
+
+    {
+        let value = storage.get_value(&value_id).await.unwrap();
+        assert_eq!(value.value, Some("second".to_string()));
+        assert!(storage
+            .get_relation(&relation_id.to_string())
+            .await
+            .is_err());
+        assert!(storage
+            .get_member(&member_address, &membership_space_id)
+            .await
+            .is_err());
+    }
+
+    // Reorg the second block out of the chain
+    indexer
+        .undo(&make_undo_signal(REORG_BASE_BLOCK + 1))
+        .await?;
+
+    {
+        let value = storage.get_value(&value_id).await.unwrap();
+        assert_eq!(value.value, Some("first".to_string()));
+        assert!(storage.get_relation(&relation_id.to_string()).await.is_ok());
+        assert!(storage
+            .get_member(&member_address, &membership_space_id)
+            .await
+            .is_ok());
+        assert!(storage.get_entity(&entity_id.to_string()).await.is_ok());
+    }
+
+    // Reorg the first block out of the chain
+    indexer.undo(&make_undo_signal(REORG_BASE_BLOCK)).await?;
+
+    {
+        assert!(storage.get_value(&value_id).await.is_err());
+        assert!(storage
+            .get_relation(&relation_id.to_string())
+            .await
+            .is_err());
+        assert!(storage.get_entity(&entity_id.to_string()).await.is_err());
+        assert!(storage
+            .get_property(&property_id.to_string())
+            .await
+            .is_err());
+        assert!(properties_cache.get(&property_id).await.is_err());
+        assert!(storage
+            .get_member(&member_address, &membership_space_id)
+            .await
+            .is_err());
+        assert!(storage
+            .get_editor(&editor_address, &membership_space_id)
+            .await
+            .is_err());
+
+        let spaces = test_storage
+            .get_spaces_by_dao_addresses(&[checksum_address(dao_address.clone())])
+            .await?;
+        assert!(spaces.is_empty());
+    }
+
+    // Reverted blocks are dropped from the undo log
+    let remaining: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM block_undo_log WHERE block_number > $1")
+            .bind(REORG_BASE_BLOCK as i64)
+            .fetch_one(test_storage.get_pool())
+            .await
+            .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+    assert_eq!(remaining, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reorg_restores_rows_written_before_reverted_blocks() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let properties_cache = Arc::new(PropertiesCache::new());
+    let indexer = TestIndexer::new(storage.clone(), properties_cache.clone());
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let property_id = Uuid::new_v4();
+
+    let make_block = |block_number: u64, ops: Vec<Op>| {
+        make_kg_data_with_spaces(
+            block_number,
+            vec![PreprocessedEdit {
+                content_uri: String::new(),
+                space_id,
+                is_errored: false,
+                edit: Some(make_edit(
+                    &Uuid::new_v4().to_string(),
+                    "Edit",
+                    &Uuid::new_v4().to_string(),
+                    ops,
+                )),
+            }],
+            vec![],
+        )
+    };
+
+    let set_value = |value: &str| {
+        make_entity_op(
+            TestEntityOpType::UPDATE,
+            &entity_id.to_string(),
+            vec![TestValue {
+                property_id: property_id.to_string(),
+                value: Some(value.to_string()),
+            }],
+        )
+    };
+
+    indexer
+        .run(&vec![
+            make_block(
+                REORG_BASE_BLOCK + 10,
+                vec![
+                    make_property_op(&property_id.to_string(), PbDataType::Number),
+                    set_value("1"),
+                ],
+            ),
+            make_block(REORG_BASE_BLOCK + 11, vec![set_value("2")]),
+            make_block(
+                REORG_BASE_BLOCK + 12,
+                vec![make_entity_op(
+                    TestEntityOpType::UNSET,
+                    &entity_id.to_string(),
+                    vec![TestValue {
+                        property_id: property_id.to_string(),
+                        value: None,
+                    }],
+                )],
+            ),
+        ])
+        .await?;
+
+    let value_id = derive_value_id(&entity_id, &property_id, &space_id).to_string();
+    assert!(storage.get_value(&value_id).await.is_err());
+
+    // Reverting several blocks at once restores the state as of the last
+    // valid block, even though the value was written by both reverted blocks.
+    indexer
+        .undo(&make_undo_signal(REORG_BASE_BLOCK + 10))
+        .await?;
+
+    let value = storage.get_value(&value_id).await.unwrap();
+    assert_eq!(value.value, Some("1".to_string()));
+
+    // The property was created before the last valid block so it survives
+    assert!(storage.get_property(&property_id.to_string()).await.is_ok());
+    assert_eq!(
+        properties_cache.get(&property_id).await.unwrap(),
+        DataType::Number
+    );
+
+    // Re-indexing the new canonical chain on top of the reverted state
+    indexer
+        .run(&vec![make_block(
+            REORG_BASE_BLOCK + 11,
+            vec![set_value("3")],
+        )])
+        .await?;
+
+    let value = storage.get_value(&value_id).await.unwrap();
+    assert_eq!(value.value, Some("3".to_string()));
+
+    indexer
+        .undo(&make_undo_signal(REORG_BASE_BLOCK + 9))
+        .await?;
+    assert!(storage.get_value(&value_id).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_block_is_written_atomically_with_its_cursor() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let postgres_storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(postgres_storage.clone());
+    let properties_cache = Arc::new(PropertiesCache::new());
+    let cursor_store = PostgresCursorStore::new(postgres_storage.pool.clone());
+
+    test_storage.clear_table("members").await?;
+    sqlx::query("DELETE FROM cursors WHERE sink_name = 'atomic_block_test'")
+        .execute(&postgres_storage.pool)
+        .await
+        .map_err(StorageError::from)?;
+
+    let dao_address = generate_unique_address("atomic_block_test_dao");
+    let member_address = generate_unique_address("atomic_block_test_mem");
+    let space_id = derive_space_id(GEO, &checksum_address(dao_address.to_string()));
+    let member_address = checksum_address(member_address.to_string());
+    let blocks = vec![make_kg_data_with_membership(
+        1,
+        vec![make_added_member(&dao_address, &member_address)],
+        vec![],
+        vec![],
+        vec![],
+    )];
+    let cursor = Cursor {
+        cursor: "cursor-1".to_string(),
+        block_number: 1,
+    };
+
+    // A block rolled back, e.g., because one of its writes failed, leaves
+    // nothing behind
+    let block = Arc::new(postgres_storage.begin_block().await?);
+    TestIndexer::new(block.clone(), properties_cache.clone())
+        .run(&blocks)
+        .await?;
+    Arc::into_inner(block).unwrap().rollback_block().await?;
+
+    assert!(postgres_storage
+        .get_member(&member_address, &space_id)
+        .await
+        .is_err());
+    assert_eq!(cursor_store.load("atomic_block_test", "hash").await?, None);
+
+    // A committed block is written along with its cursor
+    let block = Arc::new(postgres_storage.begin_block().await?);
+    TestIndexer::new(block.clone(), properties_cache.clone())
+        .run(&blocks)
+        .await?;
+    Arc::into_inner(block)
+        .unwrap()
+        .commit_block("atomic_block_test", "hash", &cursor)
+        .await?;
+
+    assert!(postgres_storage
+        .get_member(&member_address, &space_id)
+        .await
+        .is_ok());
+    assert_eq!(
+        cursor_store.load("atomic_block_test", "hash").await?,
+        Some(cursor)
+    );
+
+    Ok(())
+}
+
+const DETERMINISM_TABLES: [&str; 8] = [
+    "edits",
+    "entities",
+    "properties",
+    "values",
+    "relations",
+    "value_versions",
+    "relation_versions",
+    "rejected_values",
+];
+
+/// A block whose edits touch the same values and relations, so the state it
+/// leaves behind depends on the order its edits and their ops are applied in.
+fn make_overlapping_edits_block(
+    space_id: Uuid,
+    property_id: Uuid,
+    entity_ids: &[Uuid],
+    relation_ids: &[Uuid],
+) -> KgData {
+    let set_values = |value: &str| {
+        entity_ids
+            .iter()
+            .map(|entity_id| {
+                make_entity_op(
+                    TestEntityOpType::UPDATE,
+                    &entity_id.to_string(),
+                    vec![TestValue {
+                        property_id: property_id.to_string(),
+                        value: Some(format!("{} {}", value, entity_id)),
+                    }],
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let relation_ops = |op_type: fn() -> TestRelationOpType| {
+        relation_ids
+            .iter()
+            .map(|relation_id| {
+                make_relation_op(
+                    op_type(),
+                    &relation_id.to_string(),
+                    &relation_id.to_string(),
+                    &property_id.to_string(),
+                    &entity_ids[0].to_string(),
+                    &entity_ids[1].to_string(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let make_preprocessed_edit = |name: &str, ops: Vec<Op>| PreprocessedEdit {
+        content_uri: format!("ipfs://{}", name),
+        space_id,
+        is_errored: false,
+        edit: Some(make_edit(
+            &Uuid::new_v4().to_string(),
+            name,
+            &Uuid::new_v4().to_string(),
+            ops,
+        )),
+    };
+
+    let mut first_ops = vec![make_property_op(&property_id.to_string(), PbDataType::Text)];
+    first_ops.extend(set_values("first"));
+    first_ops.extend(relation_ops(|| TestRelationOpType::CREATE));
+
+    let mut second_ops = set_values("second");
+    second_ops.extend(relation_ops(|| TestRelationOpType::UPDATE));
+    second_ops.push(make_entity_op(
+        TestEntityOpType::UNSET,
+        &entity_ids[0].to_string(),
+        vec![TestValue {
+            property_id: property_id.to_string(),
+            value: None,
+        }],
+    ));
+
+    let mut third_ops = set_values("third");
+    third_ops.extend(relation_ops(|| TestRelationOpType::DELETE));
+    third_ops.extend(relation_ops(|| TestRelationOpType::CREATE));
+
+    KgData {
+        edits: vec![
+            make_preprocessed_edit("first", first_ops),
+            make_preprocessed_edit("second", second_ops),
+            make_preprocessed_edit("third", third_ops),
+        ],
+        ..make_kg_data_with_spaces(1, vec![], vec![])
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reindexing_produces_identical_database() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+
+    let space_id = Uuid::new_v4();
+    let property_id = Uuid::new_v4();
+    let entity_ids: Vec<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+    let relation_ids: Vec<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+    let block = make_overlapping_edits_block(space_id, property_id, &entity_ids, &relation_ids);
+
+    let mut dumps = Vec::new();
+    for _ in 0..3 {
+        for table in DETERMINISM_TABLES {
+            test_storage.truncate_table(table).await?;
+        }
+
+        let indexer = TestIndexer::new(storage.clone(), Arc::new(PropertiesCache::new()));
+        indexer.run(&vec![block.clone()]).await?;
+
+        let mut dump = Vec::new();
+        for table in DETERMINISM_TABLES {
+            dump.push(test_storage.dump_table(table).await?);
+        }
+        dumps.push(dump);
+    }
+
+    assert!(dumps.iter().all(|dump| *dump == dumps[0]));
+
+    // Edits are applied in the order they were published in
+    let value_id = derive_value_id(&entity_ids[0], &property_id, &space_id);
+    let value = storage.get_value(&value_id.to_string()).await.unwrap();
+    assert_eq!(value.value, Some(format!("third {}", entity_ids[0])));
+    assert_eq!(
+        test_storage.count_records("relations").await?,
+        relation_ids.len() as i64
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_migrates_value_ids() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let property_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let created_entity_id = Uuid::new_v4();
+    let legacy_id = Uuid::new_v4().to_string();
+    let created_legacy_id = Uuid::new_v4().to_string();
+
+    // Values keyed by IDs derived the old way, one of them snapshotted
+    // before a block that hasn't been finalized yet and the other created by it
+    sqlx::query(
+        r#"
+        INSERT INTO values (id, entity_id, property_id, space_id, value)
+        VALUES ($1, $2, $4, $5, 'existing'), ($3, $6, $4, $5, 'created')
+        "#,
+    )
+    .bind(&legacy_id)
+    .bind(entity_id)
+    .bind(&created_legacy_id)
+    .bind(property_id)
+    .bind(space_id.to_string())
+    .bind(created_entity_id)
+    .execute(test_storage.get_pool())
+    .await
+    .map_err(StorageError::from)?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO block_undo_log (block_number, table_name, row_key, snapshot)
+        SELECT $1, 'values', jsonb_build_object('id', $2::text), to_jsonb(v)
+        FROM values v WHERE v.id = $2
+        UNION ALL
+        SELECT $1, 'values', jsonb_build_object('id', $3::text), NULL
+        "#,
+    )
+    .bind((REORG_BASE_BLOCK + 1) as i64)
+    .bind(&legacy_id)
+    .bind(&created_legacy_id)
+    .execute(test_storage.get_pool())
+    .await
+    .map_err(StorageError::from)?;
+
+    assert!(storage.migrate_value_ids().await? >= 2);
+
+    // Value IDs derived in SQL match the ones derived by the indexer
+    let value_id = derive_value_id(&entity_id, &property_id, &space_id).to_string();
+    let created_value_id = derive_value_id(&created_entity_id, &property_id, &space_id).to_string();
+    assert_eq!(
+        storage.get_value(&value_id).await.unwrap().value,
+        Some("existing".to_string())
+    );
+    assert_eq!(
+        storage.get_value(&created_value_id).await.unwrap().value,
+        Some("created".to_string())
+    );
+    assert!(storage.get_value(&legacy_id).await.is_err());
+
+    let undo_keys: Vec<(String, Option<String>)> = sqlx::query_as(
+        r#"
+        SELECT row_key->>'id', snapshot->>'id' FROM block_undo_log
+        WHERE block_number > $1 AND table_name = 'values'
+        ORDER BY id
+        "#,
+    )
+    .bind(REORG_BASE_BLOCK as i64)
+    .fetch_all(test_storage.get_pool())
+    .await
+    .map_err(StorageError::from)?;
+    assert_eq!(
+        undo_keys,
+        vec![
+            (value_id.clone(), Some(value_id.clone())),
+            (created_value_id, None)
+        ]
+    );
+
+    // Migrated values are left untouched when migrating again
+    assert_eq!(storage.migrate_value_ids().await?, 0);
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_reads_entity_as_of_block_and_edit() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let indexer = TestIndexer::new(storage.clone(), Arc::new(PropertiesCache::new()));
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let name_property_id = Uuid::new_v4();
+    let description_property_id = Uuid::new_v4();
+    let relation_id = Uuid::new_v4();
+    let edit_ids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+    let block_number = REORG_BASE_BLOCK + 20;
+
+    let set_value = |property_id: &Uuid, value: &str| {
+        make_entity_op(
+            TestEntityOpType::UPDATE,
+            &entity_id.to_string(),
+            vec![TestValue {
+                property_id: property_id.to_string(),
+                value: Some(value.to_string()),
+            }],
+        )
+    };
+    let relation_op = |op_type: TestRelationOpType| {
+        make_relation_op(
+            op_type,
+            &relation_id.to_string(),
+            &relation_id.to_string(),
+            &name_property_id.to_string(),
+            &entity_id.to_string(),
+            &Uuid::new_v4().to_string(),
+        )
+    };
+    let make_preprocessed_edit = |edit_id: &Uuid, ops: Vec<Op>| PreprocessedEdit {
+        content_uri: String::new(),
+        space_id,
+        is_errored: false,
+        edit: Some(make_edit(
+            &edit_id.to_string(),
+            "Edit",
+            &Uuid::new_v4().to_string(),
+            ops,
+        )),
+    };
+    let make_block = |number: u64, edits: Vec<PreprocessedEdit>| KgData {
+        edits,
+        ..make_kg_data_with_spaces(number, vec![], vec![])
+    };
+
+    indexer
+        .run(&vec![
+            make_block(
+                block_number,
+                vec![make_preprocessed_edit(
+                    &edit_ids[0],
+                    vec![
+                        make_property_op(&name_property_id.to_string(), PbDataType::Text),
+                        make_property_op(&description_property_id.to_string(), PbDataType::Text),
+                        set_value(&name_property_id, "first"),
+                        relation_op(TestRelationOpType::CREATE),
+                    ],
+                )],
+            ),
+            make_block(
+                block_number + 1,
+                vec![
+                    make_preprocessed_edit(
+                        &edit_ids[1],
+                        vec![set_value(&name_property_id, "second")],
+                    ),
+                    make_preprocessed_edit(
+                        &edit_ids[2],
+                        vec![
+                            set_value(&description_property_id, "description"),
+                            relation_op(TestRelationOpType::DELETE),
+                        ],
+                    ),
+                ],
+            ),
+            make_block(
+                block_number + 2,
+                vec![make_preprocessed_edit(
+                    &edit_ids[3],
+                    vec![make_entity_op(
+                        TestEntityOpType::UNSET,
+                        &entity_id.to_string(),
+                        vec![TestValue {
+                            property_id: name_property_id.to_string(),
+                            value: None,
+                        }],
+                    )],
+                )],
+            ),
+        ])
+        .await?;
+
+    let values_of = |version: &EntityVersion| {
+        version
+            .values
+            .iter()
+            .map(|value| (value.property_id, value.value.clone().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let mut both_values = vec![
+        (name_property_id, "second".to_string()),
+        (description_property_id, "description".to_string()),
+    ];
+    both_values.sort_by_key(|(property_id, _)| {
+        derive_value_id(&entity_id, property_id, &space_id).to_string()
+    });
+
+    let first = storage
+        .get_entity_at_block(&entity_id, block_number)
+        .await?;
+    assert_eq!(
+        values_of(&first),
+        vec![(name_property_id, "first".to_string())]
+    );
+    assert_eq!(first.relations.len(), 1);
+    assert_eq!(first.relations[0].id, relation_id);
+
+    // Edits in the same block are read up to and including the given edit
+    let second = storage
+        .get_entity_at_edit(&entity_id, &edit_ids[1], &space_id)
+        .await?;
+    assert_eq!(
+        values_of(&second),
+        vec![(name_property_id, "second".to_string())]
+    );
+    assert_eq!(second.relations.len(), 1);
+
+    let third = storage
+        .get_entity_at_edit(&entity_id, &edit_ids[2], &space_id)
+        .await?;
+    assert_eq!(values_of(&third), both_values);
+    assert!(third.relations.is_empty());
+
+    let latest = storage
+        .get_entity_at_block(&entity_id, block_number + 2)
+        .await?;
+    assert_eq!(
+        values_of(&latest),
+        vec![(description_property_id, "description".to_string())]
+    );
+
+    // Reverting a block drops the versions it wrote
+    indexer.undo(&make_undo_signal(block_number + 1)).await?;
+
+    let reverted = storage
+        .get_entity_at_block(&entity_id, block_number + 2)
+        .await?;
+    assert_eq!(values_of(&reverted), both_values);
+    assert!(storage
+        .get_entity_at_edit(&entity_id, &edit_ids[3], &space_id)
+        .await
+        .is_err());
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    Ok(())
+}
+
+async fn get_linked_edit_id(
+    test_storage: &TestStorage,
+    table: &str,
+    id: &str,
+) -> Result<Option<Uuid>, IndexingError> {
+    sqlx::query_scalar(&format!("SELECT edit_id FROM {table} WHERE id::text = $1"))
+        .bind(id)
+        .fetch_one(test_storage.get_pool())
+        .await
+        .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))
+}
+
+#[tokio::test]
+#[serial]
+async fn test_indexes_edits_and_links_values_and_relations() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let indexer = TestIndexer::new(storage.clone(), Arc::new(PropertiesCache::new()));
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let other_space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let property_id = Uuid::new_v4();
+    let relation_id = Uuid::new_v4();
+    let author = Uuid::new_v4();
+    let edit_ids: Vec<Uuid> = (0..2).map(|_| Uuid::new_v4()).collect();
+    let block_number = REORG_BASE_BLOCK + 30;
+
+    let set_value = |value: &str| {
+        make_entity_op(
+            TestEntityOpType::UPDATE,
+            &entity_id.to_string(),
+            vec![TestValue {
+                property_id: property_id.to_string(),
+                value: Some(value.to_string()),
+            }],
+        )
+    };
+    let make_preprocessed_edit = |edit_id: &Uuid, ops: Vec<Op>| PreprocessedEdit {
+        content_uri: format!("ipfs://{}", edit_id),
+        space_id,
+        is_errored: false,
+        edit: Some(make_edit(
+            &edit_id.to_string(),
+            "Edit",
+            &author.to_string(),
+            ops,
+        )),
+    };
+    let make_block = |number: u64, edits: Vec<PreprocessedEdit>| KgData {
+        edits,
+        ..make_kg_data_with_spaces(number, vec![], vec![])
+    };
+
+    indexer
+        .run(&vec![
+            make_block(
+                block_number,
+                vec![make_preprocessed_edit(
+                    &edit_ids[0],
+                    vec![
+                        make_property_op(&property_id.to_string(), PbDataType::Text),
+                        set_value("first"),
+                        make_relation_op(
+                            TestRelationOpType::CREATE,
+                            &relation_id.to_string(),
+                            &relation_id.to_string(),
+                            &property_id.to_string(),
+                            &entity_id.to_string(),
+                            &Uuid::new_v4().to_string(),
+                        ),
+                    ],
+                )],
+            ),
+            make_block(
+                block_number + 1,
+                vec![
+                    make_preprocessed_edit(&edit_ids[1], vec![set_value("second")]),
+                    // The first edit published again to another space
+                    PreprocessedEdit {
+                        content_uri: format!("ipfs://{}", edit_ids[0]),
+                        space_id: other_space_id,
+                        is_errored: false,
+                        edit: Some(make_edit(
+                            &edit_ids[0].to_string(),
+                            "Edit",
+                            &author.to_string(),
+                            vec![],
+                        )),
+                    },
+                ],
+            ),
+        ])
+        .await?;
+
+    let first = storage.get_edit(&edit_ids[0], &space_id).await?;
+    assert_eq!(first.name, "Edit");
+    assert_eq!(first.content_uri, format!("ipfs://{}", edit_ids[0]));
+    assert_eq!(first.space_id, space_id);
+    assert_eq!(first.block_number, block_number);
+    assert_eq!(first.edit_index, 0);
+    assert_eq!(first.authors, vec![author]);
+    assert_eq!(first.language, None);
+    assert_eq!(first.op_counts.create_property, 1);
+    assert_eq!(first.op_counts.update_entity, 1);
+    assert_eq!(first.op_counts.create_relation, 1);
+    assert_eq!(first.op_counts.total(), 3);
+
+    let republished = storage.get_edit(&edit_ids[0], &other_space_id).await?;
+    assert_eq!(republished.block_number, block_number + 1);
+    assert_eq!(republished.edit_index, 1);
+    assert_eq!(republished.op_counts.total(), 0);
+
+    let value_id = derive_value_id(&entity_id, &property_id, &space_id).to_string();
+    let relation_id = relation_id.to_string();
+
+    // The value was last set by the second edit, the relation by the first
+    assert_eq!(
+        get_linked_edit_id(&test_storage, "values", &value_id).await?,
+        Some(edit_ids[1])
+    );
+    assert_eq!(
+        get_linked_edit_id(&test_storage, "relations", &relation_id).await?,
+        Some(edit_ids[0])
+    );
+
+    // Reverting a block drops its edits and restores the links of the rows
+    // they touched
+    indexer.undo(&make_undo_signal(block_number)).await?;
+
+    assert!(storage.get_edit(&edit_ids[1], &space_id).await.is_err());
+    assert!(storage
+        .get_edit(&edit_ids[0], &other_space_id)
+        .await
+        .is_err());
+    assert!(storage.get_edit(&edit_ids[0], &space_id).await.is_ok());
+    assert_eq!(
+        get_linked_edit_id(&test_storage, "values", &value_id).await?,
+        Some(edit_ids[0])
+    );
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_loads_properties_cache_from_storage() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = PostgresStorage::new(&database_url).await?;
+
+    let properties: Vec<PropertyItem> = [
+        DataType::Text,
+        DataType::Number,
+        DataType::Checkbox,
+        DataType::Time,
+        DataType::Point,
+    ]
+    .into_iter()
+    .map(|data_type| PropertyItem {
+        id: Uuid::new_v4(),
+        data_type,
+    })
+    .collect();
+    storage.insert_properties(&properties).await?;
+
+    // Pages smaller than the properties inserted so loading has to page
+    // through them
+    let properties_cache = PropertiesCache::load(&storage, 2).await?;
+
+    for property in &properties {
+        assert_eq!(
+            properties_cache.get(&property.id).await.unwrap(),
+            property.data_type
+        );
+    }
+    assert!(properties_cache.get(&Uuid::new_v4()).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_records_rejected_values() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let indexer = TestIndexer::new(storage.clone(), Arc::new(PropertiesCache::new()));
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    let space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let number_property_id = Uuid::new_v4();
+    let unknown_property_id = Uuid::new_v4();
+    let edit_id = Uuid::new_v4();
+    let block_number = REORG_BASE_BLOCK + 40;
+
+    indexer
+        .run(&vec![KgData {
+            edits: vec![PreprocessedEdit {
+                content_uri: format!("ipfs://{}", edit_id),
+                space_id,
+                is_errored: false,
+                edit: Some(make_edit(
+                    &edit_id.to_string(),
+                    "Edit",
+                    &Uuid::new_v4().to_string(),
+                    vec![
+                        make_property_op(&number_property_id.to_string(), PbDataType::Number),
+                        make_entity_op(
+                            TestEntityOpType::UPDATE,
+                            &entity_id.to_string(),
+                            vec![
+                                TestValue {
+                                    property_id: number_property_id.to_string(),
+                                    value: Some("not-a-number".to_string()),
+                                },
+                                TestValue {
+                                    property_id: unknown_property_id.to_string(),
+                                    value: Some("orphan".to_string()),
+                                },
+                            ],
+                        ),
+                    ],
+                )),
+            }],
+            ..make_kg_data_with_spaces(block_number, vec![], vec![])
+        }])
+        .await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT property_id, value, data_type::text AS data_type, reason, content_uri,
+            block_number, edit_id
+        FROM rejected_values WHERE entity_id = $1
+        ORDER BY value
+        "#,
+    )
+    .bind(entity_id)
+    .fetch_all(test_storage.get_pool())
+    .await
+    .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+
+    let rejected: Vec<(Uuid, String, Option<String>, String)> = rows
+        .iter()
+        .map(|row| {
+            (
+                row.get("property_id"),
+                row.get("value"),
+                row.get("data_type"),
+                row.get("reason"),
+            )
+        })
+        .collect();
+    assert_eq!(
+        rejected,
+        vec![
+            (
+                number_property_id,
+                "not-a-number".to_string(),
+                Some("Number".to_string()),
+                "InvalidCharacters".to_string(),
+            ),
+            (
+                unknown_property_id,
+                "orphan".to_string(),
+                None,
+                "PropertyNotFound".to_string(),
+            ),
+        ]
+    );
+    for row in &rows {
+        assert_eq!(
+            row.get::<String, _>("content_uri"),
+            format!("ipfs://{}", edit_id)
+        );
+        assert_eq!(row.get::<i64, _>("block_number"), block_number as i64);
+        assert_eq!(row.get::<Uuid, _>("edit_id"), edit_id);
+    }
+
+    // Neither value was written
+    let value_id = derive_value_id(&entity_id, &number_property_id, &space_id).to_string();
+    assert!(storage.get_value(&value_id).await.is_err());
+
+    // Reverting the block drops its rejections
+    indexer.undo(&make_undo_signal(block_number - 1)).await?;
+
+    let remaining: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM rejected_values WHERE entity_id = $1")
+            .bind(entity_id)
+            .fetch_one(test_storage.get_pool())
+            .await
+            .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+    assert_eq!(remaining, 0);
+
+    clear_reorg_undo_log(&test_storage).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_writes_typed_value_columns() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let storage = Arc::new(PostgresStorage::new(&database_url).await?);
+    let test_storage = TestStorage::new(storage.clone());
+    let indexer = TestIndexer::new(storage.clone(), Arc::new(PropertiesCache::new()));
+
+    let space_id = Uuid::new_v4();
+    let entity_id = Uuid::new_v4();
+    let properties = [
+        (Uuid::new_v4(), PbDataType::Number, "1234.5"),
+        (Uuid::new_v4(), PbDataType::Checkbox, "1"),
+        (Uuid::new_v4(), PbDataType::Time, "2024-03-01T12:00:00Z"),
+        (Uuid::new_v4(), PbDataType::Point, "40.7128,-74.006"),
+        (Uuid::new_v4(), PbDataType::Text, "Hello"),
+    ];
+
+    let mut ops: Vec<Op> = properties
+        .iter()
+        .map(|(property_id, data_type, _)| make_property_op(&property_id.to_string(), *data_type))
+        .collect();
+    ops.push(make_entity_op(
+        TestEntityOpType::UPDATE,
+        &entity_id.to_string(),
+        properties
+            .iter()
+            .map(|(property_id, _, value)| TestValue {
+                property_id: property_id.to_string(),
+                value: Some(value.to_string()),
+            })
+            .collect(),
+    ));
+
+    indexer
+        .run(&vec![KgData {
+            edits: vec![PreprocessedEdit {
+                content_uri: String::new(),
+                space_id,
+                is_errored: false,
+                edit: Some(make_edit(
+                    &Uuid::new_v4().to_string(),
+                    "Edit",
+                    &Uuid::new_v4().to_string(),
+                    ops,
+                )),
+            }],
+            ..make_kg_data_with_spaces(53965, vec![], vec![])
+        }])
+        .await?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT property_id, value, number::text AS number, boolean,
+            extract(epoch FROM time)::bigint AS time, x, y
+        FROM values WHERE entity_id = $1
+        "#,
+    )
+    .bind(entity_id)
+    .fetch_all(test_storage.get_pool())
+    .await
+    .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+    assert_eq!(rows.len(), properties.len());
+
+    let row = |property_id: &Uuid| {
+        rows.iter()
+            .find(|row| row.get::<Uuid, _>("property_id") == *property_id)
+            .unwrap()
+    };
+
+    // The original string is kept next to the typed columns
+    let number = row(&properties[0].0);
+    assert_eq!(number.get::<String, _>("value"), "1234.5");
+    assert_eq!(
+        number.get::<Option<String>, _>("number"),
+        Some("1234.5".to_string())
+    );
+    assert_eq!(number.get::<Option<bool>, _>("boolean"), None);
+
+    let checkbox = row(&properties[1].0);
+    assert_eq!(checkbox.get::<Option<bool>, _>("boolean"), Some(true));
+    assert_eq!(checkbox.get::<Option<String>, _>("number"), None);
+
+    let time = row(&properties[2].0);
+    assert_eq!(time.get::<Option<i64>, _>("time"), Some(1709294400));
+
+    let point = row(&properties[3].0);
+    assert_eq!(point.get::<Option<f64>, _>("x"), Some(40.7128));
+    assert_eq!(point.get::<Option<f64>, _>("y"), Some(-74.006));
+
+    let text = row(&properties[4].0);
+    assert_eq!(text.get::<String, _>("value"), "Hello");
+    assert_eq!(text.get::<Option<String>, _>("number"), None);
+    assert_eq!(text.get::<Option<bool>, _>("boolean"), None);
+    assert_eq!(text.get::<Option<i64>, _>("time"), None);
+    assert_eq!(text.get::<Option<f64>, _>("x"), None);
+
+    Ok(())
+}
@@ -50,6 +50,7 @@ async fn main() -> Result<(), IndexingError> {
     let crypto_space_edit = Edit::decode(Bytes::from(crypto_space_bytes.unwrap()));
 
     let root_space_preprocessed_edit = PreprocessedEdit {
+        content_uri: String::new(),
         // For now we use a random UUID instead of the correct UUID for the root space
         space_id: Uuid::parse_str("8ef40bdd-cf69-4ad7-a9a1-f71c15653994").unwrap(),
         edit: Some(root_space_edit.clone().unwrap()),
@@ -57,6 +58,7 @@ async fn main() -> Result<(), IndexingError> {
     };
 
     let crypto_space_preprocessed_edit = PreprocessedEdit {
+        content_uri: String::new(),
         // For now we use a random UUID instead of the correct UUID for the crypto space
         space_id: Uuid::parse_str("aa84b08d-779a-495c-93f1-44e667baf6d7").unwrap(),
         edit: Some(crypto_space_edit.clone().unwrap()),
@@ -0,0 +1,485 @@
+use grc20::pb::{
+    chain::{
+        EditPublished, GeoOutput, GeoPersonalSpaceAdminPluginCreated, GeoSpaceCreated,
+        InitialEditorAdded,
+    },
+    grc20::{op::Payload, DataType as PbDataType, Edit, Entity, Op, Property, Relation, Value},
+};
+use prost::Message;
+use serial_test::serial;
+use std::{env, sync::Arc};
+use stream::{
+    cursor::{Cursor, CursorStore, PostgresCursorStore},
+    pb::sf::substreams::{
+        rpc::v2::{BlockScopedData, BlockUndoSignal, MapModuleOutput},
+        v1::{BlockRef, Clock},
+    },
+    replay::{BlockRecorder, ReplayStream},
+    substreams_stream::BlockResponse,
+    utils::BlockMetadata,
+    DecodedPreprocessedSink, PreprocessedSink,
+};
+use uuid::Uuid;
+
+use dotenv::dotenv;
+use indexer::{
+    cache::{postgres::PostgresCache, properties_cache::PropertiesCache},
+    error::IndexingError,
+    sink::{KgIndexer, SINK_NAME},
+    storage::{postgres::PostgresStorage, StorageError},
+    test_utils::TestStorage,
+    KgData,
+};
+use indexer_utils::{checksum_address, id::derive_space_id, network_ids::GEO};
+
+// The fixture is a recording in the format written by `SUBSTREAMS_RECORD_FILE`.
+// Its blocks are synthetic so the rows they index can be asserted exactly,
+// regenerate it with `cargo test --test replay -- --ignored` after changing
+// them. The edits they publish are read from the IPFS cache like in
+// production, so the test seeds the cache with them.
+const FIXTURE: &str = "./tests/fixtures/kg_indexer_blocks.bin";
+const MODULE_HASH: &str = "replay-test";
+const OUTPUT_TYPE_URL: &str = "type.googleapis.com/chain.GeoOutput";
+
+// Far above the blocks indexed by the other tests so reverting the fixture's
+// blocks never touches their rows.
+const BASE_BLOCK: u64 = 2_000_000_000;
+
+const DAO_ADDRESS: &str = "0x7a1f00000000000000000000000000000000d001";
+const EDITOR_ADDRESS: &str = "0x7a1f00000000000000000000000000000000e001";
+const AUTHOR: &str = "7a1f0000-0000-4000-8000-000000000001";
+
+const PROPERTY: &str = "7a1f0000-0000-4000-8000-000000000101";
+const RELATION_TYPE: &str = "7a1f0000-0000-4000-8000-000000000102";
+const ENTITY: &str = "7a1f0000-0000-4000-8000-000000000201";
+const TO_ENTITY: &str = "7a1f0000-0000-4000-8000-000000000202";
+const RELATION: &str = "7a1f0000-0000-4000-8000-000000000301";
+const RELATION_ENTITY: &str = "7a1f0000-0000-4000-8000-000000000302";
+
+const FIRST_EDIT: &str = "7a1f0000-0000-4000-8000-000000000401";
+const REORGANIZED_EDIT: &str = "7a1f0000-0000-4000-8000-000000000402";
+const REPLACING_EDIT: &str = "7a1f0000-0000-4000-8000-000000000403";
+
+fn uuid_bytes(id: &str) -> Vec<u8> {
+    Uuid::parse_str(id).unwrap().as_bytes().to_vec()
+}
+
+fn content_uri(edit_id: &str) -> String {
+    format!("ipfs://replay-{}", edit_id)
+}
+
+fn space_id() -> Uuid {
+    derive_space_id(GEO, &checksum_address(DAO_ADDRESS.to_string()))
+}
+
+fn make_edit(id: &str, name: &str, ops: Vec<Op>) -> Edit {
+    Edit {
+        id: uuid_bytes(id),
+        name: String::from(name),
+        ops,
+        authors: vec![uuid_bytes(AUTHOR)],
+        language: None,
+    }
+}
+
+fn set_value_op(value: &str) -> Op {
+    Op {
+        payload: Some(Payload::UpdateEntity(Entity {
+            id: uuid_bytes(ENTITY),
+            values: vec![Value {
+                property: uuid_bytes(PROPERTY),
+                value: value.to_string(),
+                options: None,
+            }],
+        })),
+    }
+}
+
+/// The edits published by the fixture's blocks, in the order they're
+/// published.
+fn edits() -> Vec<Edit> {
+    vec![
+        make_edit(
+            FIRST_EDIT,
+            "First edit",
+            vec![
+                Op {
+                    payload: Some(Payload::CreateProperty(Property {
+                        id: uuid_bytes(PROPERTY),
+                        data_type: PbDataType::Text as i32,
+                    })),
+                },
+                set_value_op("first"),
+                Op {
+                    payload: Some(Payload::CreateRelation(Relation {
+                        id: uuid_bytes(RELATION),
+                        r#type: uuid_bytes(RELATION_TYPE),
+                        entity: uuid_bytes(RELATION_ENTITY),
+                        from_entity: uuid_bytes(ENTITY),
+                        from_space: None,
+                        from_version: None,
+                        to_entity: uuid_bytes(TO_ENTITY),
+                        to_space: None,
+                        to_version: None,
+                        position: None,
+                        verified: None,
+                    })),
+                },
+            ],
+        ),
+        make_edit(
+            REORGANIZED_EDIT,
+            "Reorganized edit",
+            vec![set_value_op("reorganized")],
+        ),
+        make_edit(
+            REPLACING_EDIT,
+            "Replacing edit",
+            vec![set_value_op("replacing")],
+        ),
+    ]
+}
+
+fn publish_edit(edit_id: &str) -> EditPublished {
+    EditPublished {
+        content_uri: content_uri(edit_id),
+        plugin_address: format!("{}_personal_plugin", DAO_ADDRESS),
+        dao_address: DAO_ADDRESS.to_string(),
+    }
+}
+
+fn make_block(number: u64, output: GeoOutput) -> BlockResponse {
+    BlockResponse::New(BlockScopedData {
+        clock: Some(Clock {
+            id: format!("block-{}", number),
+            number,
+            timestamp: Some(prost_types::Timestamp {
+                seconds: 1_750_000_000 + number as i64,
+                nanos: 0,
+            }),
+        }),
+        cursor: format!("cursor-{}", number),
+        output: Some(MapModuleOutput {
+            name: "geo_out".to_string(),
+            map_output: Some(prost_types::Any {
+                type_url: OUTPUT_TYPE_URL.to_string(),
+                value: output.encode_to_vec(),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+/// Creates a personal space and publishes an edit to it, publishes a second
+/// edit in a block that is then reorganized, and publishes a third edit in
+/// the block replacing it.
+fn fixture_blocks() -> Vec<BlockResponse> {
+    vec![
+        make_block(
+            BASE_BLOCK + 1,
+            GeoOutput {
+                spaces_created: vec![GeoSpaceCreated {
+                    dao_address: DAO_ADDRESS.to_string(),
+                    space_address: format!("{}_space", DAO_ADDRESS),
+                }],
+                personal_plugins_created: vec![GeoPersonalSpaceAdminPluginCreated {
+                    dao_address: DAO_ADDRESS.to_string(),
+                    personal_admin_address: format!("{}_personal_plugin", DAO_ADDRESS),
+                    initial_editor: EDITOR_ADDRESS.to_string(),
+                }],
+                initial_editors_added: vec![InitialEditorAdded {
+                    addresses: vec![EDITOR_ADDRESS.to_string()],
+                    plugin_address: format!("{}_personal_plugin", DAO_ADDRESS),
+                    dao_address: DAO_ADDRESS.to_string(),
+                }],
+                edits_published: vec![publish_edit(FIRST_EDIT)],
+                ..Default::default()
+            },
+        ),
+        make_block(
+            BASE_BLOCK + 2,
+            GeoOutput {
+                edits_published: vec![publish_edit(REORGANIZED_EDIT)],
+                ..Default::default()
+            },
+        ),
+        BlockResponse::Undo(BlockUndoSignal {
+            last_valid_block: Some(BlockRef {
+                id: format!("block-{}", BASE_BLOCK + 1),
+                number: BASE_BLOCK + 1,
+            }),
+            last_valid_cursor: format!("cursor-{}", BASE_BLOCK + 1),
+        }),
+        make_block(
+            BASE_BLOCK + 2,
+            GeoOutput {
+                edits_published: vec![publish_edit(REPLACING_EDIT)],
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+#[tokio::test]
+#[ignore = "regenerates the fixture"]
+async fn record_fixture() {
+    let _ = std::fs::remove_file(FIXTURE);
+    let mut recorder = BlockRecorder::create(FIXTURE).await.unwrap();
+
+    for block in fixture_blocks() {
+        recorder.record(&block).await.unwrap();
+    }
+}
+
+async fn clear_fixture_rows(test_storage: &TestStorage) -> Result<(), sqlx::Error> {
+    let pool = test_storage.get_pool();
+    let space_id = space_id();
+    let entity_ids = [
+        Uuid::parse_str(ENTITY).unwrap(),
+        Uuid::parse_str(RELATION_ENTITY).unwrap(),
+    ];
+    let edit_uris: Vec<String> = [FIRST_EDIT, REORGANIZED_EDIT, REPLACING_EDIT]
+        .iter()
+        .map(|edit_id| content_uri(edit_id))
+        .collect();
+
+    sqlx::query("DELETE FROM values WHERE entity_id = ANY($1)")
+        .bind(&entity_ids[..])
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM value_versions WHERE entity_id = ANY($1)")
+        .bind(&entity_ids[..])
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM relations WHERE id = $1")
+        .bind(Uuid::parse_str(RELATION).unwrap())
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM relation_versions WHERE relation_id = $1")
+        .bind(Uuid::parse_str(RELATION).unwrap())
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM entities WHERE id = ANY($1)")
+        .bind(&entity_ids[..])
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM properties WHERE id = $1")
+        .bind(Uuid::parse_str(PROPERTY).unwrap())
+        .execute(pool)
+        .await?;
+
+    for table in ["edits", "editors", "members"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE space_id = $1"))
+            .bind(space_id)
+            .execute(pool)
+            .await?;
+    }
+
+    sqlx::query("DELETE FROM spaces WHERE id = $1")
+        .bind(space_id)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM ipfs_cache WHERE uri = ANY($1)")
+        .bind(&edit_uris)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM block_undo_log WHERE block_number > $1")
+        .bind(BASE_BLOCK as i64)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM cursors WHERE sink_name = $1 AND module_hash = $2")
+        .bind(SINK_NAME)
+        .bind(MODULE_HASH)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Seeds the IPFS cache with the fixture's edits, like the cache does before
+/// the indexer reads them.
+async fn seed_ipfs_cache(test_storage: &TestStorage) -> Result<(), sqlx::Error> {
+    for edit in edits() {
+        let edit_id = Uuid::from_slice(&edit.id).unwrap().to_string();
+
+        sqlx::query(
+            "INSERT INTO ipfs_cache (uri, json, block, space, is_errored) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(content_uri(&edit_id))
+        .bind(serde_json::to_value(&edit).unwrap())
+        .bind(BASE_BLOCK.to_string())
+        .bind(space_id())
+        .bind(false)
+        .execute(test_storage.get_pool())
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_replays_recorded_blocks() -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let test_storage = TestStorage::new(Arc::new(PostgresStorage::new(&database_url).await?));
+    let pool = test_storage.get_pool();
+    let database_error = |e| IndexingError::StorageError(StorageError::Database(e));
+
+    clear_fixture_rows(&test_storage)
+        .await
+        .map_err(database_error)?;
+    seed_ipfs_cache(&test_storage)
+        .await
+        .map_err(database_error)?;
+
+    let indexer = KgIndexer::new(
+        PostgresStorage::new(&database_url).await?,
+        PostgresCache::new().await?,
+        PropertiesCache::new(),
+    );
+    indexer
+        .run_stream(MODULE_HASH, 2, ReplayStream::new(FIXTURE, None, 0, 0))
+        .await
+        .expect("replaying the fixture should succeed");
+
+    // The reorganized edit's value was reverted and replaced
+    let entity_id = Uuid::parse_str(ENTITY).unwrap();
+    let values = test_storage.get_values_by_entity_id(&entity_id).await?;
+    assert_eq!(values.len(), 1);
+    assert_eq!(values[0].value, "replacing");
+    assert_eq!(values[0].space_id, space_id().to_string());
+
+    let value_edit_id: Option<Uuid> =
+        sqlx::query_scalar("SELECT edit_id FROM values WHERE entity_id = $1")
+            .bind(entity_id)
+            .fetch_one(pool)
+            .await
+            .map_err(database_error)?;
+    assert_eq!(
+        value_edit_id,
+        Some(Uuid::parse_str(REPLACING_EDIT).unwrap())
+    );
+
+    let relations = test_storage
+        .get_relations_by_entity_id(&Uuid::parse_str(RELATION_ENTITY).unwrap())
+        .await?;
+    assert_eq!(relations.len(), 1);
+    assert_eq!(relations[0].id, Uuid::parse_str(RELATION).unwrap());
+    assert_eq!(relations[0].from_entity_id, entity_id);
+    assert_eq!(
+        relations[0].to_entity_id,
+        Uuid::parse_str(TO_ENTITY).unwrap()
+    );
+
+    let edits: Vec<(Uuid, i64, Vec<Uuid>)> = sqlx::query_as(
+        "SELECT id, block_number, authors FROM edits WHERE space_id = $1 ORDER BY block_number",
+    )
+    .bind(space_id())
+    .fetch_all(pool)
+    .await
+    .map_err(database_error)?;
+    let author = Uuid::parse_str(AUTHOR).unwrap();
+    assert_eq!(
+        edits,
+        vec![
+            (
+                Uuid::parse_str(FIRST_EDIT).unwrap(),
+                (BASE_BLOCK + 1) as i64,
+                vec![author]
+            ),
+            (
+                Uuid::parse_str(REPLACING_EDIT).unwrap(),
+                (BASE_BLOCK + 2) as i64,
+                vec![author]
+            ),
+        ]
+    );
+
+    // The personal space is indexed with its initial editor as a member
+    let spaces = test_storage
+        .get_spaces_by_dao_addresses(&[checksum_address(DAO_ADDRESS.to_string())])
+        .await?;
+    assert_eq!(spaces.len(), 1);
+    assert_eq!(spaces[0].id, space_id());
+    assert!(spaces[0].is_personal());
+
+    for table in ["editors", "members"] {
+        let addresses: Vec<String> =
+            sqlx::query_scalar(&format!("SELECT address FROM {table} WHERE space_id = $1"))
+                .bind(space_id())
+                .fetch_all(pool)
+                .await
+                .map_err(database_error)?;
+        assert_eq!(
+            addresses,
+            vec![checksum_address(EDITOR_ADDRESS.to_string())]
+        );
+    }
+
+    let cursor = PostgresCursorStore::new(pool.clone())
+        .load(SINK_NAME, MODULE_HASH)
+        .await?
+        .expect("the cursor should be persisted");
+    assert_eq!(cursor.cursor, format!("cursor-{}", BASE_BLOCK + 2));
+    assert_eq!(cursor.block_number, BASE_BLOCK + 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn test_fails_writing_a_block_before_the_previous_one_is_committed(
+) -> Result<(), IndexingError> {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
+    let test_storage = TestStorage::new(Arc::new(PostgresStorage::new(&database_url).await?));
+    clear_fixture_rows(&test_storage)
+        .await
+        .map_err(|e| IndexingError::StorageError(StorageError::Database(e)))?;
+
+    let indexer = KgIndexer::new(
+        PostgresStorage::new(&database_url).await?,
+        PostgresCache::new().await?,
+        PropertiesCache::new(),
+    );
+    let block_data = BlockScopedData {
+        cursor: format!("cursor-{}", BASE_BLOCK + 1),
+        ..Default::default()
+    };
+    let kg_data = KgData {
+        block: BlockMetadata {
+            cursor: block_data.cursor.clone(),
+            block_number: BASE_BLOCK + 1,
+            timestamp: "0".to_string(),
+        },
+        edits: vec![],
+        added_editors: vec![],
+        removed_editors: vec![],
+        added_members: vec![],
+        removed_members: vec![],
+        spaces: vec![],
+    };
+
+    DecodedPreprocessedSink::process_block_scoped_data(&indexer, &block_data, kg_data.clone())
+        .await?;
+
+    // The first block's writes are still waiting on its cursor
+    let result =
+        DecodedPreprocessedSink::process_block_scoped_data(&indexer, &block_data, kg_data.clone())
+            .await;
+    assert!(matches!(
+        result,
+        Err(IndexingError::StorageError(StorageError::BlockNotCommitted))
+    ));
+
+    let cursor = Cursor {
+        cursor: block_data.cursor.clone(),
+        block_number: BASE_BLOCK + 1,
+    };
+    DecodedPreprocessedSink::persist_cursor(&indexer, MODULE_HASH, cursor).await?;
+    DecodedPreprocessedSink::process_block_scoped_data(&indexer, &block_data, kg_data).await?;
+
+    Ok(())
+}
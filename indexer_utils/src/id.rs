@@ -1,4 +1,5 @@
 use md5::{Digest, Md5};
+use sha2::Sha256;
 use uuid::{Builder, Uuid};
 
 use crate::checksum_address;
@@ -20,6 +21,26 @@ pub fn derive_space_id(network: &str, dao_address: &str) -> Uuid {
     Builder::from_random_bytes(hashed).into_uuid()
 }
 
+/// Derives the ID of the value an entity has for a property in a space.
+///
+/// The ID is the first 16 bytes of the SHA-256 hash of the entity, property
+/// and space IDs, each as its 16 big-endian bytes and concatenated in that
+/// order, with the version set to 4 and the variant set to RFC 4122, i.e.,
+/// `hash[6] = (hash[6] & 0x0f) | 0x40` and `hash[8] = (hash[8] & 0x3f) | 0x80`.
+/// This is stable across releases so clients can derive the same IDs.
+pub fn derive_value_id(entity_id: &Uuid, property_id: &Uuid, space_id: &Uuid) -> Uuid {
+    let mut hasher = Sha256::new();
+    hasher.update(entity_id.as_bytes());
+    hasher.update(property_id.as_bytes());
+    hasher.update(space_id.as_bytes());
+    let hashed: [u8; 32] = hasher.finalize().into();
+
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hashed[..16]);
+
+    Builder::from_random_bytes(bytes).into_uuid()
+}
+
 const BASE58_ALLOWED_CHARS: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
 
 pub fn encode_uuid_to_base58(val: &str) -> String {
@@ -107,6 +128,22 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_derive_value_id() {
+        let entity_id = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440001").unwrap();
+        let property_id = Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c1").unwrap();
+        let space_id = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440007").unwrap();
+
+        assert_eq!(
+            derive_value_id(&entity_id, &property_id, &space_id).to_string(),
+            "c5274669-b3a4-41a1-bb5b-2c880c23f1d7",
+        );
+        assert_ne!(
+            derive_value_id(&property_id, &entity_id, &space_id),
+            derive_value_id(&entity_id, &property_id, &space_id),
+        );
+    }
+
     #[test]
     fn test_encode_decode() {
         let uuid = "1cc6995f-6cc2-4c7a-9592-1466bf95f6be";
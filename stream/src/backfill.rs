@@ -0,0 +1,483 @@
+use std::{future::Future, time::Instant};
+
+use futures03::{StreamExt, TryStreamExt, stream};
+use sqlx::{Postgres, Row};
+use thiserror::Error;
+use tracing::{Instrument, info, info_span};
+
+use crate::{
+    config::RunConfig,
+    error::StreamError,
+    health::{self, Phase},
+    metrics,
+    pb::sf::substreams::v1::Package,
+    shutdown::with_shutdown_timeout,
+    sink::{Sink, block_stream, load_package},
+    substreams_stream::BlockResponse,
+};
+
+#[derive(Error, Debug)]
+pub enum BackfillError {
+    #[error("Backfill error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Backfill error: unable to resolve the head block")]
+    UnknownHead,
+}
+
+/// A range of blocks, `end` being exclusive like a stream's stop block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Durable storage for the block ranges a sink finished backfilling. Ranges
+/// are keyed by the name of the sink and the hash of the module graph it
+/// consumes, like cursors are.
+pub trait RangeStore: Send + Sync {
+    fn completed_ranges(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> impl Future<Output = Result<Vec<BlockRange>, BackfillError>> + Send;
+
+    fn complete_range(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+        range: &BlockRange,
+    ) -> impl Future<Output = Result<(), BackfillError>> + Send;
+}
+
+/// Stores completed ranges in the `backfill_ranges` table. The table schema
+/// is managed by the API project alongside the rest of the indexer tables.
+#[derive(Clone)]
+pub struct PostgresRangeStore {
+    pool: sqlx::Pool<Postgres>,
+}
+
+impl PostgresRangeStore {
+    pub fn new(pool: sqlx::Pool<Postgres>) -> Self {
+        PostgresRangeStore { pool }
+    }
+}
+
+impl RangeStore for PostgresRangeStore {
+    async fn completed_ranges(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> Result<Vec<BlockRange>, BackfillError> {
+        let rows = sqlx::query(
+            "SELECT start_block, end_block FROM backfill_ranges WHERE sink_name = $1 AND module_hash = $2",
+        )
+        .bind(sink_name)
+        .bind(module_hash)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| BlockRange {
+                start: row.get::<i64, _>("start_block") as u64,
+                end: row.get::<i64, _>("end_block") as u64,
+            })
+            .collect())
+    }
+
+    async fn complete_range(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+        range: &BlockRange,
+    ) -> Result<(), BackfillError> {
+        sqlx::query(
+            r#"
+            INSERT INTO backfill_ranges (sink_name, module_hash, start_block, end_block)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(sink_name)
+        .bind(module_hash)
+        .bind(range.start as i64)
+        .bind(range.end as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Splits the blocks of `[start, end)` not covered by `completed` into ranges
+/// of at most `range_size` blocks.
+pub fn pending_ranges(
+    start: u64,
+    end: u64,
+    range_size: u64,
+    completed: &[BlockRange],
+) -> Vec<BlockRange> {
+    let range_size = range_size.max(1);
+
+    let mut completed = completed.to_vec();
+    completed.sort();
+
+    let mut gaps = vec![];
+    let mut next = start;
+    for range in completed {
+        if range.start > next {
+            gaps.push(BlockRange {
+                start: next,
+                end: range.start.min(end),
+            });
+        }
+        next = next.max(range.end);
+
+        if next >= end {
+            break;
+        }
+    }
+    if next < end {
+        gaps.push(BlockRange { start: next, end });
+    }
+
+    gaps.into_iter()
+        .filter(|gap| gap.start < gap.end)
+        .flat_map(|gap| {
+            (gap.start..gap.end)
+                .step_by(range_size as usize)
+                .map(move |start| BlockRange {
+                    start,
+                    end: (start + range_size).min(gap.end),
+                })
+        })
+        .collect()
+}
+
+/// Fills a sink with the blocks of `[start_block, end_block)` by streaming
+/// ranges of blocks concurrently, each through its own stream. Only suitable
+/// for sinks whose output doesn't depend on the order blocks are processed
+/// in, e.g., the IPFS cache.
+///
+/// Each range is recorded in the `RangeStore` once every block in it has been
+/// processed, so a backfill interrupted by a crash only streams the ranges it
+/// didn't finish when run again. Ranges are streamed from their start again,
+/// so the sink may process a block more than once.
+///
+/// The sink's cursor is never persisted while backfilling. Once done, the
+/// sink streams sequentially from the backfill's end block as usual.
+pub struct Backfill<R> {
+    config: RunConfig,
+    sink_name: String,
+    range_store: R,
+    range_size: u64,
+    concurrency: usize,
+}
+
+impl<R: RangeStore> Backfill<R> {
+    /// Streams `concurrency` ranges of `range_size` blocks at a time. The
+    /// config's end block defaults to the current final head block.
+    pub fn new(
+        config: RunConfig,
+        sink_name: &str,
+        range_store: R,
+        range_size: u64,
+        concurrency: usize,
+    ) -> Self {
+        Backfill {
+            config,
+            sink_name: sink_name.to_string(),
+            range_store,
+            range_size: range_size.max(1),
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    /// Backfills every range that isn't complete yet, returning the block the
+    /// backfill ended at. Returns early without completing the ranges in
+    /// flight when shutdown is requested.
+    pub async fn run<S: Sink<T>, T: Send>(&self, sink: &S) -> Result<u64, StreamError> {
+        health::set_phase(Phase::Backfilling);
+
+        let (package, module_hash) = load_package(&self.config).await?;
+
+        let start = u64::try_from(self.config.start_block).unwrap_or(0);
+        let end = match self.config.end_block {
+            0 => self.head_block(&package, &module_hash).await?,
+            end_block => end_block,
+        };
+
+        let completed = self
+            .range_store
+            .completed_ranges(&self.sink_name, &module_hash)
+            .await?;
+        let pending = pending_ranges(start, end, self.range_size, &completed);
+
+        info!(
+            "Backfilling blocks {} to {} ({} ranges pending)",
+            start,
+            end,
+            pending.len()
+        );
+
+        let backfill = stream::iter(pending)
+            .take_until(self.config.shutdown.clone().cancelled_owned())
+            .map(Ok::<_, StreamError>)
+            .try_for_each_concurrent(self.concurrency, |range| {
+                let package = &package;
+                let module_hash = &module_hash;
+
+                let span = info_span!("backfill_range", start = range.start, end = range.end);
+
+                async move {
+                    self.backfill_range(sink, package, module_hash, range)
+                        .await?;
+
+                    // The range's stream ends early on shutdown, it's only
+                    // complete if it wasn't cut short.
+                    if self.config.shutdown.is_cancelled() {
+                        return Ok(());
+                    }
+
+                    self.range_store
+                        .complete_range(&self.sink_name, module_hash, &range)
+                        .await?;
+
+                    info!("Backfilled blocks {} to {}", range.start, range.end);
+                    Ok(())
+                }
+                .instrument(span)
+            });
+
+        with_shutdown_timeout(&self.config, backfill).await?;
+
+        Ok(end)
+    }
+
+    async fn backfill_range<S: Sink<T>, T: Send>(
+        &self,
+        sink: &S,
+        package: &Package,
+        module_hash: &str,
+        range: BlockRange,
+    ) -> Result<(), StreamError> {
+        // Only final blocks are streamed, so ranges never need to be undone.
+        let config = RunConfig {
+            start_block: range.start as i64,
+            end_block: range.end,
+            final_blocks_only: true,
+            ..self.config.clone()
+        };
+
+        let mut stream = block_stream(&config, package, module_hash, None).await?;
+
+        while let Some(response) = stream.next().await {
+            match response? {
+                BlockResponse::New(data) => {
+                    let started_at = Instant::now();
+                    sink.process_block_scoped_data(&data)
+                        .await
+                        .map_err(StreamError::sink)?;
+                    metrics::record_block_processed(&data, started_at.elapsed());
+                    health::record_block(&data);
+                }
+                BlockResponse::Undo(undo_signal) => sink
+                    .process_block_undo_signal(&undo_signal)
+                    .await
+                    .map_err(StreamError::sink)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The block after the current final head block, streamed by starting
+    /// the stream one block before the head.
+    async fn head_block(&self, package: &Package, module_hash: &str) -> Result<u64, StreamError> {
+        let config = RunConfig {
+            start_block: -1,
+            end_block: 0,
+            final_blocks_only: true,
+            ..self.config.clone()
+        };
+
+        let mut stream = block_stream(&config, package, module_hash, None).await?;
+
+        while let Some(response) = stream.next().await {
+            if let BlockResponse::New(data) = response? {
+                if let Some(clock) = data.clock {
+                    return Ok(clock.number + 1);
+                }
+            }
+        }
+
+        Err(BackfillError::UnknownHead.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+        test_utils::make_config,
+    };
+
+    fn range(start: u64, end: u64) -> BlockRange {
+        BlockRange { start, end }
+    }
+
+    #[test]
+    fn test_pending_ranges_splits_range() {
+        assert_eq!(
+            pending_ranges(10, 35, 10, &[]),
+            vec![range(10, 20), range(20, 30), range(30, 35)]
+        );
+        assert_eq!(pending_ranges(10, 10, 10, &[]), vec![]);
+    }
+
+    #[test]
+    fn test_pending_ranges_skips_completed_ranges() {
+        let completed = [range(30, 40), range(10, 20), range(45, 60)];
+
+        assert_eq!(
+            pending_ranges(10, 50, 10, &completed),
+            vec![range(20, 30), range(40, 45)]
+        );
+
+        // Ranges completed by a backfill that ended at an earlier head.
+        assert_eq!(
+            pending_ranges(0, 30, 10, &[range(0, 10), range(10, 15)]),
+            vec![range(15, 25), range(25, 30)]
+        );
+    }
+
+    #[derive(Default)]
+    struct TestRangeStore {
+        completed: Mutex<Vec<BlockRange>>,
+    }
+
+    impl RangeStore for &TestRangeStore {
+        async fn completed_ranges(
+            &self,
+            _sink_name: &str,
+            _module_hash: &str,
+        ) -> Result<Vec<BlockRange>, BackfillError> {
+            Ok(self.completed.lock().unwrap().clone())
+        }
+
+        async fn complete_range(
+            &self,
+            _sink_name: &str,
+            _module_hash: &str,
+            range: &BlockRange,
+        ) -> Result<(), BackfillError> {
+            self.completed.lock().unwrap().push(*range);
+            Ok(())
+        }
+    }
+
+    #[derive(Error, Debug)]
+    #[error("test sink error")]
+    struct TestSinkError;
+
+    #[derive(Default)]
+    struct TestSink {
+        processed: Mutex<Vec<u64>>,
+    }
+
+    impl Sink<()> for TestSink {
+        type Error = TestSinkError;
+
+        async fn process_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+        ) -> Result<(), Self::Error> {
+            let number = block_data.clock.as_ref().unwrap().number;
+            self.processed.lock().unwrap().push(number);
+            Ok(())
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            _undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            Err(TestSinkError)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfills_every_block_once() {
+        let config = RunConfig {
+            start_block: 1,
+            end_block: 26,
+            ..make_config("backfill-every-block", 30).await
+        };
+        let range_store = TestRangeStore::default();
+        let sink = TestSink::default();
+
+        let end = Backfill::new(config, "ipfs_cache", &range_store, 10, 3)
+            .run(&sink)
+            .await
+            .unwrap();
+
+        assert_eq!(end, 26);
+
+        let mut processed = sink.processed.lock().unwrap().clone();
+        processed.sort();
+        assert_eq!(processed, (1..26).collect::<Vec<_>>());
+
+        let mut completed = range_store.completed.lock().unwrap().clone();
+        completed.sort();
+        assert_eq!(completed, vec![range(1, 11), range(11, 21), range(21, 26)]);
+    }
+
+    #[tokio::test]
+    async fn test_resumes_unfinished_ranges() {
+        let config = RunConfig {
+            start_block: 1,
+            end_block: 31,
+            ..make_config("backfill-resume", 30).await
+        };
+        let range_store = TestRangeStore {
+            completed: Mutex::new(vec![range(1, 11), range(21, 31)]),
+        };
+        let sink = TestSink::default();
+
+        Backfill::new(config, "ipfs_cache", &range_store, 10, 2)
+            .run(&sink)
+            .await
+            .unwrap();
+
+        let mut processed = sink.processed.lock().unwrap().clone();
+        processed.sort();
+        assert_eq!(processed, (11..21).collect::<Vec<_>>());
+        assert_eq!(range_store.completed.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_records_backfilled_blocks() {
+        let config = RunConfig {
+            start_block: 1,
+            end_block: 21,
+            ..make_config("backfill-record", 20).await
+        };
+        let range_store = TestRangeStore::default();
+        let sink = TestSink::default();
+        let started_at = std::time::Instant::now();
+        let processed = metrics::BLOCKS.processed.get();
+
+        Backfill::new(config, "ipfs_cache", &range_store, 10, 2)
+            .run(&sink)
+            .await
+            .unwrap();
+
+        // Other tests may process blocks meanwhile, but never less than the
+        // blocks backfilled here.
+        assert!(metrics::BLOCKS.processed.get() >= processed + 20);
+        assert!(health::last_block_at().is_some_and(|at| at >= started_at));
+    }
+}
@@ -0,0 +1,162 @@
+use std::{env, fmt, sync::Arc, time::Duration};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::observer::StreamObserver;
+
+/// Reads the environment variable `name`, treating an empty value as unset,
+/// e.g., a variable left blank in a `.env` file.
+pub fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// A Substreams endpoint along with the API token to authenticate with.
+#[derive(Clone, PartialEq)]
+pub struct EndpointConfig {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl EndpointConfig {
+    pub fn new(url: &str, token: Option<&str>) -> Self {
+        EndpointConfig {
+            url: url.to_string(),
+            token: token.map(str::to_string),
+        }
+    }
+
+    /// Reads the primary endpoint from `SUBSTREAMS_ENDPOINT` and
+    /// `SUBSTREAMS_API_TOKEN`, followed by the fallback endpoints from
+    /// `SUBSTREAMS_ENDPOINT_1` and `SUBSTREAMS_API_TOKEN_1`,
+    /// `SUBSTREAMS_ENDPOINT_2` and so on, stopping at the first unset endpoint.
+    pub fn from_env() -> Vec<EndpointConfig> {
+        let mut endpoints = vec![];
+        for index in 0.. {
+            let suffix = match index {
+                0 => String::new(),
+                index => format!("_{}", index),
+            };
+
+            let Some(url) = env_var(&format!("SUBSTREAMS_ENDPOINT{}", suffix)) else {
+                break;
+            };
+
+            endpoints.push(EndpointConfig {
+                url,
+                token: env_var(&format!("SUBSTREAMS_API_TOKEN{}", suffix)),
+            });
+        }
+
+        endpoints
+    }
+}
+
+impl fmt::Debug for EndpointConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keeps tokens out of logs.
+        f.debug_struct("EndpointConfig")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Configuration for running a sink against a Substreams endpoint. Fields
+/// not covered by `new` default to streaming every block from genesis
+/// forever, e.g., `RunConfig { start_block, ..RunConfig::new(...) }`.
+#[derive(Clone)]
+pub struct RunConfig {
+    /// The endpoints to stream from, in order of preference. The stream fails
+    /// over to the next endpoint when one keeps failing, and periodically
+    /// tries to return to the first one.
+    pub endpoints: Vec<EndpointConfig>,
+    /// How many consecutive failures to connect to or stream from an endpoint
+    /// before failing over to the next one.
+    pub failover_after: u32,
+    /// How long to stream from a fallback endpoint before trying to return to
+    /// the primary endpoint.
+    pub primary_retry_interval: Duration,
+    pub spkg_file: String,
+    pub module_name: String,
+    pub start_block: i64,
+    /// The block to stop streaming at. `0` streams forever.
+    pub end_block: u64,
+    /// Only stream blocks once they are final. Final blocks can never be
+    /// reorganized, so sinks streaming final blocks only never receive a
+    /// `BlockUndoSignal`, at the cost of lagging behind the chain head.
+    ///
+    /// Sinks that don't support undoing blocks must set this.
+    pub final_blocks_only: bool,
+    /// How many blocks `PreprocessedSink` may preprocess concurrently ahead of
+    /// the block being processed. Blocks are still processed and their cursor
+    /// persisted in order. `1` preprocesses one block at a time.
+    pub preprocess_lookahead: usize,
+    /// Records every block the sink processes to this file, see
+    /// `replay::BlockRecorder`.
+    pub record_file: Option<String>,
+    /// Replays the blocks recorded in this file instead of streaming them
+    /// from the endpoint, see `replay::ReplayStream`.
+    pub replay_file: Option<String>,
+    /// Caches packages resolved from the registry in this directory, see
+    /// `package_cache::PackageCache`.
+    pub package_cache_dir: Option<String>,
+    /// Refuses to stream when the module hash of the package doesn't match,
+    /// e.g., to make sure a deployment runs the package it was tested with.
+    pub expected_module_hash: Option<String>,
+    /// Stops the stream from taking new blocks once cancelled, e.g., by
+    /// `shutdown::shutdown_on_signals`. The sink finishes the blocks in
+    /// flight and returns.
+    pub shutdown: CancellationToken,
+    /// How long to wait for blocks in flight once shutdown is requested
+    /// before giving up on them.
+    pub shutdown_timeout: Duration,
+    /// Receives the session, progress and debug snapshot messages streamed
+    /// alongside blocks, along with the module hash being streamed.
+    pub observer: Option<Arc<dyn StreamObserver>>,
+}
+
+impl RunConfig {
+    pub fn new(endpoints: Vec<EndpointConfig>, spkg_file: &str, module_name: &str) -> Self {
+        RunConfig {
+            endpoints,
+            failover_after: 3,
+            primary_retry_interval: Duration::from_secs(600),
+            spkg_file: spkg_file.to_string(),
+            module_name: module_name.to_string(),
+            start_block: 0,
+            end_block: 0,
+            final_blocks_only: false,
+            preprocess_lookahead: 1,
+            record_file: None,
+            replay_file: None,
+            package_cache_dir: None,
+            expected_module_hash: None,
+            shutdown: CancellationToken::new(),
+            shutdown_timeout: Duration::from_secs(30),
+            observer: None,
+        }
+    }
+}
+
+impl fmt::Debug for RunConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunConfig")
+            .field("endpoints", &self.endpoints)
+            .field("failover_after", &self.failover_after)
+            .field("primary_retry_interval", &self.primary_retry_interval)
+            .field("spkg_file", &self.spkg_file)
+            .field("module_name", &self.module_name)
+            .field("start_block", &self.start_block)
+            .field("end_block", &self.end_block)
+            .field("final_blocks_only", &self.final_blocks_only)
+            .field("preprocess_lookahead", &self.preprocess_lookahead)
+            .field("record_file", &self.record_file)
+            .field("replay_file", &self.replay_file)
+            .field("package_cache_dir", &self.package_cache_dir)
+            .field("expected_module_hash", &self.expected_module_hash)
+            .field("shutdown", &self.shutdown)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
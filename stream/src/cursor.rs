@@ -0,0 +1,433 @@
+use std::{future::Future, path::PathBuf};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+use sqlx::{PgExecutor, Postgres, Row};
+use thiserror::Error;
+
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::Modules,
+};
+
+#[derive(Error, Debug)]
+pub enum CursorError {
+    #[error("Cursor error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Cursor error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Cursor error: malformed cursor file {0}")]
+    Malformed(String),
+
+    #[error(
+        "Cursor error: {sink_name} has no cursor for module hash {module_hash} but has cursors for {}, the package changed since it last ran. Delete its cursors to stream from the start block",
+        persisted.join(", ")
+    )]
+    ModuleHashChanged {
+        sink_name: String,
+        module_hash: String,
+        persisted: Vec<String>,
+    },
+}
+
+/// A Substreams cursor along with the block it points at. The cursor itself
+/// is opaque, so we keep the block number next to it to be able to reason
+/// about how far a sink has progressed without decoding the cursor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    pub cursor: String,
+    pub block_number: u64,
+}
+
+impl Cursor {
+    /// The cursor to resume from once `block_data` has been processed.
+    pub fn from_block(block_data: &BlockScopedData) -> Self {
+        Cursor {
+            cursor: block_data.cursor.clone(),
+            block_number: block_data.clock.as_ref().map_or(0, |clock| clock.number),
+        }
+    }
+
+    /// The cursor to resume from once every block after the undo signal's
+    /// last valid block has been reverted.
+    pub fn from_undo(undo_signal: &BlockUndoSignal) -> Self {
+        Cursor {
+            cursor: undo_signal.last_valid_cursor.clone(),
+            block_number: undo_signal
+                .last_valid_block
+                .as_ref()
+                .map_or(0, |block| block.number),
+        }
+    }
+}
+
+/// Durable storage for cursors. Cursors are keyed by the name of the sink
+/// consuming the stream and the hash of the module graph it consumes, so a
+/// cursor persisted against one version of a package is never used to resume
+/// a stream for a different version.
+pub trait CursorStore: Send + Sync {
+    fn load(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> impl Future<Output = Result<Option<Cursor>, CursorError>> + Send;
+
+    fn persist(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> impl Future<Output = Result<(), CursorError>> + Send;
+
+    /// The module hashes the sink has persisted cursors for.
+    fn module_hashes(
+        &self,
+        sink_name: &str,
+    ) -> impl Future<Output = Result<Vec<String>, CursorError>> + Send;
+
+    /// Loads the sink's cursor like `load`, but fails when the sink only has
+    /// cursors persisted against other module hashes, i.e., the package was
+    /// changed underneath an existing cursor. Resuming would otherwise restart
+    /// streaming from the start block on top of the data the sink already
+    /// wrote with the previous package.
+    fn load_checked(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> impl Future<Output = Result<Option<Cursor>, CursorError>> + Send {
+        async move {
+            if let Some(cursor) = self.load(sink_name, module_hash).await? {
+                return Ok(Some(cursor));
+            }
+
+            let persisted = self.module_hashes(sink_name).await?;
+            if !persisted.is_empty() {
+                return Err(CursorError::ModuleHashChanged {
+                    sink_name: sink_name.to_string(),
+                    module_hash: module_hash.to_string(),
+                    persisted,
+                });
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+/// Computes a stable hash of the module graph for `output_module`. Any change
+/// to the package's modules or binaries results in a different hash.
+pub fn module_hash(modules: &Option<Modules>, output_module: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(output_module.as_bytes());
+
+    if let Some(modules) = modules {
+        hasher.update(modules.encode_to_vec());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Stores cursors in the `cursors` table. The table schema is managed by the
+/// API project alongside the rest of the indexer tables.
+#[derive(Clone)]
+pub struct PostgresCursorStore {
+    pool: sqlx::Pool<Postgres>,
+}
+
+impl PostgresCursorStore {
+    pub fn new(pool: sqlx::Pool<Postgres>) -> Self {
+        PostgresCursorStore { pool }
+    }
+
+    /// Writes the cursor using an arbitrary executor. Sinks that write their
+    /// data to the same database can pass their open transaction here so the
+    /// cursor is committed atomically with the block's data.
+    pub async fn persist_with<'e, E>(
+        executor: E,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> Result<(), CursorError>
+    where
+        E: PgExecutor<'e>,
+    {
+        sqlx::query(
+            r#"
+            INSERT INTO cursors (sink_name, module_hash, cursor, block_number)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (sink_name, module_hash)
+            DO UPDATE SET cursor = EXCLUDED.cursor, block_number = EXCLUDED.block_number
+            "#,
+        )
+        .bind(sink_name)
+        .bind(module_hash)
+        .bind(&cursor.cursor)
+        .bind(cursor.block_number as i64)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl CursorStore for PostgresCursorStore {
+    async fn load(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, CursorError> {
+        let row = sqlx::query(
+            "SELECT cursor, block_number FROM cursors WHERE sink_name = $1 AND module_hash = $2",
+        )
+        .bind(sink_name)
+        .bind(module_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| Cursor {
+            cursor: row.get("cursor"),
+            block_number: row.get::<i64, _>("block_number") as u64,
+        }))
+    }
+
+    async fn persist(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> Result<(), CursorError> {
+        Self::persist_with(&self.pool, sink_name, module_hash, cursor).await
+    }
+
+    async fn module_hashes(&self, sink_name: &str) -> Result<Vec<String>, CursorError> {
+        let rows = sqlx::query("SELECT module_hash FROM cursors WHERE sink_name = $1")
+            .bind(sink_name)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("module_hash")).collect())
+    }
+}
+
+/// Stores each cursor in its own file within a directory. Mostly useful for
+/// tests and local development where no database is available.
+#[derive(Clone)]
+pub struct FileCursorStore {
+    dir: PathBuf,
+}
+
+impl FileCursorStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileCursorStore { dir: dir.into() }
+    }
+
+    fn path(&self, sink_name: &str, module_hash: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}-{}.cursor",
+            Self::file_prefix(sink_name),
+            module_hash
+        ))
+    }
+
+    fn file_prefix(sink_name: &str) -> String {
+        sink_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+impl CursorStore for FileCursorStore {
+    async fn load(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, CursorError> {
+        let path = self.path(sink_name, module_hash);
+
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let malformed = || CursorError::Malformed(path.display().to_string());
+        let (block_number, cursor) = content.trim_end().split_once('\n').ok_or_else(malformed)?;
+
+        Ok(Some(Cursor {
+            cursor: cursor.to_string(),
+            block_number: block_number.parse().map_err(|_| malformed())?,
+        }))
+    }
+
+    async fn persist(
+        &self,
+        sink_name: &str,
+        module_hash: &str,
+        cursor: &Cursor,
+    ) -> Result<(), CursorError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        // Write to a temporary file first and rename it over the previous cursor
+        // so a crash mid-write never leaves a truncated cursor behind.
+        let path = self.path(sink_name, module_hash);
+        let tmp_path = path.with_extension("cursor.tmp");
+
+        tokio::fs::write(
+            &tmp_path,
+            format!("{}\n{}\n", cursor.block_number, cursor.cursor),
+        )
+        .await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        Ok(())
+    }
+
+    async fn module_hashes(&self, sink_name: &str) -> Result<Vec<String>, CursorError> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(error) => return Err(error.into()),
+        };
+
+        // Module hashes are hex encoded, so the hash is whatever follows the
+        // last dash even when the sink name itself contains dashes.
+        let prefix = Self::file_prefix(sink_name);
+        let mut module_hashes = vec![];
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let Some(stem) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".cursor"))
+            else {
+                continue;
+            };
+
+            if let Some((name, module_hash)) = stem.rsplit_once('-') {
+                if name == prefix {
+                    module_hashes.push(module_hash.to_string());
+                }
+            }
+        }
+
+        Ok(module_hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pb::sf::substreams::v1::Module, test_utils::test_dir};
+
+    #[tokio::test]
+    async fn test_file_store_missing_cursor() {
+        let store = FileCursorStore::new(test_dir("cursor-missing"));
+
+        let cursor = store.load("kg_indexer", "abc").await.unwrap();
+        assert_eq!(cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_file_store_roundtrip() {
+        let store = FileCursorStore::new(test_dir("cursor-roundtrip"));
+        let cursor = Cursor {
+            cursor: "c1".to_string(),
+            block_number: 53965,
+        };
+
+        store.persist("kg_indexer", "abc", &cursor).await.unwrap();
+        assert_eq!(store.load("kg_indexer", "abc").await.unwrap(), Some(cursor));
+
+        let next = Cursor {
+            cursor: "c2".to_string(),
+            block_number: 53966,
+        };
+
+        store.persist("kg_indexer", "abc", &next).await.unwrap();
+        assert_eq!(store.load("kg_indexer", "abc").await.unwrap(), Some(next));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_keys_by_sink_and_module_hash() {
+        let store = FileCursorStore::new(test_dir("cursor-keys"));
+        let cursor = Cursor {
+            cursor: "c1".to_string(),
+            block_number: 1,
+        };
+
+        store.persist("kg_indexer", "abc", &cursor).await.unwrap();
+
+        assert_eq!(store.load("ipfs_cache", "abc").await.unwrap(), None);
+        assert_eq!(store.load("kg_indexer", "def").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_store_detects_module_hash_change() {
+        let store = FileCursorStore::new(test_dir("cursor-changed"));
+        let cursor = Cursor {
+            cursor: "c1".to_string(),
+            block_number: 1,
+        };
+
+        assert_eq!(store.load_checked("kg_indexer", "abc").await.unwrap(), None);
+
+        store.persist("kg_indexer", "abc", &cursor).await.unwrap();
+        store.persist("kg-indexer", "def", &cursor).await.unwrap();
+
+        assert_eq!(
+            store.module_hashes("kg_indexer").await.unwrap(),
+            vec!["abc".to_string()]
+        );
+        assert_eq!(
+            store.load_checked("kg_indexer", "abc").await.unwrap(),
+            Some(cursor)
+        );
+        assert!(matches!(
+            store.load_checked("kg_indexer", "def").await,
+            Err(CursorError::ModuleHashChanged { persisted, .. }) if persisted == vec!["abc".to_string()]
+        ));
+        assert_eq!(store.load_checked("ipfs_cache", "abc").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_module_hash_changes_with_modules() {
+        let modules = Some(Modules {
+            modules: vec![Module {
+                name: "geo_out".to_string(),
+                ..Default::default()
+            }],
+            binaries: vec![],
+        });
+
+        let changed = Some(Modules {
+            modules: vec![Module {
+                name: "geo_out".to_string(),
+                initial_block: 1,
+                ..Default::default()
+            }],
+            binaries: vec![],
+        });
+
+        assert_eq!(
+            module_hash(&modules, "geo_out"),
+            module_hash(&modules.clone(), "geo_out")
+        );
+        assert_ne!(
+            module_hash(&modules, "geo_out"),
+            module_hash(&changed, "geo_out")
+        );
+        assert_ne!(
+            module_hash(&modules, "geo_out"),
+            module_hash(&modules, "other")
+        );
+    }
+}
@@ -0,0 +1,481 @@
+use futures03::{Stream, StreamExt, future};
+use prost::Message;
+use thiserror::Error;
+
+use crate::{
+    cursor::Cursor,
+    error::StreamError,
+    pb::sf::substreams::{
+        rpc::v2::{BlockScopedData, BlockUndoSignal},
+        v1::{Package, module::Kind},
+    },
+    sink::{PreprocessedSink, Sink},
+    substreams_stream::BlockResponse,
+    utils::{self, BlockMetadata},
+};
+
+#[derive(Error, Debug)]
+pub enum OutputError {
+    #[error("Output error: block {0} has no map output")]
+    MissingOutput(String),
+
+    #[error("Output error: block {0} has no clock")]
+    MissingClock(String),
+
+    #[error("Output error: {0}")]
+    Decode(#[from] prost::DecodeError),
+
+    #[error(
+        "Output error: undoing blocks after block {0} is not supported, stream final blocks only"
+    )]
+    UndoNotSupported(u64),
+}
+
+fn undo_not_supported(undo_signal: &BlockUndoSignal) -> OutputError {
+    let last_valid_block = undo_signal
+        .last_valid_block
+        .as_ref()
+        .map_or(0, |block| block.number);
+
+    OutputError::UndoNotSupported(last_valid_block)
+}
+
+/// Decodes the block's map output into the module's output type along with
+/// the block's metadata.
+pub fn decode_output<M: Message + Default>(
+    block_data: &BlockScopedData,
+) -> Result<(BlockMetadata, M), OutputError> {
+    let has_timestamp = block_data
+        .clock
+        .as_ref()
+        .is_some_and(|clock| clock.timestamp.is_some());
+    if !has_timestamp {
+        return Err(OutputError::MissingClock(block_data.cursor.clone()));
+    }
+
+    let output = block_data
+        .output
+        .as_ref()
+        .and_then(|output| output.map_output.as_ref())
+        .ok_or_else(|| OutputError::MissingOutput(block_data.cursor.clone()))?;
+
+    let decoded = M::decode(output.value.as_slice())?;
+
+    Ok((utils::block_metadata(block_data), decoded))
+}
+
+/// The type url of the output declared by `module_name` in the package, if
+/// the module is a map module.
+pub(crate) fn output_type_url(package: &Package, module_name: &str) -> Option<String> {
+    let module = package
+        .modules
+        .as_ref()?
+        .modules
+        .iter()
+        .find(|module| module.name == module_name)?;
+
+    match module.kind.as_ref()? {
+        Kind::KindMap(map) => Some(format!(
+            "type.googleapis.com/{}",
+            map.output_type.trim_start_matches("proto:")
+        )),
+        _ => None,
+    }
+}
+
+/// Fails the stream as soon as a block's output doesn't have the type the
+/// package declares for the module, e.g., when streaming from an endpoint
+/// serving a different version of the package.
+pub(crate) fn check_output_type<S>(
+    stream: S,
+    expected_type_url: String,
+) -> impl Stream<Item = Result<BlockResponse, StreamError>>
+where
+    S: Stream<Item = Result<BlockResponse, StreamError>>,
+{
+    stream.map(move |response| {
+        if let Ok(BlockResponse::New(block_data)) = &response {
+            let type_url = block_data
+                .output
+                .as_ref()
+                .and_then(|output| output.map_output.as_ref())
+                .map(|output| output.type_url.as_str());
+
+            if let Some(type_url) = type_url {
+                if type_url != expected_type_url {
+                    return Err(StreamError::UnexpectedOutputType {
+                        expected: expected_type_url.clone(),
+                        actual: type_url.to_string(),
+                    });
+                }
+            }
+        }
+
+        response
+    })
+}
+
+/// A `Sink` receiving the module's output already decoded. Implementing this
+/// implements `Sink<Self::Output>`, see `Sink` for the default behaviors.
+pub trait DecodedSink: Send + Sync {
+    type Output: Message + Default + Send;
+    type Error: std::error::Error + From<OutputError> + Send + Sync + 'static;
+
+    fn process_decoded_block(
+        &self,
+        block: &BlockMetadata,
+        output: Self::Output,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Defaults to failing with `OutputError::UndoNotSupported`, which is
+    /// only fine for sinks streaming final blocks only.
+    fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(Err(undo_not_supported(undo_signal).into()))
+    }
+
+    fn persist_cursor(
+        &self,
+        _module_hash: &str,
+        _cursor: Cursor,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(Ok(()))
+    }
+
+    fn load_persisted_cursor(
+        &self,
+        _module_hash: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Cursor>, Self::Error>> + Send {
+        future::ready(Ok(None))
+    }
+}
+
+impl<S: DecodedSink> Sink<S::Output> for S {
+    type Error = S::Error;
+
+    async fn process_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+    ) -> Result<(), Self::Error> {
+        let (block, output) = decode_output(block_data)?;
+        self.process_decoded_block(&block, output).await
+    }
+
+    async fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> Result<(), Self::Error> {
+        DecodedSink::process_block_undo_signal(self, undo_signal).await
+    }
+
+    async fn persist_cursor(&self, module_hash: &str, cursor: Cursor) -> Result<(), Self::Error> {
+        DecodedSink::persist_cursor(self, module_hash, cursor).await
+    }
+
+    async fn load_persisted_cursor(
+        &self,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, Self::Error> {
+        DecodedSink::load_persisted_cursor(self, module_hash).await
+    }
+}
+
+/// A `PreprocessedSink` preprocessing the module's output already decoded.
+/// Implementing this implements `PreprocessedSink<P>`, see `PreprocessedSink`
+/// for the default behaviors.
+pub trait DecodedPreprocessedSink<P: Send>: Send + Sync {
+    type Output: Message + Default + Send;
+    type Error: std::error::Error + From<OutputError> + Send + Sync + 'static;
+
+    fn preprocess_decoded_block(
+        &self,
+        block: BlockMetadata,
+        output: Self::Output,
+    ) -> impl std::future::Future<Output = Result<P, Self::Error>> + Send;
+
+    fn process_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+        decoded_data: P,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Defaults to failing with `OutputError::UndoNotSupported`, which is
+    /// only fine for sinks streaming final blocks only.
+    fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(Err(undo_not_supported(undo_signal).into()))
+    }
+
+    fn persist_cursor(
+        &self,
+        _module_hash: &str,
+        _cursor: Cursor,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        future::ready(Ok(()))
+    }
+
+    fn load_persisted_cursor(
+        &self,
+        _module_hash: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Cursor>, Self::Error>> + Send {
+        future::ready(Ok(None))
+    }
+}
+
+impl<S: DecodedPreprocessedSink<P>, P: Send> PreprocessedSink<P> for S {
+    type Error = S::Error;
+
+    async fn preprocess_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+    ) -> Result<P, Self::Error> {
+        let (block, output) = decode_output(block_data)?;
+        self.preprocess_decoded_block(block, output).await
+    }
+
+    async fn process_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+        decoded_data: P,
+    ) -> Result<(), Self::Error> {
+        DecodedPreprocessedSink::process_block_scoped_data(self, block_data, decoded_data).await
+    }
+
+    async fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> Result<(), Self::Error> {
+        DecodedPreprocessedSink::process_block_undo_signal(self, undo_signal).await
+    }
+
+    async fn persist_cursor(&self, module_hash: &str, cursor: Cursor) -> Result<(), Self::Error> {
+        DecodedPreprocessedSink::persist_cursor(self, module_hash, cursor).await
+    }
+
+    async fn load_persisted_cursor(
+        &self,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, Self::Error> {
+        DecodedPreprocessedSink::load_persisted_cursor(self, module_hash).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures03::stream;
+
+    use super::*;
+    use crate::{
+        pb::sf::substreams::{
+            rpc::v2::MapModuleOutput,
+            v1::{BlockRef, Module, Modules, module::KindMap},
+        },
+        test_utils::make_block_data,
+    };
+
+    const TYPE_URL: &str = "type.googleapis.com/sf.substreams.v1.BlockRef";
+
+    fn make_block(number: u64, type_url: &str) -> BlockScopedData {
+        let output = BlockRef {
+            id: format!("block-{}", number),
+            number,
+        };
+
+        let mut block_data = make_block_data(number);
+        if let Some(clock) = block_data.clock.as_mut() {
+            clock.timestamp = Some(prost_types::Timestamp::default());
+        }
+        block_data.output = Some(MapModuleOutput {
+            name: "geo_out".to_string(),
+            map_output: Some(prost_types::Any {
+                type_url: type_url.to_string(),
+                value: output.encode_to_vec(),
+            }),
+            ..Default::default()
+        });
+        block_data
+    }
+
+    #[test]
+    fn test_decode_output() {
+        let (block, output) = decode_output::<BlockRef>(&make_block(7, TYPE_URL)).unwrap();
+
+        assert_eq!(block.block_number, 7);
+        assert_eq!(block.cursor, "cursor-7");
+        assert_eq!(output.id, "block-7");
+        assert_eq!(output.number, 7);
+    }
+
+    #[test]
+    fn test_decode_output_errors() {
+        let mut block_data = make_block(7, TYPE_URL);
+        block_data.output = None;
+        assert!(matches!(
+            decode_output::<BlockRef>(&block_data),
+            Err(OutputError::MissingOutput(_))
+        ));
+
+        let mut block_data = make_block(7, TYPE_URL);
+        block_data.clock = None;
+        assert!(matches!(
+            decode_output::<BlockRef>(&block_data),
+            Err(OutputError::MissingClock(_))
+        ));
+
+        let mut block_data = make_block(7, TYPE_URL);
+        if let Some(any) = block_data
+            .output
+            .as_mut()
+            .and_then(|output| output.map_output.as_mut())
+        {
+            any.value = vec![0xff];
+        }
+        assert!(matches!(
+            decode_output::<BlockRef>(&block_data),
+            Err(OutputError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn test_output_type_url() {
+        let package = Package {
+            modules: Some(Modules {
+                modules: vec![
+                    Module {
+                        name: "geo_out".to_string(),
+                        kind: Some(Kind::KindMap(KindMap {
+                            output_type: "proto:sf.substreams.v1.BlockRef".to_string(),
+                        })),
+                        ..Default::default()
+                    },
+                    Module {
+                        name: "no_kind".to_string(),
+                        ..Default::default()
+                    },
+                ],
+                binaries: vec![],
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            output_type_url(&package, "geo_out"),
+            Some(TYPE_URL.to_string())
+        );
+        assert_eq!(output_type_url(&package, "no_kind"), None);
+        assert_eq!(output_type_url(&package, "missing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_check_output_type_rejects_other_types() {
+        let responses = vec![
+            Ok(BlockResponse::New(make_block(1, TYPE_URL))),
+            Ok(BlockResponse::New(make_block(
+                2,
+                "type.googleapis.com/other",
+            ))),
+        ];
+
+        let responses: Vec<_> = check_output_type(stream::iter(responses), TYPE_URL.to_string())
+            .collect()
+            .await;
+
+        assert!(responses[0].is_ok());
+        assert!(matches!(
+            &responses[1],
+            Err(StreamError::UnexpectedOutputType { actual, .. }) if actual == "type.googleapis.com/other"
+        ));
+    }
+
+    #[derive(Error, Debug)]
+    enum TestSinkError {
+        #[error("test sink error: {0}")]
+        Output(#[from] OutputError),
+    }
+
+    #[derive(Default)]
+    struct TestSink {
+        processed: Mutex<Vec<(u64, BlockRef)>>,
+        cursors: Mutex<Vec<u64>>,
+    }
+
+    impl DecodedSink for TestSink {
+        type Output = BlockRef;
+        type Error = TestSinkError;
+
+        async fn process_decoded_block(
+            &self,
+            block: &BlockMetadata,
+            output: BlockRef,
+        ) -> Result<(), Self::Error> {
+            self.processed
+                .lock()
+                .unwrap()
+                .push((block.block_number, output));
+            Ok(())
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            self.cursors.lock().unwrap().push(cursor.block_number);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decoded_sink_receives_decoded_output() {
+        let sink = TestSink::default();
+        let responses = vec![
+            Ok(BlockResponse::New(make_block(1, TYPE_URL))),
+            Ok(BlockResponse::New(make_block(2, TYPE_URL))),
+        ];
+
+        sink.run_stream("abc", stream::iter(responses))
+            .await
+            .unwrap();
+
+        let processed = sink.processed.lock().unwrap();
+        assert_eq!(processed.len(), 2);
+        assert_eq!(processed[1].0, 2);
+        assert_eq!(processed[1].1.id, "block-2");
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_decoded_sink_fails_on_undo_by_default() {
+        let sink = TestSink::default();
+        let responses = vec![
+            Ok(BlockResponse::New(make_block(1, TYPE_URL))),
+            Ok(BlockResponse::Undo(BlockUndoSignal {
+                last_valid_block: Some(BlockRef {
+                    number: 1,
+                    ..Default::default()
+                }),
+                last_valid_cursor: "cursor-1".to_string(),
+            })),
+        ];
+
+        let error = sink
+            .run_stream("abc", stream::iter(responses))
+            .await
+            .unwrap_err();
+
+        let StreamError::Sink(error) = error else {
+            panic!("expected a sink error, got {:?}", error);
+        };
+        assert!(matches!(
+            error.downcast_ref::<TestSinkError>(),
+            Some(TestSinkError::Output(OutputError::UndoNotSupported(1)))
+        ));
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1]);
+    }
+}
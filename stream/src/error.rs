@@ -0,0 +1,138 @@
+use thiserror::Error;
+
+use crate::{
+    backfill::BackfillError, pb::sf::substreams::rpc::v2::Error as ModuleError, replay::ReplayError,
+};
+
+/// Errors that stop a sink from streaming. Fatal errors won't go away by
+/// reconnecting, e.g., a bad package or an invalid token, while transient
+/// errors may succeed when retried later.
+///
+/// Transient errors are retried with backoff within the stream itself and
+/// are only surfaced wrapped in `BackoffExhausted` once retries run out.
+#[derive(Error, Debug)]
+pub enum StreamError {
+    #[error("Stream error: invalid package {0:#}")]
+    InvalidPackage(anyhow::Error),
+
+    #[error("Stream error: module {0} not found in package")]
+    InvalidModule(String),
+
+    #[error("Stream error: invalid endpoint {0:#}")]
+    InvalidEndpoint(anyhow::Error),
+
+    #[error("Stream error: unauthenticated {0}")]
+    Unauthenticated(tonic::Status),
+
+    #[error("Stream error: invalid request {0}")]
+    InvalidRequest(tonic::Status),
+
+    #[error("Stream error: module {} failed: {}", .0.module, .0.reason)]
+    ModuleFailed(ModuleError),
+
+    #[error("Stream error: {0}")]
+    Replay(#[from] ReplayError),
+
+    #[error("Stream error: expected output type {expected}, received {actual}")]
+    UnexpectedOutputType { expected: String, actual: String },
+
+    #[error("Stream error: {0}")]
+    Backfill(#[from] BackfillError),
+
+    #[error("Stream error: expected module hash {expected}, package has {actual}")]
+    UnexpectedModuleHash { expected: String, actual: String },
+
+    #[error("Stream error: blocks in flight not processed within {0:?} of shutdown")]
+    ShutdownTimedOut(std::time::Duration),
+
+    #[error("Stream error: disconnected {0}")]
+    Disconnected(tonic::Status),
+
+    #[error("Stream error: backoff exhausted, last error: {0}")]
+    BackoffExhausted(Box<StreamError>),
+
+    /// Errors returned by the sink itself. Whether these are recoverable
+    /// depends on the sink.
+    #[error("Stream error: sink error {0}")]
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+
+    /// Errors returned by the sinks of a `FanOut`, keyed by sink name.
+    #[error("Stream error: sinks failed {}", format_sink_errors(.0))]
+    SinksFailed(Vec<(String, StreamError)>),
+}
+
+fn format_sink_errors(errors: &[(String, StreamError)]) -> String {
+    errors
+        .iter()
+        .map(|(name, error)| format!("[{}: {}]", name, error))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl StreamError {
+    pub fn sink<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        StreamError::Sink(Box::new(error))
+    }
+
+    /// Maps a gRPC status returned by the Substreams endpoint.
+    pub fn from_status(status: tonic::Status) -> Self {
+        match status.code() {
+            tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => {
+                StreamError::Unauthenticated(status)
+            }
+            tonic::Code::InvalidArgument => StreamError::InvalidRequest(status),
+            _ => StreamError::Disconnected(status),
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            StreamError::InvalidPackage(_)
+                | StreamError::InvalidModule(_)
+                | StreamError::InvalidEndpoint(_)
+                | StreamError::Unauthenticated(_)
+                | StreamError::InvalidRequest(_)
+                | StreamError::ModuleFailed(_)
+                | StreamError::Replay(_)
+                | StreamError::UnexpectedOutputType { .. }
+                | StreamError::UnexpectedModuleHash { .. }
+        )
+    }
+
+    /// Whether the endpoint rejected the credentials. Unlike other fatal
+    /// errors, another endpoint may still accept its own.
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, StreamError::Unauthenticated(_))
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            StreamError::Disconnected(_) | StreamError::BackoffExhausted(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_status_classifies_errors() {
+        assert!(StreamError::from_status(tonic::Status::unauthenticated("bad token")).is_fatal());
+        assert!(StreamError::from_status(tonic::Status::permission_denied("denied")).is_fatal());
+        assert!(StreamError::from_status(tonic::Status::invalid_argument("bad module")).is_fatal());
+
+        assert!(StreamError::from_status(tonic::Status::unavailable("gone")).is_transient());
+        assert!(StreamError::from_status(tonic::Status::internal("reset")).is_transient());
+    }
+
+    #[test]
+    fn test_sink_errors_are_neither_fatal_nor_transient() {
+        let error = StreamError::sink(std::io::Error::other("db down"));
+
+        assert!(!error.is_fatal());
+        assert!(!error.is_transient());
+    }
+}
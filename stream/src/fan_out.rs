@@ -0,0 +1,530 @@
+use futures03::{
+    StreamExt,
+    future::{self, BoxFuture},
+    stream::BoxStream,
+};
+use std::marker::PhantomData;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::{
+    config::RunConfig,
+    cursor::Cursor,
+    error::StreamError,
+    health::{self, Phase},
+    replay::BlockRecorder,
+    shutdown::with_shutdown_timeout,
+    sink::{
+        BlockStream, PreprocessedSink, Sink, block_recorder, block_stream, load_package,
+        process_preprocessed_stream, process_stream,
+    },
+    substreams_stream::BlockResponse,
+};
+
+/// Object-safe view of `Sink` and `PreprocessedSink` so sinks of different
+/// types can be driven by the same `FanOut`.
+trait FanOutSink: Send + Sync {
+    fn load_persisted_cursor<'a>(
+        &'a self,
+        module_hash: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Cursor>, StreamError>>;
+
+    fn run_stream<'a>(
+        &'a self,
+        module_hash: &'a str,
+        config: &'a RunConfig,
+        stream: BoxStream<'a, Result<BlockResponse, StreamError>>,
+        recorder: Option<BlockRecorder>,
+    ) -> BoxFuture<'a, Result<(), StreamError>>;
+}
+
+struct SinkAdapter<'s, S, T> {
+    sink: &'s S,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<S: Sink<T>, T: Send> FanOutSink for SinkAdapter<'_, S, T> {
+    fn load_persisted_cursor<'a>(
+        &'a self,
+        module_hash: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Cursor>, StreamError>> {
+        Box::pin(async move {
+            self.sink
+                .load_persisted_cursor(module_hash)
+                .await
+                .map_err(StreamError::sink)
+        })
+    }
+
+    fn run_stream<'a>(
+        &'a self,
+        module_hash: &'a str,
+        _config: &'a RunConfig,
+        stream: BoxStream<'a, Result<BlockResponse, StreamError>>,
+        recorder: Option<BlockRecorder>,
+    ) -> BoxFuture<'a, Result<(), StreamError>> {
+        Box::pin(process_stream(self.sink, module_hash, stream, recorder))
+    }
+}
+
+struct PreprocessedSinkAdapter<'s, S, P> {
+    sink: &'s S,
+    _data: PhantomData<fn() -> P>,
+}
+
+impl<S: PreprocessedSink<P>, P: Send> FanOutSink for PreprocessedSinkAdapter<'_, S, P> {
+    fn load_persisted_cursor<'a>(
+        &'a self,
+        module_hash: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Cursor>, StreamError>> {
+        Box::pin(async move {
+            self.sink
+                .load_persisted_cursor(module_hash)
+                .await
+                .map_err(StreamError::sink)
+        })
+    }
+
+    fn run_stream<'a>(
+        &'a self,
+        module_hash: &'a str,
+        config: &'a RunConfig,
+        stream: BoxStream<'a, Result<BlockResponse, StreamError>>,
+        recorder: Option<BlockRecorder>,
+    ) -> BoxFuture<'a, Result<(), StreamError>> {
+        Box::pin(process_preprocessed_stream(
+            self.sink,
+            module_hash,
+            config.preprocess_lookahead,
+            stream,
+            recorder,
+        ))
+    }
+}
+
+/// Drives several sinks from a single Substreams connection.
+///
+/// Every sink keeps its own cursor. The stream resumes from the cursor of the
+/// sink that is furthest behind, and each sink skips the blocks it already
+/// processed. All sinks share the request made to the endpoint, so they must
+/// agree on e.g. `final_blocks_only`.
+///
+/// Each sink consumes blocks from its own buffer of `buffer_size` blocks. A
+/// slow sink doesn't hold back the others until its buffer is full, at which
+/// point the stream waits for it. A sink that fails is dropped from the fan
+/// out while the others keep streaming; its error is returned once the stream
+/// ends.
+///
+/// When recording, the blocks processed by the first sink added are recorded
+/// so the recording follows that sink's cursor.
+pub struct FanOut<'a> {
+    config: RunConfig,
+    buffer_size: usize,
+    sinks: Vec<(String, Box<dyn FanOutSink + 'a>)>,
+}
+
+impl<'a> FanOut<'a> {
+    pub fn new(config: RunConfig, buffer_size: usize) -> Self {
+        FanOut {
+            config,
+            buffer_size: buffer_size.max(1),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn add_sink<S: Sink<T>, T: Send + 'a>(&mut self, name: &str, sink: &'a S) {
+        let adapter = SinkAdapter {
+            sink,
+            _data: PhantomData,
+        };
+
+        self.sinks.push((name.to_string(), Box::new(adapter)));
+    }
+
+    pub fn add_preprocessed_sink<S: PreprocessedSink<P>, P: Send + 'a>(
+        &mut self,
+        name: &str,
+        sink: &'a S,
+    ) {
+        let adapter = PreprocessedSinkAdapter {
+            sink,
+            _data: PhantomData,
+        };
+
+        self.sinks.push((name.to_string(), Box::new(adapter)));
+    }
+
+    pub async fn run(self) -> Result<(), StreamError> {
+        info!("Processing package {}", self.config.spkg_file);
+
+        let (package, module_hash) = load_package(&self.config).await?;
+
+        let mut cursors = Vec::with_capacity(self.sinks.len());
+        for (name, sink) in &self.sinks {
+            let cursor = sink.load_persisted_cursor(&module_hash).await?;
+
+            if let Some(cursor) = &cursor {
+                info!(
+                    "Sink {} resuming from persisted cursor at block {}",
+                    name, cursor.block_number
+                );
+            }
+
+            cursors.push(cursor);
+        }
+
+        // Sinks without a cursor need the stream to start from the configured
+        // start block, otherwise we resume from the sink furthest behind.
+        let start_cursor = if cursors.iter().all(Option::is_some) {
+            cursors
+                .iter()
+                .flatten()
+                .min_by_key(|cursor| cursor.block_number)
+                .map(|cursor| cursor.cursor.clone())
+        } else {
+            None
+        };
+
+        let stream = block_stream(&self.config, &package, &module_hash, start_cursor).await?;
+        let recorder = block_recorder(&self.config).await?;
+        health::set_phase(Phase::Streaming);
+
+        with_shutdown_timeout(
+            &self.config,
+            self.fan_out(&module_hash, cursors, stream, recorder),
+        )
+        .await
+    }
+
+    async fn fan_out(
+        &self,
+        module_hash: &str,
+        cursors: Vec<Option<Cursor>>,
+        mut stream: BlockStream,
+        mut recorder: Option<BlockRecorder>,
+    ) -> Result<(), StreamError> {
+        let mut senders = Vec::with_capacity(self.sinks.len());
+        let mut sink_runs = Vec::with_capacity(self.sinks.len());
+
+        for ((name, sink), cursor) in self.sinks.iter().zip(cursors) {
+            let (sender, receiver) = mpsc::channel(self.buffer_size);
+            let receiver = skip_processed_blocks(ReceiverStream::new(receiver), cursor);
+
+            senders.push(Some(sender));
+            sink_runs.push(
+                sink.run_stream(module_hash, &self.config, receiver, recorder.take())
+                    .instrument(info_span!("sink", name = %name)),
+            );
+        }
+
+        let broadcast = async move {
+            loop {
+                let response = match stream.next().await {
+                    Some(Ok(response)) => response,
+                    Some(Err(error)) => return Err(error),
+                    None => return Ok(()),
+                };
+
+                for sender in senders.iter_mut() {
+                    let Some(sink_sender) = sender else {
+                        continue;
+                    };
+
+                    // The sink's receiver is only dropped once it stopped
+                    // processing blocks, i.e., it failed.
+                    if sink_sender.send(response.clone()).await.is_err() {
+                        *sender = None;
+                    }
+                }
+
+                if senders.iter().all(Option::is_none) {
+                    return Ok(());
+                }
+            }
+        };
+
+        let (result, sink_results) = future::join(broadcast, future::join_all(sink_runs)).await;
+
+        let errors: Vec<_> = self
+            .sinks
+            .iter()
+            .zip(sink_results)
+            .filter_map(|((name, _), result)| result.err().map(|error| (name.clone(), error)))
+            .collect();
+
+        for (name, error) in &errors {
+            error!("Sink {} terminated with error {}", name, error);
+        }
+
+        result?;
+
+        if !errors.is_empty() {
+            return Err(StreamError::SinksFailed(errors));
+        }
+
+        Ok(())
+    }
+}
+
+/// Skips the blocks at or before the block the sink's cursor points at since
+/// the stream may resume from an earlier cursor for another sink.
+fn skip_processed_blocks<'a>(
+    receiver: ReceiverStream<BlockResponse>,
+    cursor: Option<Cursor>,
+) -> BoxStream<'a, Result<BlockResponse, StreamError>> {
+    let mut processed_block = cursor.map(|cursor| cursor.block_number);
+
+    receiver
+        .filter_map(move |response| {
+            let response = match (&response, processed_block) {
+                (BlockResponse::New(block_data), Some(block_number)) => {
+                    let number = block_data.clock.as_ref().map_or(0, |clock| clock.number);
+                    (number > block_number).then_some(response)
+                }
+                (BlockResponse::Undo(undo_signal), Some(block_number)) => {
+                    // Blocks after the last valid block are streamed again, so
+                    // the sink has to process them even if it processed them
+                    // before on the reorganized fork.
+                    let last_valid_block = undo_signal
+                        .last_valid_block
+                        .as_ref()
+                        .map_or(0, |block| block.number);
+                    processed_block = Some(block_number.min(last_valid_block));
+                    Some(response)
+                }
+                (_, None) => Some(response),
+            };
+
+            future::ready(response.map(Ok))
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use thiserror::Error;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::{
+        pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+        test_utils::make_config,
+    };
+
+    #[derive(Error, Debug)]
+    #[error("test sink error at block {0}")]
+    struct TestSinkError(u64);
+
+    #[derive(Default)]
+    struct TestSink {
+        cursor: Mutex<Option<Cursor>>,
+        failing_block: Option<u64>,
+        blocked: Option<Arc<Notify>>,
+        processed: Mutex<Vec<u64>>,
+    }
+
+    impl TestSink {
+        fn processed(&self) -> Vec<u64> {
+            self.processed.lock().unwrap().clone()
+        }
+
+        async fn process(&self, block_data: &BlockScopedData) -> Result<(), TestSinkError> {
+            let number = block_data.clock.as_ref().unwrap().number;
+
+            if let Some(blocked) = &self.blocked {
+                blocked.notified().await;
+            }
+
+            if self.failing_block == Some(number) {
+                return Err(TestSinkError(number));
+            }
+
+            self.processed.lock().unwrap().push(number);
+            Ok(())
+        }
+    }
+
+    impl Sink<()> for TestSink {
+        type Error = TestSinkError;
+
+        async fn process_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+        ) -> Result<(), Self::Error> {
+            self.process(block_data).await
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            _undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            *self.cursor.lock().unwrap() = Some(cursor);
+            Ok(())
+        }
+
+        async fn load_persisted_cursor(
+            &self,
+            _module_hash: &str,
+        ) -> Result<Option<Cursor>, Self::Error> {
+            Ok(self.cursor.lock().unwrap().clone())
+        }
+    }
+
+    impl PreprocessedSink<()> for TestSink {
+        type Error = TestSinkError;
+
+        async fn preprocess_block_scoped_data(
+            &self,
+            _block_data: &BlockScopedData,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn process_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+            _decoded_data: (),
+        ) -> Result<(), Self::Error> {
+            self.process(block_data).await
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            _undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            *self.cursor.lock().unwrap() = Some(cursor);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sinks_resume_from_their_own_cursor() {
+        let config = make_config("fan-out-cursors", 5).await;
+
+        let ahead = TestSink {
+            cursor: Mutex::new(Some(Cursor {
+                cursor: "cursor-3".to_string(),
+                block_number: 3,
+            })),
+            ..Default::default()
+        };
+        let behind = TestSink {
+            cursor: Mutex::new(Some(Cursor {
+                cursor: "cursor-1".to_string(),
+                block_number: 1,
+            })),
+            ..Default::default()
+        };
+
+        let mut fan_out = FanOut::new(config, 2);
+        fan_out.add_sink("ahead", &ahead);
+        fan_out.add_sink("behind", &behind);
+        fan_out.run().await.unwrap();
+
+        assert_eq!(ahead.processed(), vec![4, 5]);
+        assert_eq!(behind.processed(), vec![2, 3, 4, 5]);
+        assert_eq!(
+            ahead.cursor.lock().unwrap().as_ref().unwrap().block_number,
+            5
+        );
+        assert_eq!(
+            behind.cursor.lock().unwrap().as_ref().unwrap().block_number,
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_sink_does_not_stop_others() {
+        let config = make_config("fan-out-isolation", 5).await;
+
+        let failing = TestSink {
+            failing_block: Some(2),
+            ..Default::default()
+        };
+        let healthy = TestSink::default();
+
+        let mut fan_out = FanOut::new(config, 1);
+        fan_out.add_sink("failing", &failing);
+        fan_out.add_preprocessed_sink("healthy", &healthy);
+
+        let error = fan_out.run().await.unwrap_err();
+
+        assert_eq!(failing.processed(), vec![1]);
+        assert_eq!(
+            failing
+                .cursor
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .block_number,
+            1
+        );
+        assert_eq!(healthy.processed(), vec![1, 2, 3, 4, 5]);
+
+        let StreamError::SinksFailed(errors) = error else {
+            panic!("expected sink errors, got {:?}", error);
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "failing");
+    }
+
+    #[tokio::test]
+    async fn test_slow_sink_applies_backpressure_beyond_buffer() {
+        let config = make_config("fan-out-backpressure", 6).await;
+
+        let blocked = Arc::new(Notify::new());
+        let slow = TestSink {
+            blocked: Some(blocked.clone()),
+            ..Default::default()
+        };
+        let fast = TestSink::default();
+
+        let mut fan_out = FanOut::new(config, 2);
+        fan_out.add_sink("slow", &slow);
+        fan_out.add_sink("fast", &fast);
+
+        let release = async {
+            // The slow sink holds block 1 while blocks 2 and 3 fill its buffer,
+            // so the fast sink only gets up to block 3 until it catches up.
+            while fast.processed().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(fast.processed(), vec![1, 2, 3]);
+
+            for _ in 0..6 {
+                blocked.notify_one();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        let (result, _) = future::join(fan_out.run(), release).await;
+        result.unwrap();
+
+        assert_eq!(slow.processed(), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(fast.processed(), vec![1, 2, 3, 4, 5, 6]);
+    }
+}
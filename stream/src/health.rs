@@ -0,0 +1,278 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::time::timeout;
+
+use crate::pb::sf::substreams::rpc::v2::BlockScopedData;
+
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Starting,
+    Backfilling,
+    Streaming,
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthConfig {
+    /// The process is considered stuck when it didn't process a block for
+    /// longer than this.
+    pub max_block_interval: Duration,
+    /// The process is considered caught up when the last block it processed
+    /// is at most this far behind the current time.
+    pub max_head_drift: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            max_block_interval: Duration::from_secs(600),
+            max_head_drift: Duration::from_secs(60),
+        }
+    }
+}
+
+impl HealthConfig {
+    /// Reads the thresholds in seconds from `HEALTH_MAX_BLOCK_INTERVAL` and
+    /// `HEALTH_MAX_HEAD_DRIFT`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        let default = HealthConfig::default();
+
+        HealthConfig {
+            max_block_interval: seconds_from_env("HEALTH_MAX_BLOCK_INTERVAL")
+                .unwrap_or(default.max_block_interval),
+            max_head_drift: seconds_from_env("HEALTH_MAX_HEAD_DRIFT")
+                .unwrap_or(default.max_head_drift),
+        }
+    }
+}
+
+fn seconds_from_env(name: &str) -> Option<Duration> {
+    let value = env::var(name).ok()?;
+    let seconds = value
+        .parse()
+        .unwrap_or_else(|_| panic!("{} must be a number of seconds", name));
+
+    Some(Duration::from_secs(seconds))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub phase: Phase,
+    pub last_block: Option<u64>,
+    pub head_drift_seconds: Option<i64>,
+    /// Seconds since the last block was processed, or since the process
+    /// started if it didn't process any block yet.
+    pub seconds_since_last_block: u64,
+    /// Whether the database is reachable, when the process uses one.
+    pub database: Option<bool>,
+    /// Whether the process is still processing blocks.
+    pub live: bool,
+    /// Whether the process is live, can reach its database and caught up
+    /// with the head of the chain.
+    pub ready: bool,
+}
+
+#[derive(Debug)]
+struct HealthState {
+    phase: Phase,
+    started_at: Instant,
+    last_block: Option<u64>,
+    last_block_timestamp: Option<i64>,
+    last_block_at: Option<Instant>,
+}
+
+impl HealthState {
+    fn new() -> Self {
+        HealthState {
+            phase: Phase::Starting,
+            started_at: Instant::now(),
+            last_block: None,
+            last_block_timestamp: None,
+            last_block_at: None,
+        }
+    }
+
+    fn record_block(&mut self, block_data: &BlockScopedData) {
+        let clock = block_data.clock.as_ref();
+
+        self.last_block = clock.map(|clock| clock.number);
+        self.last_block_timestamp = clock
+            .and_then(|clock| clock.timestamp.as_ref())
+            .map(|timestamp| timestamp.seconds);
+        self.last_block_at = Some(Instant::now());
+    }
+
+    fn report(&self, config: &HealthConfig, database: Option<bool>) -> HealthReport {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let head_drift_seconds = self.last_block_timestamp.map(|timestamp| now - timestamp);
+        let since_last_block = self.last_block_at.unwrap_or(self.started_at).elapsed();
+
+        let live = since_last_block <= config.max_block_interval;
+        let caught_up = self.phase == Phase::Streaming
+            && head_drift_seconds
+                .is_some_and(|drift| drift <= config.max_head_drift.as_secs() as i64);
+
+        HealthReport {
+            phase: self.phase,
+            last_block: self.last_block,
+            head_drift_seconds,
+            seconds_since_last_block: since_last_block.as_secs(),
+            database,
+            live,
+            ready: live && database != Some(false) && caught_up,
+        }
+    }
+}
+
+lazy_static! {
+    static ref STATE: Mutex<HealthState> = Mutex::new(HealthState::new());
+}
+
+pub(crate) fn set_phase(phase: Phase) {
+    STATE.lock().unwrap().phase = phase;
+}
+
+pub(crate) fn record_block(block_data: &BlockScopedData) {
+    STATE.lock().unwrap().record_block(block_data);
+}
+
+#[cfg(test)]
+pub(crate) fn last_block_at() -> Option<Instant> {
+    STATE.lock().unwrap().last_block_at
+}
+
+struct HealthChecks {
+    config: HealthConfig,
+    pool: Option<PgPool>,
+}
+
+impl HealthChecks {
+    async fn report(&self) -> HealthReport {
+        let database = match &self.pool {
+            Some(pool) => Some(database_reachable(pool).await),
+            None => None,
+        };
+
+        STATE.lock().unwrap().report(&self.config, database)
+    }
+}
+
+async fn database_reachable(pool: &PgPool) -> bool {
+    let query = sqlx::query("SELECT 1").execute(pool);
+
+    matches!(timeout(DATABASE_CHECK_TIMEOUT, query).await, Ok(Ok(_)))
+}
+
+fn respond(report: HealthReport, healthy: bool) -> (StatusCode, Json<HealthReport>) {
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
+
+async fn live(State(checks): State<Arc<HealthChecks>>) -> (StatusCode, Json<HealthReport>) {
+    let report = checks.report().await;
+    let live = report.live;
+
+    respond(report, live)
+}
+
+async fn ready(State(checks): State<Arc<HealthChecks>>) -> (StatusCode, Json<HealthReport>) {
+    let report = checks.report().await;
+    let ready = report.ready;
+
+    respond(report, ready)
+}
+
+/// A router serving the liveness probe on `/health` and the readiness probe
+/// on `/ready`. Both respond with a `HealthReport`, with a 503 status when
+/// the probe fails. `pool` is checked for connectivity when given.
+pub fn router(config: HealthConfig, pool: Option<PgPool>) -> Router {
+    Router::new()
+        .route("/health", get(live))
+        .route("/ready", get(ready))
+        .with_state(Arc::new(HealthChecks { config, pool }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::sf::substreams::v1::Clock;
+
+    fn block(number: u64, age: Duration) -> BlockScopedData {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        BlockScopedData {
+            clock: Some(Clock {
+                number,
+                timestamp: Some(prost_types::Timestamp {
+                    seconds: (now - age).as_secs() as i64,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_not_ready_until_streaming_head_blocks() {
+        let config = HealthConfig::default();
+        let mut state = HealthState::new();
+
+        let report = state.report(&config, Some(true));
+        assert!(report.live);
+        assert!(!report.ready);
+        assert_eq!(report.last_block, None);
+
+        state.phase = Phase::Backfilling;
+        state.record_block(&block(53965, Duration::from_secs(3600)));
+        let report = state.report(&config, Some(true));
+        assert!(report.live);
+        assert!(!report.ready);
+        assert_eq!(report.last_block, Some(53965));
+
+        state.phase = Phase::Streaming;
+        let report = state.report(&config, Some(true));
+        assert!(!report.ready);
+        assert!(report.head_drift_seconds.unwrap() >= 3600);
+
+        state.record_block(&block(53966, Duration::from_secs(2)));
+        assert!(state.report(&config, Some(true)).ready);
+        assert!(state.report(&config, None).ready);
+        assert!(!state.report(&config, Some(false)).ready);
+    }
+
+    #[test]
+    fn test_not_live_when_stuck() {
+        let config = HealthConfig {
+            max_block_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut state = HealthState::new();
+        state.phase = Phase::Streaming;
+        state.record_block(&block(53965, Duration::from_secs(2)));
+
+        std::thread::sleep(Duration::from_millis(10));
+
+        let report = state.report(&config, Some(true));
+        assert!(!report.live);
+        assert!(!report.ready);
+    }
+}
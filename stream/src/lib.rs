@@ -1,7 +1,30 @@
+pub mod backfill;
+pub mod config;
+pub mod cursor;
+pub mod decoded;
+pub mod error;
+pub mod fan_out;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod mock_server;
+pub mod observer;
+pub mod package_cache;
 pub mod pb;
+pub mod replay;
+pub mod shutdown;
 pub mod sink;
 pub mod substreams;
 pub mod substreams_stream;
+#[cfg(test)]
+mod test_utils;
 
+pub use backfill::Backfill;
+pub use config::{EndpointConfig, RunConfig};
+pub use decoded::{DecodedPreprocessedSink, DecodedSink};
+pub use error::StreamError;
+pub use fan_out::FanOut;
+pub use observer::StreamObserver;
 pub use sink::{PreprocessedSink, Sink};
 pub mod utils;
@@ -0,0 +1,70 @@
+use tracing::{Span, info_span};
+use tracing_subscriber::{EnvFilter, fmt};
+
+use crate::pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    /// Reads the format from `LOG_FORMAT`, defaulting to human readable text.
+    pub fn from_env() -> Self {
+        Self::parse(std::env::var("LOG_FORMAT").ok().as_deref())
+    }
+
+    fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some(value) if value.eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
+/// Installs the global subscriber writing the process' logs in `format`,
+/// filtered by `RUST_LOG` and defaulting to `info`.
+///
+/// JSON logs include the spans an event was written in, e.g., the block and
+/// the edit being processed.
+pub fn init_tracing(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = fmt().with_env_filter(filter);
+
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// The span a block is preprocessed and processed in.
+pub fn block_span(block_data: &BlockScopedData) -> Span {
+    info_span!(
+        "block",
+        number = block_data.clock.as_ref().map(|clock| clock.number),
+        cursor = %block_data.cursor
+    )
+}
+
+/// The span an undo signal is processed in.
+pub fn undo_span(undo_signal: &BlockUndoSignal) -> Span {
+    info_span!(
+        "undo",
+        last_valid_block = undo_signal.last_valid_block.as_ref().map(|block| block.number),
+        cursor = %undo_signal.last_valid_cursor
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_log_format() {
+        assert_eq!(LogFormat::parse(None), LogFormat::Text);
+        assert_eq!(LogFormat::parse(Some("json")), LogFormat::Json);
+        assert_eq!(LogFormat::parse(Some("JSON")), LogFormat::Json);
+        assert_eq!(LogFormat::parse(Some("text")), LogFormat::Text);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Router, routing::get};
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder,
+    register_histogram, register_int_counter,
+};
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::pb::sf::substreams::rpc::v2::BlockScopedData;
+
+lazy_static! {
+    pub static ref BLOCKS: BlockMetrics =
+        BlockMetrics::register(prometheus::default_registry()).unwrap();
+    pub static ref PREPROCESS_DURATION: Histogram = register_histogram!(
+        "stream_preprocess_duration_seconds",
+        "Time spent preprocessing a block",
+        duration_buckets()
+    )
+    .unwrap();
+    pub static ref RECONNECTS: IntCounter = register_int_counter!(
+        "stream_reconnects_total",
+        "Connections to a Substreams endpoint after the first one"
+    )
+    .unwrap();
+    pub static ref BACKOFFS: IntCounter = register_int_counter!(
+        "stream_backoffs_total",
+        "Times the stream backed off before reconnecting"
+    )
+    .unwrap();
+    pub static ref BACKOFF_SECONDS: IntCounter = register_int_counter!(
+        "stream_backoff_seconds_total",
+        "Seconds spent backing off before reconnecting"
+    )
+    .unwrap();
+    pub static ref FAILOVERS: IntCounter = register_int_counter!(
+        "stream_failovers_total",
+        "Times the stream failed over to another endpoint"
+    )
+    .unwrap();
+}
+
+fn duration_buckets() -> Vec<f64> {
+    vec![
+        0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+    ]
+}
+
+/// The metrics of the blocks processed by sinks. `BLOCKS` registers them
+/// with the default registry.
+pub struct BlockMetrics {
+    pub processed: IntCounter,
+    pub number: IntGauge,
+    pub head_drift: IntGauge,
+    pub process_duration: Histogram,
+}
+
+impl BlockMetrics {
+    pub fn register(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = BlockMetrics {
+            processed: IntCounter::new(
+                "stream_blocks_processed_total",
+                "Blocks processed by sinks",
+            )?,
+            number: IntGauge::new("stream_block_number", "Number of the last block processed")?,
+            head_drift: IntGauge::new(
+                "stream_head_drift_seconds",
+                "Seconds between the timestamp of the last block processed and the time it was processed",
+            )?,
+            process_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "stream_process_duration_seconds",
+                    "Time spent processing a block, including persisting its cursor",
+                )
+                .buckets(duration_buckets()),
+            )?,
+        };
+
+        registry.register(Box::new(metrics.processed.clone()))?;
+        registry.register(Box::new(metrics.number.clone()))?;
+        registry.register(Box::new(metrics.head_drift.clone()))?;
+        registry.register(Box::new(metrics.process_duration.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// Records that `block_data` was processed in `duration`.
+    fn record(&self, block_data: &BlockScopedData, duration: Duration) {
+        self.processed.inc();
+        self.process_duration.observe(duration.as_secs_f64());
+
+        let Some(clock) = &block_data.clock else {
+            return;
+        };
+        self.number.set(clock.number as i64);
+
+        if let Some(timestamp) = &clock.timestamp {
+            self.head_drift.set(unix_now() - timestamp.seconds);
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Records that `block_data` was processed in `duration`.
+pub(crate) fn record_block_processed(block_data: &BlockScopedData, duration: Duration) {
+    BLOCKS.record(block_data, duration);
+}
+
+/// Renders every registered metric, including the ones registered by the
+/// sinks, in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("metrics are valid utf-8");
+
+    String::from_utf8(buffer).expect("metrics are valid utf-8")
+}
+
+/// A router serving the metrics on `/metrics`, which processes can extend
+/// with their own routes.
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(|| async { render() }))
+}
+
+/// Serves `router` on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, router: Router) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    axum::serve(listener, router).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::sf::substreams::v1::Clock;
+
+    #[test]
+    fn test_records_processed_blocks() {
+        let registry = Registry::new();
+        let metrics = BlockMetrics::register(&registry).unwrap();
+
+        let before = unix_now();
+        metrics.record(
+            &BlockScopedData {
+                clock: Some(Clock {
+                    number: 53965,
+                    timestamp: Some(prost_types::Timestamp::default()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Duration::from_millis(20),
+        );
+        let after = unix_now();
+
+        assert_eq!(metrics.processed.get(), 1);
+        assert_eq!(metrics.number.get(), 53965);
+        assert_eq!(metrics.process_duration.get_sample_count(), 1);
+        assert_eq!(metrics.process_duration.get_sample_sum(), 0.02);
+        // The block's timestamp is the epoch
+        assert!((before..=after).contains(&metrics.head_drift.get()));
+
+        let names: Vec<String> = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "stream_block_number",
+                "stream_blocks_processed_total",
+                "stream_head_drift_seconds",
+                "stream_process_duration_seconds",
+            ]
+        );
+    }
+}
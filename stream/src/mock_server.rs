@@ -0,0 +1,627 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    future::{Ready, ready},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures03::{Stream, StreamExt, stream};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle, time::sleep};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    body::BoxBody,
+    codec::{CompressionEncoding, EnabledCompressionEncodings, ProstCodec},
+    codegen::{Body, BoxFuture, Service, StdError, http},
+    server::{Grpc, NamedService, ServerStreamingService},
+    transport::Server,
+};
+use tracing::error;
+
+use crate::pb::sf::substreams::rpc::v2::{
+    BlockScopedData, BlockUndoSignal, Error as ModuleError, ModulesProgress, Request, Response,
+    SessionInit, response::Message,
+};
+
+/// A message sent by the mock server over a scripted connection.
+#[derive(Clone, Debug)]
+pub enum MockResponse {
+    /// Sent to the client as is.
+    Message(Box<Response>),
+    /// Ends the stream with the given status, e.g., to simulate a mid-stream
+    /// error returned by the endpoint.
+    Status(tonic::Status),
+    /// Resets the stream without a status, the way a client observes the
+    /// endpoint going away in the middle of a stream.
+    Disconnect,
+}
+
+impl MockResponse {
+    pub fn session() -> Self {
+        Self::message(Message::Session(SessionInit {
+            trace_id: "mock".to_string(),
+            ..Default::default()
+        }))
+    }
+
+    pub fn block(block_data: BlockScopedData) -> Self {
+        Self::message(Message::BlockScopedData(block_data))
+    }
+
+    pub fn undo(undo_signal: BlockUndoSignal) -> Self {
+        Self::message(Message::BlockUndoSignal(undo_signal))
+    }
+
+    pub fn progress(progress: ModulesProgress) -> Self {
+        Self::message(Message::Progress(progress))
+    }
+
+    pub fn fatal_error(error: ModuleError) -> Self {
+        Self::message(Message::FatalError(error))
+    }
+
+    fn message(message: Message) -> Self {
+        MockResponse::Message(Box::new(Response {
+            message: Some(message),
+        }))
+    }
+}
+
+/// What the mock server does with a single `Blocks` call.
+#[derive(Clone, Debug)]
+pub enum MockConnection {
+    /// Streams the responses in order, then completes the stream unless one
+    /// of the responses ends it first.
+    Stream(Vec<MockResponse>),
+    /// Rejects the call with the given status, e.g., an unauthenticated
+    /// request.
+    Reject(tonic::Status),
+}
+
+struct MockStream {
+    connections: Mutex<VecDeque<MockConnection>>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<Response, tonic::Status>> + Send>>;
+
+/// How long the responses before a `MockResponse::Disconnect` are left to be
+/// read before the stream is reset.
+const DISCONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Marks a response whose stream is reset once its scripted responses are
+/// sent.
+#[derive(Clone, Copy)]
+struct Disconnected;
+
+impl MockStream {
+    fn blocks(&self, request: Request) -> Result<tonic::Response<ResponseStream>, tonic::Status> {
+        self.requests.lock().unwrap().push(request);
+
+        let connection = self.connections.lock().unwrap().pop_front();
+
+        let responses = match connection {
+            Some(MockConnection::Stream(responses)) => responses,
+            Some(MockConnection::Reject(status)) => return Err(status),
+            None => {
+                return Err(tonic::Status::unavailable(
+                    "mock server has no scripted connections left",
+                ));
+            }
+        };
+
+        let mut items = Vec::with_capacity(responses.len());
+        let mut disconnected = false;
+        for response in responses {
+            match response {
+                MockResponse::Message(response) => items.push(Ok(*response)),
+                MockResponse::Status(status) => {
+                    items.push(Err(status));
+                    break;
+                }
+                MockResponse::Disconnect => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        let stream: ResponseStream = Box::pin(tokio_stream::iter(items));
+        let mut response = tonic::Response::new(stream);
+        if disconnected {
+            response.extensions_mut().insert(Disconnected);
+        }
+
+        Ok(response)
+    }
+}
+
+/// The server side of the `Blocks` method of `sf.substreams.rpc.v2.Stream`.
+/// Only the client of the service is generated, so the mock implements the
+/// one method it serves on top of tonic's gRPC handling.
+#[derive(Clone)]
+struct MockStreamService(Arc<MockStream>);
+
+impl NamedService for MockStreamService {
+    const NAME: &'static str = "sf.substreams.rpc.v2.Stream";
+}
+
+impl ServerStreamingService<Request> for MockStreamService {
+    type Response = Response;
+    type ResponseStream = ResponseStream;
+    type Future = Ready<Result<tonic::Response<ResponseStream>, tonic::Status>>;
+
+    fn call(&mut self, request: tonic::Request<Request>) -> Self::Future {
+        ready(self.0.blocks(request.into_inner()))
+    }
+}
+
+impl<B> Service<http::Request<B>> for MockStreamService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+
+        Box::pin(async move {
+            if request.uri().path() != "/sf.substreams.rpc.v2.Stream/Blocks" {
+                return Ok(
+                    tonic::Status::unimplemented("mock server only serves Blocks").into_http(),
+                );
+            }
+
+            let mut encodings = EnabledCompressionEncodings::default();
+            encodings.enable(CompressionEncoding::Gzip);
+
+            let response = Grpc::new(ProstCodec::default())
+                .apply_compression_config(encodings, encodings)
+                .server_streaming(service, request)
+                .await;
+
+            if response.extensions().get::<Disconnected>().is_none() {
+                return Ok(response);
+            }
+
+            // Failing the body instead of ending it with the status trailers
+            // resets the HTTP/2 stream. A reset discards what the client
+            // hasn't read yet, so we give it time to read the responses.
+            let (parts, body) = response.into_parts();
+            let data = axum::body::Body::new(body)
+                .into_data_stream()
+                .chain(stream::once(async {
+                    sleep(DISCONNECT_DELAY).await;
+                    Err(axum::Error::new(std::io::Error::other(
+                        "mock server disconnected",
+                    )))
+                }));
+
+            Ok(http::Response::from_parts(
+                parts,
+                tonic::body::boxed(axum::body::Body::from_stream(data)),
+            ))
+        })
+    }
+}
+
+/// An in-process Substreams endpoint serving scripted responses on a local
+/// port, so `SubstreamsEndpoint` and `SubstreamsStream` can be exercised
+/// end-to-end without network access.
+///
+/// Each `Blocks` call consumes the next scripted connection. Once the script
+/// runs out, calls are rejected as unavailable. The server shuts down when
+/// dropped.
+pub struct MockSubstreamsServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockSubstreamsServer {
+    pub async fn start(connections: Vec<MockConnection>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let service = MockStreamService(Arc::new(MockStream {
+            connections: Mutex::new(connections.into()),
+            requests: requests.clone(),
+        }));
+
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let result = Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = shutdown_signal.await;
+                })
+                .await;
+
+            if let Err(error) = result {
+                error!("Mock substreams server error {}", error);
+            }
+        });
+
+        Ok(MockSubstreamsServer {
+            addr,
+            requests,
+            shutdown: Some(shutdown),
+            handle,
+        })
+    }
+
+    /// The url to pass to `SubstreamsEndpoint::new`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Every request received so far, in order, e.g., to check the cursor a
+    /// stream reconnected with.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockSubstreamsServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures03::StreamExt;
+
+    use super::*;
+    use crate::{
+        error::StreamError,
+        observer::StreamObserver,
+        pb::sf::substreams::rpc::v2::{InitialSnapshotComplete, InitialSnapshotData},
+        substreams::{EndpointPool, SubstreamsEndpoint},
+        substreams_stream::{BlockResponse, SubstreamsStream},
+        test_utils::{make_block, make_block_data, make_undo, make_undo_signal},
+    };
+
+    async fn stream(server: &MockSubstreamsServer, token: Option<String>) -> SubstreamsStream {
+        observed_stream(server, token, None).await
+    }
+
+    async fn observed_stream(
+        server: &MockSubstreamsServer,
+        token: Option<String>,
+        observer: Option<Arc<dyn StreamObserver>>,
+    ) -> SubstreamsStream {
+        let endpoint = SubstreamsEndpoint::new(server.url(), token).await.unwrap();
+
+        SubstreamsStream::new(
+            Arc::new(endpoint),
+            None,
+            None,
+            "geo_out".to_string(),
+            0,
+            0,
+            false,
+            observer,
+        )
+    }
+
+    fn block_number(response: &BlockResponse) -> u64 {
+        match response {
+            BlockResponse::New(block_data) => block_data.clock.as_ref().unwrap().number,
+            BlockResponse::Undo(undo_signal) => {
+                undo_signal.last_valid_block.as_ref().unwrap().number
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_scripted_responses() {
+        let server = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::session(),
+            MockResponse::block(make_block_data(1)),
+            MockResponse::progress(ModulesProgress::default()),
+            MockResponse::block(make_block_data(2)),
+            MockResponse::undo(make_undo_signal(1)),
+            MockResponse::block(make_block_data(2)),
+        ])])
+        .await
+        .unwrap();
+
+        let responses: Vec<_> = stream(&server, Some("token".to_string()))
+            .await
+            .collect()
+            .await;
+        let responses: Vec<_> = responses.into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(
+            responses,
+            vec![make_block(1), make_block(2), make_undo(1), make_block(2)]
+        );
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(server.requests()[0].output_module, "geo_out");
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_from_latest_cursor() {
+        let server = MockSubstreamsServer::start(vec![
+            MockConnection::Stream(vec![
+                MockResponse::block(make_block_data(1)),
+                MockResponse::block(make_block_data(2)),
+                MockResponse::Disconnect,
+            ]),
+            MockConnection::Stream(vec![
+                MockResponse::block(make_block_data(3)),
+                MockResponse::Status(tonic::Status::internal("stream reset")),
+            ]),
+            MockConnection::Stream(vec![MockResponse::block(make_block_data(4))]),
+        ])
+        .await
+        .unwrap();
+
+        let responses: Vec<_> = stream(&server, None).await.collect().await;
+        let block_numbers: Vec<_> = responses
+            .iter()
+            .map(|response| block_number(response.as_ref().unwrap()))
+            .collect();
+
+        assert_eq!(block_numbers, vec![1, 2, 3, 4]);
+
+        let cursors: Vec<_> = server
+            .requests()
+            .into_iter()
+            .map(|request| request.start_cursor)
+            .collect();
+        assert_eq!(cursors, vec!["", "cursor-2", "cursor-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_resets_the_stream() {
+        let server = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::block(make_block_data(1)),
+            MockResponse::Disconnect,
+            MockResponse::block(make_block_data(2)),
+        ])])
+        .await
+        .unwrap();
+
+        let endpoint = SubstreamsEndpoint::new(server.url(), None).await.unwrap();
+        let responses: Vec<_> = Arc::new(endpoint)
+            .substreams(Request::default())
+            .await
+            .unwrap()
+            .take(2)
+            .collect()
+            .await;
+
+        assert_eq!(
+            responses[0].as_ref().unwrap().message,
+            Some(Message::BlockScopedData(make_block_data(1)))
+        );
+        // The stream fails with a transport error, not a status sent by the server
+        let status = responses[1].as_ref().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert!(!status.message().contains("mock server"));
+    }
+
+    #[tokio::test]
+    async fn test_fatal_errors_are_not_retried() {
+        let server = MockSubstreamsServer::start(vec![MockConnection::Reject(
+            tonic::Status::unauthenticated("bad token"),
+        )])
+        .await
+        .unwrap();
+
+        let responses: Vec<_> = stream(&server, None).await.collect().await;
+
+        assert_eq!(responses.len(), 1);
+        assert!(matches!(responses[0], Err(StreamError::Unauthenticated(_))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_when_endpoint_rejects_credentials() {
+        let primary = MockSubstreamsServer::start(vec![MockConnection::Reject(
+            tonic::Status::permission_denied("bad token"),
+        )])
+        .await
+        .unwrap();
+        let fallback = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::block(make_block_data(1)),
+            MockResponse::block(make_block_data(2)),
+        ])])
+        .await
+        .unwrap();
+
+        let mut endpoints = vec![];
+        for server in [&primary, &fallback] {
+            let endpoint = SubstreamsEndpoint::new(server.url(), None).await.unwrap();
+            endpoints.push(Arc::new(endpoint));
+        }
+
+        // Fails over right away even though the pool tolerates more
+        // failures of the other kinds.
+        let stream = SubstreamsStream::new(
+            EndpointPool::new(endpoints, 5, std::time::Duration::MAX),
+            None,
+            None,
+            "geo_out".to_string(),
+            0,
+            0,
+            false,
+            None,
+        );
+
+        let responses: Vec<_> = stream.collect().await;
+        let responses: Vec<_> = responses.into_iter().map(Result::unwrap).collect();
+
+        assert_eq!(responses, vec![make_block(1), make_block(2)]);
+        assert_eq!(primary.requests().len(), 1);
+        assert_eq!(fallback.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_module_failure_ends_stream() {
+        let server = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::block(make_block_data(1)),
+            MockResponse::fatal_error(ModuleError {
+                module: "geo_out".to_string(),
+                reason: "panicked".to_string(),
+                ..Default::default()
+            }),
+            MockResponse::block(make_block_data(2)),
+        ])])
+        .await
+        .unwrap();
+
+        let responses: Vec<_> = stream(&server, None).await.collect().await;
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(*responses[0].as_ref().unwrap(), make_block(1));
+        assert!(matches!(responses[1], Err(StreamError::ModuleFailed(_))));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_and_returns_to_primary() {
+        let primary = MockSubstreamsServer::start(vec![
+            MockConnection::Reject(tonic::Status::unavailable("down")),
+            MockConnection::Stream(vec![
+                MockResponse::block(make_block_data(2)),
+                MockResponse::block(make_block_data(3)),
+            ]),
+        ])
+        .await
+        .unwrap();
+        let fallback = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::block(make_block_data(1)),
+            MockResponse::block(make_block_data(2)),
+        ])])
+        .await
+        .unwrap();
+
+        let mut endpoints = vec![];
+        for server in [&primary, &fallback] {
+            let endpoint = SubstreamsEndpoint::new(server.url(), None).await.unwrap();
+            endpoints.push(Arc::new(endpoint));
+        }
+
+        // Fails over on the first failure and returns to the primary
+        // endpoint as soon as the fallback endpoint streamed a block.
+        let stream = SubstreamsStream::new(
+            EndpointPool::new(endpoints, 1, std::time::Duration::ZERO),
+            None,
+            None,
+            "geo_out".to_string(),
+            0,
+            0,
+            false,
+            None,
+        );
+
+        let responses: Vec<_> = stream.collect().await;
+        let block_numbers: Vec<_> = responses
+            .iter()
+            .map(|response| block_number(response.as_ref().unwrap()))
+            .collect();
+
+        assert_eq!(block_numbers, vec![1, 2, 3]);
+
+        let cursors = |server: &MockSubstreamsServer| -> Vec<String> {
+            server
+                .requests()
+                .into_iter()
+                .map(|request| request.start_cursor)
+                .collect()
+        };
+        assert_eq!(cursors(&primary), vec!["", "cursor-1"]);
+        assert_eq!(cursors(&fallback), vec![""]);
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        messages: Mutex<Vec<String>>,
+    }
+
+    impl StreamObserver for RecordingObserver {
+        fn on_session(&self, session: &SessionInit) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push(format!("session {}", session.resolved_start_block));
+        }
+
+        fn on_progress(&self, progress: &ModulesProgress) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push(format!("progress {}", progress.running_jobs.len()));
+        }
+
+        fn on_snapshot_data(&self, snapshot: &InitialSnapshotData) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push(format!("snapshot {}", snapshot.module_name));
+        }
+
+        fn on_snapshot_complete(&self, complete: &InitialSnapshotComplete) {
+            self.messages
+                .lock()
+                .unwrap()
+                .push(format!("snapshot complete {}", complete.cursor));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwards_messages_to_observer() {
+        let server = MockSubstreamsServer::start(vec![MockConnection::Stream(vec![
+            MockResponse::message(Message::Session(SessionInit {
+                resolved_start_block: 36_000,
+                ..Default::default()
+            })),
+            MockResponse::message(Message::DebugSnapshotData(InitialSnapshotData {
+                module_name: "store_spaces".to_string(),
+                ..Default::default()
+            })),
+            MockResponse::message(Message::DebugSnapshotComplete(InitialSnapshotComplete {
+                cursor: "cursor-0".to_string(),
+            })),
+            MockResponse::progress(ModulesProgress::default()),
+            MockResponse::block(make_block_data(1)),
+        ])])
+        .await
+        .unwrap();
+
+        let observer = Arc::new(RecordingObserver::default());
+        let responses: Vec<_> = observed_stream(&server, None, Some(observer.clone()))
+            .await
+            .collect()
+            .await;
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            *observer.messages.lock().unwrap(),
+            vec![
+                "session 36000",
+                "snapshot store_spaces",
+                "snapshot complete cursor-0",
+                "progress 0",
+            ]
+        );
+    }
+}
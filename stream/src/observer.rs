@@ -0,0 +1,31 @@
+use crate::pb::sf::substreams::rpc::v2::{
+    InitialSnapshotComplete, InitialSnapshotData, ModulesProgress, SessionInit,
+};
+
+/// Receives the messages a Substreams endpoint sends alongside blocks, which
+/// sinks never see, e.g., to report backfill progress or export metrics.
+///
+/// Every method defaults to doing nothing. Methods are called inline while
+/// streaming, so they must not block.
+pub trait StreamObserver: Send + Sync {
+    /// Called once the sink's cursor has been loaded, before streaming starts.
+    /// The module hash identifies the version of the package being streamed,
+    /// see `cursor::module_hash`.
+    fn on_module_hash(&self, _module_hash: &str) {}
+
+    /// Called each time a connection to the endpoint is established. The
+    /// session carries the start block the endpoint resolved the request to.
+    fn on_session(&self, _session: &SessionInit) {}
+
+    /// Called for every progress message, which the endpoint sends while it
+    /// processes blocks in parallel ahead of streaming them, e.g., during a
+    /// backfill.
+    fn on_progress(&self, _progress: &ModulesProgress) {}
+
+    /// Called with each store snapshot entry when debugging initial store
+    /// snapshots.
+    fn on_snapshot_data(&self, _snapshot: &InitialSnapshotData) {}
+
+    /// Called once every store snapshot entry has been sent.
+    fn on_snapshot_complete(&self, _complete: &InitialSnapshotComplete) {}
+}
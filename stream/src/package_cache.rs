@@ -0,0 +1,183 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PackageCacheError {
+    #[error("Package cache error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Package cache error: {0} doesn't match its content hash")]
+    Corrupted(String),
+}
+
+/// Caches packages resolved from the registry on disk, keyed by their name,
+/// version and content hash, e.g., `<dir>/geo-substream/v1.0.0/<sha256>.spkg`.
+///
+/// Runs resolving a pinned version read it from the cache instead of fetching
+/// it again, and runs resolving `latest` fall back to the package they last
+/// fetched when the registry can't be reached.
+#[derive(Clone, Debug)]
+pub struct PackageCache {
+    dir: PathBuf,
+}
+
+impl PackageCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        PackageCache { dir: dir.into() }
+    }
+
+    fn version_dir(&self, name: &str, version: &str) -> PathBuf {
+        self.dir.join(name).join(version)
+    }
+
+    /// Stores the content of `name@version`, returning its content hash.
+    pub async fn store(
+        &self,
+        name: &str,
+        version: &str,
+        content: &[u8],
+    ) -> Result<String, PackageCacheError> {
+        let dir = self.version_dir(name, version);
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let hash = content_hash(content);
+        let path = dir.join(format!("{}.spkg", hash));
+        let tmp_path = path.with_extension("spkg.tmp");
+
+        // Write to a temporary file first and rename it so a crash mid-write
+        // never leaves a truncated package behind.
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        Ok(hash)
+    }
+
+    /// Loads the content of `name@version` stored last, checking that it
+    /// still matches its content hash.
+    pub async fn load(
+        &self,
+        name: &str,
+        version: &str,
+    ) -> Result<Option<Vec<u8>>, PackageCacheError> {
+        let mut entries = match tokio::fs::read_dir(self.version_dir(name, version)).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut latest: Option<(SystemTime, PathBuf)> = None;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "spkg") {
+                continue;
+            }
+
+            let modified = entry.metadata().await?.modified()?;
+            if latest.as_ref().is_none_or(|(latest, _)| modified > *latest) {
+                latest = Some((modified, path));
+            }
+        }
+
+        let Some((_, path)) = latest else {
+            return Ok(None);
+        };
+
+        let content = tokio::fs::read(&path).await?;
+        if !matches_content_hash(&path, &content) {
+            return Err(PackageCacheError::Corrupted(path.display().to_string()));
+        }
+
+        Ok(Some(content))
+    }
+}
+
+fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+fn matches_content_hash(path: &Path, content: &[u8]) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|hash| hash == content_hash(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_dir;
+
+    #[tokio::test]
+    async fn test_missing_package() {
+        let cache = PackageCache::new(test_dir("package-cache-missing"));
+
+        assert_eq!(cache.load("geo-substream", "v1.0.0").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let cache = PackageCache::new(test_dir("package-cache-roundtrip"));
+
+        let hash = cache
+            .store("geo-substream", "v1.0.0", b"package")
+            .await
+            .unwrap();
+
+        assert_eq!(hash, content_hash(b"package"));
+        assert_eq!(
+            cache.load("geo-substream", "v1.0.0").await.unwrap(),
+            Some(b"package".to_vec())
+        );
+        assert_eq!(cache.load("geo-substream", "v1.0.1").await.unwrap(), None);
+        assert_eq!(cache.load("other", "v1.0.0").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_loads_latest_stored_content() {
+        let cache = PackageCache::new(test_dir("package-cache-latest"));
+
+        cache
+            .store("geo-substream", "latest", b"first")
+            .await
+            .unwrap();
+        // File modification times may not be precise enough to tell apart
+        // writes happening right after one another.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        cache
+            .store("geo-substream", "latest", b"second")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cache.load("geo-substream", "latest").await.unwrap(),
+            Some(b"second".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detects_corrupted_package() {
+        let dir = test_dir("package-cache-corrupted");
+        let cache = PackageCache::new(&dir);
+
+        let hash = cache
+            .store("geo-substream", "v1.0.0", b"package")
+            .await
+            .unwrap();
+        std::fs::write(
+            dir.join("geo-substream")
+                .join("v1.0.0")
+                .join(format!("{}.spkg", hash)),
+            b"tampered",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            cache.load("geo-substream", "v1.0.0").await,
+            Err(PackageCacheError::Corrupted(_))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use async_stream::try_stream;
+use futures03::{Stream, StreamExt};
+use prost::Message;
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+};
+use tracing::info;
+
+use crate::{
+    error::StreamError,
+    pb::sf::substreams::rpc::v2::{Response, response::Message as ResponseMessage},
+    substreams_stream::BlockResponse,
+};
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Replay error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Replay error: {0}")]
+    Decode(#[from] prost::DecodeError),
+
+    #[error("Replay error: cursor {0} not found in recording")]
+    CursorNotFound(String),
+}
+
+/// Appends every block it is given to a recording file. Recordings are a
+/// sequence of length-delimited `Response` messages, i.e., the exact messages
+/// the Substreams endpoint sent, so they can be inspected with the regular
+/// protobuf tooling.
+pub struct BlockRecorder {
+    file: File,
+}
+
+impl BlockRecorder {
+    /// Opens the recording at `path`, appending to it if it already exists so
+    /// a sink resuming from its cursor keeps extending the same recording.
+    pub async fn create(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(BlockRecorder { file })
+    }
+
+    pub async fn record(&mut self, response: &BlockResponse) -> Result<(), ReplayError> {
+        let message = match response {
+            BlockResponse::New(block_data) => ResponseMessage::BlockScopedData(block_data.clone()),
+            BlockResponse::Undo(undo_signal) => {
+                ResponseMessage::BlockUndoSignal(undo_signal.clone())
+            }
+        };
+
+        let encoded = Response {
+            message: Some(message),
+        }
+        .encode_length_delimited_to_vec();
+
+        self.file.write_all(&encoded).await?;
+        self.file.flush().await?;
+
+        Ok(())
+    }
+}
+
+/// Streams the blocks of a recording made with `BlockRecorder`, following the
+/// same semantics as `SubstreamsStream` for the cursor, start and stop block.
+///
+/// Blocks are yielded as they were recorded, so replaying a recording of head
+/// blocks yields the same `BlockUndoSignal`s the sink received when it was
+/// recorded.
+pub struct ReplayStream {
+    stream: Pin<Box<dyn Stream<Item = Result<BlockResponse, StreamError>> + Send>>,
+}
+
+impl ReplayStream {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        cursor: Option<String>,
+        start_block: i64,
+        end_block: u64,
+    ) -> Self {
+        ReplayStream {
+            stream: Box::pin(replay_blocks(path.into(), cursor, start_block, end_block)),
+        }
+    }
+}
+
+fn replay_blocks(
+    path: PathBuf,
+    cursor: Option<String>,
+    start_block_num: i64,
+    stop_block_num: u64,
+) -> impl Stream<Item = Result<BlockResponse, StreamError>> {
+    try_stream! {
+        let file = File::open(&path).await.map_err(|e| StreamError::Replay(e.into()))?;
+        let mut reader = BufReader::new(file);
+
+        // Like the endpoint, resuming from a cursor starts right after the block
+        // the cursor points at and ignores the start block.
+        let mut skip_until_cursor = cursor.filter(|cursor| !cursor.is_empty());
+        let start_block_num = match skip_until_cursor {
+            Some(_) => 0,
+            None => u64::try_from(start_block_num).unwrap_or(0),
+        };
+
+        while let Some(buf) = read_length_delimited(&mut reader).await.map_err(StreamError::Replay)? {
+            let response = Response::decode(buf.as_slice()).map_err(|e| StreamError::Replay(e.into()))?;
+
+            let (response, cursor, block_number) = match response.message {
+                Some(ResponseMessage::BlockScopedData(block_data)) => {
+                    let cursor = block_data.cursor.clone();
+                    let block_number = block_data.clock.as_ref().map_or(0, |clock| clock.number);
+                    (BlockResponse::New(block_data), cursor, block_number)
+                }
+                Some(ResponseMessage::BlockUndoSignal(undo_signal)) => {
+                    let cursor = undo_signal.last_valid_cursor.clone();
+                    let block_number = undo_signal.last_valid_block.as_ref().map_or(0, |block| block.number);
+                    (BlockResponse::Undo(undo_signal), cursor, block_number)
+                }
+                _ => continue,
+            };
+
+            if let Some(resume_cursor) = &skip_until_cursor {
+                if *resume_cursor == cursor {
+                    skip_until_cursor = None;
+                }
+                continue;
+            }
+
+            if let BlockResponse::New(_) = response {
+                if block_number < start_block_num {
+                    continue;
+                }
+
+                // The stop block is exclusive.
+                if stop_block_num != 0 && block_number >= stop_block_num {
+                    break;
+                }
+            }
+
+            yield response;
+        }
+
+        if let Some(cursor) = skip_until_cursor {
+            return Err(StreamError::Replay(ReplayError::CursorNotFound(cursor)))?;
+        }
+
+        info!("Replay completed, reached end of recording");
+    }
+}
+
+/// Reads the next length-delimited message, returning `None` at the end of
+/// the recording.
+async fn read_length_delimited<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<u8>>, ReplayError> {
+    let mut len: u64 = 0;
+
+    for shift in (0..64).step_by(7) {
+        let byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof && shift == 0 => {
+                return Ok(None);
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        len |= u64::from(byte & 0x7f) << shift;
+
+        if byte & 0x80 == 0 {
+            let mut buf = vec![0; len as usize];
+            reader.read_exact(&mut buf).await?;
+            return Ok(Some(buf));
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "invalid message length in recording",
+    )
+    .into())
+}
+
+impl Stream for ReplayStream {
+    type Item = Result<BlockResponse, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        cursor::Cursor,
+        pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+        sink::Sink,
+        test_utils::{make_block, make_undo, test_path},
+    };
+
+    async fn record(path: &Path, responses: &[BlockResponse]) {
+        let mut recorder = BlockRecorder::create(path).await.unwrap();
+        for response in responses {
+            recorder.record(response).await.unwrap();
+        }
+    }
+
+    async fn replay(stream: ReplayStream) -> Result<Vec<BlockResponse>, StreamError> {
+        stream.collect::<Vec<_>>().await.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_roundtrip() {
+        let path = test_path("replay-roundtrip");
+        let responses = vec![make_block(1), make_block(2), make_undo(1), make_block(2)];
+        record(&path, &responses).await;
+
+        let replayed = replay(ReplayStream::new(&path, None, 0, 0)).await.unwrap();
+        assert_eq!(replayed, responses);
+    }
+
+    #[tokio::test]
+    async fn test_replay_resumes_after_cursor() {
+        let path = test_path("replay-cursor");
+        record(&path, &[make_block(1), make_block(2), make_block(3)]).await;
+
+        // The start block is ignored when resuming from a cursor.
+        let replayed = replay(ReplayStream::new(&path, Some("cursor-1".to_string()), 3, 0))
+            .await
+            .unwrap();
+        assert_eq!(replayed, vec![make_block(2), make_block(3)]);
+
+        let error = replay(ReplayStream::new(&path, Some("unknown".to_string()), 0, 0))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StreamError::Replay(ReplayError::CursorNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replay_respects_start_and_stop_block() {
+        let path = test_path("replay-range");
+        record(
+            &path,
+            &[make_block(1), make_block(2), make_block(3), make_block(4)],
+        )
+        .await;
+
+        let replayed = replay(ReplayStream::new(&path, None, 2, 4)).await.unwrap();
+        assert_eq!(replayed, vec![make_block(2), make_block(3)]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_truncated_recording() {
+        let path = test_path("replay-truncated");
+        record(&path, &[make_block(1), make_block(2)]).await;
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+
+        let mut stream = ReplayStream::new(&path, None, 0, 0);
+        assert_eq!(stream.next().await.unwrap().unwrap(), make_block(1));
+        assert!(matches!(
+            stream.next().await.unwrap(),
+            Err(StreamError::Replay(ReplayError::Io(_)))
+        ));
+    }
+
+    #[derive(Default)]
+    struct TestSink {
+        processed: Mutex<Vec<BlockResponse>>,
+        cursors: Mutex<Vec<Cursor>>,
+    }
+
+    #[derive(Error, Debug)]
+    #[error("test sink error")]
+    struct TestSinkError;
+
+    impl Sink<()> for TestSink {
+        type Error = TestSinkError;
+
+        async fn process_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+        ) -> Result<(), Self::Error> {
+            let response = BlockResponse::New(block_data.clone());
+            self.processed.lock().unwrap().push(response);
+            Ok(())
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            let response = BlockResponse::Undo(undo_signal.clone());
+            self.processed.lock().unwrap().push(response);
+            Ok(())
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            self.cursors.lock().unwrap().push(cursor);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sink_replays_recording() {
+        let path = test_path("replay-sink");
+        let responses = vec![make_block(1), make_block(2), make_undo(1), make_block(2)];
+        record(&path, &responses).await;
+
+        let sink = TestSink::default();
+        sink.run_stream("abc", ReplayStream::new(&path, None, 0, 0))
+            .await
+            .unwrap();
+
+        assert_eq!(*sink.processed.lock().unwrap(), responses);
+
+        let block_numbers: Vec<u64> = sink
+            .cursors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|cursor| cursor.block_number)
+            .collect();
+        assert_eq!(block_numbers, vec![1, 2, 1, 2]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::future::Future;
+
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{config::RunConfig, error::StreamError};
+
+/// A token cancelled once the process receives SIGINT or SIGTERM, to pass as
+/// `RunConfig::shutdown`.
+pub fn shutdown_on_signals() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+    let token = shutdown.clone();
+
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Received shutdown signal");
+        token.cancel();
+    });
+
+    shutdown
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Runs `run` to completion, unless shutdown is requested and `run` doesn't
+/// complete within the configured shutdown timeout.
+///
+/// Streams built from the config stop taking new blocks on shutdown, so `run`
+/// completes once the blocks in flight have been processed. When the timeout
+/// passes first, `run` is dropped in the middle of a block. Cursors are only
+/// persisted once a block has been fully processed, so the sink resumes from
+/// the last block it committed.
+pub(crate) async fn with_shutdown_timeout<F, T>(
+    config: &RunConfig,
+    run: F,
+) -> Result<T, StreamError>
+where
+    F: Future<Output = Result<T, StreamError>>,
+{
+    let timeout = async {
+        config.shutdown.cancelled().await;
+        info!(
+            "Shutting down, waiting up to {:?} for blocks in flight",
+            config.shutdown_timeout
+        );
+        sleep(config.shutdown_timeout).await;
+    };
+
+    tokio::select! {
+        result = run => result,
+        _ = timeout => Err(StreamError::ShutdownTimedOut(config.shutdown_timeout)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use thiserror::Error;
+
+    use super::*;
+    use crate::{
+        cursor::Cursor,
+        pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+        sink::Sink,
+        test_utils::make_config,
+    };
+
+    #[derive(Error, Debug)]
+    #[error("test sink error")]
+    struct TestSinkError;
+
+    /// Requests shutdown while processing `shutdown_block`, then takes
+    /// `processing_time` to finish processing it.
+    struct TestSink {
+        shutdown: CancellationToken,
+        shutdown_block: u64,
+        processing_time: Duration,
+        processed: Mutex<Vec<u64>>,
+        cursors: Mutex<Vec<u64>>,
+    }
+
+    impl TestSink {
+        fn new(config: &RunConfig, shutdown_block: u64, processing_time: Duration) -> Self {
+            TestSink {
+                shutdown: config.shutdown.clone(),
+                shutdown_block,
+                processing_time,
+                processed: Mutex::new(vec![]),
+                cursors: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl Sink<()> for TestSink {
+        type Error = TestSinkError;
+
+        async fn process_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+        ) -> Result<(), Self::Error> {
+            let number = block_data.clock.as_ref().unwrap().number;
+
+            if number == self.shutdown_block {
+                self.shutdown.cancel();
+                sleep(self.processing_time).await;
+            }
+
+            self.processed.lock().unwrap().push(number);
+            Ok(())
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            _undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            Err(TestSinkError)
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            self.cursors.lock().unwrap().push(cursor.block_number);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_finishes_block_in_flight_on_shutdown() {
+        let config = make_config("shutdown-drain", 5).await;
+        let sink = TestSink::new(&config, 2, Duration::from_millis(50));
+
+        sink.run(&config).await.unwrap();
+
+        assert_eq!(*sink.processed.lock().unwrap(), vec![1, 2]);
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_on_block_in_flight_after_timeout() {
+        let config = RunConfig {
+            shutdown_timeout: Duration::from_millis(10),
+            ..make_config("shutdown-timeout", 5).await
+        };
+        let sink = TestSink::new(&config, 2, Duration::from_secs(60));
+
+        let result = sink.run(&config).await;
+
+        assert!(matches!(result, Err(StreamError::ShutdownTimedOut(_))));
+        assert_eq!(*sink.processed.lock().unwrap(), vec![1]);
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1]);
+    }
+}
@@ -1,21 +1,36 @@
 use anyhow::{Context, Error, format_err};
-use futures03::StreamExt;
+use futures03::{Stream, StreamExt};
 use lazy_static::lazy_static;
 use prost::Message;
 use regex::Regex;
 use semver::Version;
 
-use std::{env, process::exit, sync::Arc};
+use std::{marker::PhantomData, pin::Pin, sync::Arc, time::Instant};
+use tracing::{Instrument, error, info, warn};
 
 use crate::{
+    config::RunConfig,
+    cursor::{self, Cursor},
+    decoded::{check_output_type, output_type_url},
+    error::StreamError,
+    health::{self, Phase},
+    logging, metrics,
+    package_cache::PackageCache,
     pb::sf::substreams::{
         rpc::v2::{BlockScopedData, BlockUndoSignal},
         v1::Package,
     },
-    substreams::SubstreamsEndpoint,
+    replay::{BlockRecorder, ReplayStream},
+    shutdown::with_shutdown_timeout,
+    substreams::{EndpointPool, SubstreamsEndpoint},
     substreams_stream::{BlockResponse, SubstreamsStream},
 };
 
+enum PreprocessedResponse<P> {
+    New(BlockScopedData, P),
+    Undo(BlockUndoSignal),
+}
+
 pub trait PreprocessedSink<P: Send>: Send + Sync {
     type Error: std::error::Error + Send + Sync + 'static;
 
@@ -30,101 +45,108 @@ pub trait PreprocessedSink<P: Send>: Send + Sync {
         decoded_data: P,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
 
-    fn process_block_undo_signal(&self, _undo_signal: &BlockUndoSignal) -> Result<(), Self::Error> {
-        // `BlockUndoSignal` must be treated as "delete every data that has been recorded after
-        // block height specified by block in BlockUndoSignal". In the example above, this means
-        // you must delete changes done by `Block #7b` and `Block #6b`. The exact details depends
-        // on your own logic. If for example all your added record contain a block number, a
-        // simple way is to do `delete all records where block_num > 5` which is the block num
-        // received in the `BlockUndoSignal` (this is true for append only records, so when only `INSERT` are allowed).
-        unimplemented!(
-            "you must implement some kind of block undo handling, or request only final blocks (tweak substreams_stream.rs)"
-        )
-    }
+    /// `BlockUndoSignal` must be treated as "delete every data that has been
+    /// recorded after the block height specified by the `BlockUndoSignal`".
+    /// The exact details depend on your own logic. If for example all your
+    /// records contain a block number, a simple way is to do `delete all
+    /// records where block_num > 5`, 5 being the block number received in the
+    /// `BlockUndoSignal` (this is true for append only records, so when only
+    /// `INSERT` are allowed).
+    ///
+    /// Sinks streaming only final blocks never receive a `BlockUndoSignal`,
+    /// see `RunConfig::final_blocks_only`, and may return an error instead.
+    fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
 
     fn persist_cursor(
         &self,
-        _cursor: String,
+        _module_hash: &str,
+        _cursor: Cursor,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
-        // FIXME: Handling of the cursor is missing here. It should be saved each time
-        // a full block has been correctly processed/persisted. The saving location
-        // is your responsibility.
+        // The cursor should be saved each time a full block has been correctly
+        // processed/persisted, usually through a `CursorStore` keyed by the sink's
+        // name and the module hash.
         //
         // By making it persistent, we ensure that if we crash, on startup we are
-        // going to read it back from database and start back our SubstreamsStream
-        // with it ensuring we are continuously streaming without ever losing a single
-        // element.
+        // going to read it back and start back our SubstreamsStream with it ensuring
+        // we are continuously streaming without ever losing a single element.
         async { Ok(()) }
     }
 
     fn load_persisted_cursor(
         &self,
-    ) -> impl std::future::Future<Output = Result<Option<String>, Self::Error>> + Send {
-        // FIXME: Handling of the cursor is missing here. It should be loaded from
-        // somewhere (local file, database, cloud storage) and then `SubstreamStream` will
-        // be able correctly resume from the right block.
+        _module_hash: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Cursor>, Self::Error>> + Send {
+        // The cursor should be loaded from the same `CursorStore` it was persisted
+        // to so `SubstreamStream` is able to correctly resume from the right block.
+        // Returning `None` streams from the configured start block.
         async { Ok(None) }
     }
 
     fn run(
         &self,
-        endpoint_url: &str,
-        spkg_file: &str,
-        module_name: &str,
-        start_block: i64,
-        end_block: u64,
-    ) -> impl std::future::Future<Output = Result<(), anyhow::Error>> + Send {
+        config: &RunConfig,
+    ) -> impl std::future::Future<Output = Result<(), StreamError>> + Send {
         async move {
-            let token_env = env::var("SUBSTREAMS_API_TOKEN").unwrap_or("".to_string());
-            let mut token: Option<String> = None;
-            if !token_env.is_empty() {
-                token = Some(token_env);
-            }
-
-            let cursor: Option<String> = self.load_persisted_cursor().await?;
-
-            println!("Processing block {}", spkg_file);
+            info!("Processing package {}", config.spkg_file);
 
-            let package = read_package(spkg_file).await.unwrap();
+            let (package, module_hash) = load_package(config).await?;
 
-            let endpoint = Arc::new(SubstreamsEndpoint::new(&endpoint_url, token).await?);
+            let cursor = self
+                .load_persisted_cursor(&module_hash)
+                .await
+                .map_err(StreamError::sink)?;
 
-            let mut stream = SubstreamsStream::new(
-                endpoint.clone(),
-                cursor,
-                package.modules.clone(),
-                module_name.to_string(),
-                start_block,
-                end_block,
-            );
-
-            loop {
-                match stream.next().await {
-                    None => {
-                        println!("Stream consumed");
-                        break;
-                    }
-                    Some(Ok(BlockResponse::New(data))) => {
-                        let decoded_data = self.preprocess_block_scoped_data(&data).await?;
-                        self.process_block_scoped_data(&data, decoded_data).await?;
-                        self.persist_cursor(data.cursor).await?;
-                    }
-                    Some(Ok(BlockResponse::Undo(undo_signal))) => {
-                        self.process_block_undo_signal(&undo_signal)?;
-                        self.persist_cursor(undo_signal.last_valid_cursor).await?;
-                    }
-                    Some(Err(err)) => {
-                        println!();
-                        println!("Stream terminated with error");
-                        println!("{:?}", err);
-                        exit(1);
-                    }
-                }
+            if let Some(cursor) = &cursor {
+                info!(
+                    "Resuming from persisted cursor at block {}",
+                    cursor.block_number
+                );
             }
 
-            Ok(())
+            let stream = block_stream(
+                config,
+                &package,
+                &module_hash,
+                cursor.map(|cursor| cursor.cursor),
+            )
+            .await?;
+            let recorder = block_recorder(config).await?;
+            health::set_phase(Phase::Streaming);
+
+            with_shutdown_timeout(
+                config,
+                process_preprocessed_stream(
+                    self,
+                    &module_hash,
+                    config.preprocess_lookahead,
+                    stream,
+                    recorder,
+                ),
+            )
+            .await
         }
     }
+
+    /// Processes every block of `stream` in order, persisting the cursor after
+    /// each one. `run` builds the stream from its config, this allows driving
+    /// the sink with any other source of blocks, e.g., a `ReplayStream` in tests.
+    ///
+    /// Up to `lookahead` blocks are preprocessed concurrently ahead of the block
+    /// being processed.
+    fn run_stream<S>(
+        &self,
+        module_hash: &str,
+        lookahead: usize,
+        stream: S,
+    ) -> impl std::future::Future<Output = Result<(), StreamError>> + Send
+    where
+        S: Stream<Item = Result<BlockResponse, StreamError>> + Send,
+    {
+        process_preprocessed_stream(self, module_hash, lookahead, stream, None)
+    }
 }
 
 pub trait Sink<T: Send>: Send + Sync {
@@ -135,99 +157,283 @@ pub trait Sink<T: Send>: Send + Sync {
         block_data: &BlockScopedData,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
 
-    fn process_block_undo_signal(&self, _undo_signal: &BlockUndoSignal) -> Result<(), Self::Error> {
-        // `BlockUndoSignal` must be treated as "delete every data that has been recorded after
-        // block height specified by block in BlockUndoSignal". In the example above, this means
-        // you must delete changes done by `Block #7b` and `Block #6b`. The exact details depends
-        // on your own logic. If for example all your added record contain a block number, a
-        // simple way is to do `delete all records where block_num > 5` which is the block num
-        // received in the `BlockUndoSignal` (this is true for append only records, so when only `INSERT` are allowed).
-        unimplemented!(
-            "you must implement some kind of block undo handling, or request only final blocks (tweak substreams_stream.rs)"
-        )
-    }
+    /// `BlockUndoSignal` must be treated as "delete every data that has been
+    /// recorded after the block height specified by the `BlockUndoSignal`".
+    /// The exact details depend on your own logic. If for example all your
+    /// records contain a block number, a simple way is to do `delete all
+    /// records where block_num > 5`, 5 being the block number received in the
+    /// `BlockUndoSignal` (this is true for append only records, so when only
+    /// `INSERT` are allowed).
+    ///
+    /// Sinks streaming only final blocks never receive a `BlockUndoSignal`,
+    /// see `RunConfig::final_blocks_only`, and may return an error instead.
+    fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send;
 
     fn persist_cursor(
         &self,
-        _cursor: String,
+        _module_hash: &str,
+        _cursor: Cursor,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
-        // FIXME: Handling of the cursor is missing here. It should be saved each time
-        // a full block has been correctly processed/persisted. The saving location
-        // is your responsibility.
+        // The cursor should be saved each time a full block has been correctly
+        // processed/persisted, usually through a `CursorStore` keyed by the sink's
+        // name and the module hash.
         //
         // By making it persistent, we ensure that if we crash, on startup we are
-        // going to read it back from database and start back our SubstreamsStream
-        // with it ensuring we are continuously streaming without ever losing a single
-        // element.
+        // going to read it back and start back our SubstreamsStream with it ensuring
+        // we are continuously streaming without ever losing a single element.
         async { Ok(()) }
     }
 
     fn load_persisted_cursor(
         &self,
-    ) -> impl std::future::Future<Output = Result<Option<String>, Self::Error>> + Send {
-        // FIXME: Handling of the cursor is missing here. It should be loaded from
-        // somewhere (local file, database, cloud storage) and then `SubstreamStream` will
-        // be able correctly resume from the right block.
+        _module_hash: &str,
+    ) -> impl std::future::Future<Output = Result<Option<Cursor>, Self::Error>> + Send {
+        // The cursor should be loaded from the same `CursorStore` it was persisted
+        // to so `SubstreamStream` is able to correctly resume from the right block.
+        // Returning `None` streams from the configured start block.
         async { Ok(None) }
     }
 
     fn run(
         &self,
-        endpoint_url: &str,
-        spkg_file: &str,
-        module_name: &str,
-        start_block: i64,
-        end_block: u64,
-    ) -> impl std::future::Future<Output = Result<(), anyhow::Error>> + Send {
+        config: &RunConfig,
+    ) -> impl std::future::Future<Output = Result<(), StreamError>> + Send {
         async move {
-            let token_env = env::var("SUBSTREAMS_API_TOKEN").unwrap_or("".to_string());
-            let mut token: Option<String> = None;
-            if !token_env.is_empty() {
-                token = Some(token_env);
+            info!("Processing package {}", config.spkg_file);
+
+            let (package, module_hash) = load_package(config).await?;
+
+            let cursor = self
+                .load_persisted_cursor(&module_hash)
+                .await
+                .map_err(StreamError::sink)?;
+
+            if let Some(cursor) = &cursor {
+                info!(
+                    "Resuming from persisted cursor at block {}",
+                    cursor.block_number
+                );
             }
 
-            let cursor: Option<String> = self.load_persisted_cursor().await?;
+            let stream = block_stream(
+                config,
+                &package,
+                &module_hash,
+                cursor.map(|cursor| cursor.cursor),
+            )
+            .await?;
+            let recorder = block_recorder(config).await?;
+            health::set_phase(Phase::Streaming);
+
+            with_shutdown_timeout(config, process_stream(self, &module_hash, stream, recorder))
+                .await
+        }
+    }
 
-            println!("Processing block {}", spkg_file);
+    /// Processes every block of `stream` in order, persisting the cursor after
+    /// each one. `run` builds the stream from its config, this allows driving
+    /// the sink with any other source of blocks, e.g., a `ReplayStream` in tests.
+    fn run_stream<S>(
+        &self,
+        module_hash: &str,
+        stream: S,
+    ) -> impl std::future::Future<Output = Result<(), StreamError>> + Send
+    where
+        S: Stream<Item = Result<BlockResponse, StreamError>> + Send,
+    {
+        process_stream(self, module_hash, stream, None)
+    }
+}
 
-            let package = read_package(spkg_file).await.unwrap();
+/// Processes every block of `stream` with `sink`, see `Sink::run_stream`.
+///
+/// Blocks are recorded to `recorder`, if any, once they are processed and
+/// their cursor persisted. A block the sink fails on is never recorded, and
+/// resuming from the sink's cursor never records a block twice.
+pub(crate) async fn process_stream<K, T, S>(
+    sink: &K,
+    module_hash: &str,
+    stream: S,
+    recorder: Option<BlockRecorder>,
+) -> Result<(), StreamError>
+where
+    K: Sink<T> + ?Sized,
+    T: Send,
+    S: Stream<Item = Result<BlockResponse, StreamError>> + Send,
+{
+    let sink = WithoutPreprocessing {
+        sink,
+        output: PhantomData,
+    };
+
+    process_preprocessed_stream(&sink, module_hash, 1, stream, recorder).await
+}
 
-            let endpoint = Arc::new(SubstreamsEndpoint::new(&endpoint_url, token).await?);
+/// A `Sink` run as a `PreprocessedSink` with nothing to preprocess, so both
+/// kinds of sinks process their streams the same way.
+struct WithoutPreprocessing<'a, K: ?Sized, T> {
+    sink: &'a K,
+    output: PhantomData<fn() -> T>,
+}
 
-            let mut stream = SubstreamsStream::new(
-                endpoint.clone(),
-                cursor,
-                package.modules.clone(),
-                module_name.to_string(),
-                start_block,
-                end_block,
-            );
-
-            loop {
-                match stream.next().await {
-                    None => {
-                        println!("Stream consumed");
-                        break;
-                    }
-                    Some(Ok(BlockResponse::New(data))) => {
-                        self.process_block_scoped_data(&data).await?;
-                        self.persist_cursor(data.cursor).await?;
-                    }
-                    Some(Ok(BlockResponse::Undo(undo_signal))) => {
-                        self.process_block_undo_signal(&undo_signal)?;
-                        self.persist_cursor(undo_signal.last_valid_cursor).await?;
-                    }
-                    Some(Err(err)) => {
-                        println!();
-                        println!("Stream terminated with error");
-                        println!("{:?}", err);
-                        exit(1);
+impl<K, T> PreprocessedSink<()> for WithoutPreprocessing<'_, K, T>
+where
+    K: Sink<T> + ?Sized,
+    T: Send,
+{
+    type Error = K::Error;
+
+    async fn preprocess_block_scoped_data(
+        &self,
+        _block_data: &BlockScopedData,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn process_block_scoped_data(
+        &self,
+        block_data: &BlockScopedData,
+        _decoded_data: (),
+    ) -> Result<(), Self::Error> {
+        self.sink.process_block_scoped_data(block_data).await
+    }
+
+    async fn process_block_undo_signal(
+        &self,
+        undo_signal: &BlockUndoSignal,
+    ) -> Result<(), Self::Error> {
+        self.sink.process_block_undo_signal(undo_signal).await
+    }
+
+    async fn persist_cursor(&self, module_hash: &str, cursor: Cursor) -> Result<(), Self::Error> {
+        self.sink.persist_cursor(module_hash, cursor).await
+    }
+
+    async fn load_persisted_cursor(
+        &self,
+        module_hash: &str,
+    ) -> Result<Option<Cursor>, Self::Error> {
+        self.sink.load_persisted_cursor(module_hash).await
+    }
+}
+
+/// Processes every block of `stream` with `sink`, see
+/// `PreprocessedSink::run_stream`. Blocks are recorded like `process_stream`
+/// does, i.e., only once processed, even though up to `lookahead` blocks are
+/// taken from the stream ahead of time.
+pub(crate) async fn process_preprocessed_stream<K, P, S>(
+    sink: &K,
+    module_hash: &str,
+    lookahead: usize,
+    stream: S,
+    mut recorder: Option<BlockRecorder>,
+) -> Result<(), StreamError>
+where
+    K: PreprocessedSink<P> + ?Sized,
+    P: Send,
+    S: Stream<Item = Result<BlockResponse, StreamError>> + Send,
+{
+    // Preprocessing a block doesn't depend on earlier blocks being
+    // processed, so we run it ahead of time. `buffered` still yields
+    // the preprocessed blocks in the order they were streamed.
+    let mut stream = Box::pin(
+        stream
+            .map(|response| async move {
+                match response? {
+                    BlockResponse::New(data) => {
+                        let started_at = Instant::now();
+                        let decoded_data = sink
+                            .preprocess_block_scoped_data(&data)
+                            .instrument(logging::block_span(&data))
+                            .await
+                            .map_err(StreamError::sink)?;
+                        metrics::PREPROCESS_DURATION.observe(started_at.elapsed().as_secs_f64());
+                        Ok(PreprocessedResponse::New(data, decoded_data))
                     }
+                    BlockResponse::Undo(undo_signal) => Ok(PreprocessedResponse::Undo(undo_signal)),
                 }
+            })
+            .buffered(lookahead.max(1)),
+    );
+
+    loop {
+        match stream.next().await {
+            None => {
+                info!("Stream consumed");
+                break;
             }
+            Some(Ok(PreprocessedResponse::New(data, decoded_data))) => {
+                let span = logging::block_span(&data);
+
+                async {
+                    let started_at = Instant::now();
+                    sink.process_block_scoped_data(&data, decoded_data)
+                        .await
+                        .map_err(StreamError::sink)?;
+                    sink.persist_cursor(module_hash, Cursor::from_block(&data))
+                        .await
+                        .map_err(StreamError::sink)?;
+                    metrics::record_block_processed(&data, started_at.elapsed());
+                    health::record_block(&data);
+                    Ok::<_, StreamError>(())
+                }
+                .instrument(span)
+                .await?;
 
-            Ok(())
+                record_processed(&mut recorder, BlockResponse::New(data)).await?;
+            }
+            Some(Ok(PreprocessedResponse::Undo(undo_signal))) => {
+                let span = logging::undo_span(&undo_signal);
+
+                async {
+                    sink.process_block_undo_signal(&undo_signal)
+                        .await
+                        .map_err(StreamError::sink)?;
+                    sink.persist_cursor(module_hash, Cursor::from_undo(&undo_signal))
+                        .await
+                        .map_err(StreamError::sink)
+                }
+                .instrument(span)
+                .await?;
+
+                record_processed(&mut recorder, BlockResponse::Undo(undo_signal)).await?;
+            }
+            Some(Err(err)) => {
+                error!("Stream terminated with error {:?}", err);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn record_processed(
+    recorder: &mut Option<BlockRecorder>,
+    response: BlockResponse,
+) -> Result<(), StreamError> {
+    match recorder {
+        Some(recorder) => recorder
+            .record(&response)
+            .await
+            .map_err(StreamError::Replay),
+        None => Ok(()),
+    }
+}
+
+/// Opens the configured recording, if any.
+pub(crate) async fn block_recorder(
+    config: &RunConfig,
+) -> Result<Option<BlockRecorder>, StreamError> {
+    match &config.record_file {
+        Some(record_file) => {
+            info!("Recording blocks to {}", record_file);
+            Ok(Some(BlockRecorder::create(record_file).await?))
         }
+        None => Ok(None),
     }
 }
 
@@ -237,31 +443,196 @@ lazy_static! {
 
 const REGISTRY_URL: &str = "https://spkg.io";
 
-async fn read_package(input: &str) -> Result<Package, anyhow::Error> {
-    let mut mutable_input = input.to_string();
+pub(crate) type BlockStream =
+    Pin<Box<dyn Stream<Item = Result<BlockResponse, StreamError>> + Send>>;
+
+/// Streams blocks from the configured endpoint, or from a recording when
+/// replaying. Blocks are checked to have the output type declared by the
+/// package.
+pub(crate) async fn block_stream(
+    config: &RunConfig,
+    package: &Package,
+    module_hash: &str,
+    cursor: Option<String>,
+) -> Result<BlockStream, StreamError> {
+    if let Some(observer) = &config.observer {
+        observer.on_module_hash(module_hash);
+    }
 
-    let val = parse_standard_package_and_version(input);
-    if val.is_ok() {
-        let package_and_version = val.unwrap();
-        mutable_input = format!(
-            "{}/v1/packages/{}/{}",
-            REGISTRY_URL, package_and_version.0, package_and_version.1
-        );
+    let stream: BlockStream = match &config.replay_file {
+        Some(replay_file) => {
+            info!("Replaying blocks from {}", replay_file);
+
+            Box::pin(ReplayStream::new(
+                replay_file,
+                cursor,
+                config.start_block,
+                config.end_block,
+            ))
+        }
+        None => {
+            let mut endpoints = vec![];
+            for endpoint in &config.endpoints {
+                let endpoint =
+                    SubstreamsEndpoint::new(&endpoint.url, endpoint.token.clone()).await?;
+                endpoints.push(Arc::new(endpoint));
+            }
+
+            if endpoints.is_empty() {
+                return Err(StreamError::InvalidEndpoint(format_err!(
+                    "no substreams endpoint configured"
+                )));
+            }
+
+            Box::pin(SubstreamsStream::new(
+                EndpointPool::new(
+                    endpoints,
+                    config.failover_after,
+                    config.primary_retry_interval,
+                ),
+                cursor,
+                package.modules.clone(),
+                config.module_name.clone(),
+                config.start_block,
+                config.end_block,
+                config.final_blocks_only,
+                config.observer.clone(),
+            ))
+        }
+    };
+
+    let stream: BlockStream = match output_type_url(package, &config.module_name) {
+        Some(type_url) => Box::pin(check_output_type(stream, type_url)),
+        None => stream,
+    };
+
+    // Stops taking new blocks once shutdown is requested. The stream ends
+    // there, so sinks return once the blocks in flight are processed.
+    Ok(Box::pin(
+        stream.take_until(config.shutdown.clone().cancelled_owned()),
+    ))
+}
+
+/// Reads and validates the configured package, returning it along with the
+/// module hash of the configured module. Fails when the module hash doesn't
+/// match the expected one, if any.
+pub(crate) async fn load_package(config: &RunConfig) -> Result<(Package, String), StreamError> {
+    let package_cache = config.package_cache_dir.as_ref().map(PackageCache::new);
+
+    let package = read_package(&config.spkg_file, package_cache.as_ref())
+        .await
+        .map_err(StreamError::InvalidPackage)?;
+    validate_module(&package, &config.module_name)?;
+
+    let module_hash = cursor::module_hash(&package.modules, &config.module_name);
+    info!(
+        "Streaming module {} (module hash {})",
+        config.module_name, module_hash
+    );
+
+    if let Some(expected) = &config.expected_module_hash {
+        if *expected != module_hash {
+            return Err(StreamError::UnexpectedModuleHash {
+                expected: expected.clone(),
+                actual: module_hash,
+            });
+        }
+    }
+
+    Ok((package, module_hash))
+}
+
+pub(crate) fn validate_module(package: &Package, module_name: &str) -> Result<(), StreamError> {
+    let exists = package.modules.as_ref().is_some_and(|modules| {
+        modules
+            .modules
+            .iter()
+            .any(|module| module.name == module_name)
+    });
+
+    if !exists {
+        return Err(StreamError::InvalidModule(module_name.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Reads a package from a local file, a url or the registry when given as
+/// `<package>@<version>`. Packages resolved from the registry are cached in
+/// `package_cache` when given.
+pub(crate) async fn read_package(
+    input: &str,
+    package_cache: Option<&PackageCache>,
+) -> Result<Package, anyhow::Error> {
+    if let Ok((name, version)) = parse_standard_package_and_version(input) {
+        let url = format!("{}/v1/packages/{}/{}", REGISTRY_URL, name, version);
+
+        return match package_cache {
+            Some(package_cache) => read_cached_package(package_cache, &url, &name, &version).await,
+            None => read_http_package(&url).await,
+        };
     }
 
-    if mutable_input.starts_with("http") {
-        return read_http_package(&mutable_input).await;
+    if input.starts_with("http") {
+        return read_http_package(input).await;
     }
 
     // Assume it's a local file
-    let content = std::fs::read(&mutable_input)
-        .context(format_err!("read package from file '{}'", mutable_input))?;
+    let content =
+        std::fs::read(input).context(format_err!("read package from file '{}'", input))?;
     Package::decode(content.as_ref()).context("decode command")
 }
+
 async fn read_http_package(input: &str) -> Result<Package, anyhow::Error> {
-    let body = reqwest::get(input).await?.bytes().await?;
+    let body = fetch_package(input).await?;
+
+    Package::decode(body.as_ref()).context("decode command")
+}
+
+async fn fetch_package(input: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let body = reqwest::get(input)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
 
-    Package::decode(body).context("decode command")
+    Ok(body.to_vec())
+}
+
+async fn read_cached_package(
+    package_cache: &PackageCache,
+    url: &str,
+    name: &str,
+    version: &str,
+) -> Result<Package, anyhow::Error> {
+    // Anything but `latest` always resolves to the same package, so we only
+    // fetch it once.
+    if version != "latest" {
+        if let Some(content) = package_cache.load(name, version).await? {
+            info!("Using cached package {}@{}", name, version);
+            return Package::decode(content.as_ref()).context("decode command");
+        }
+    }
+
+    let content = match fetch_package(url).await {
+        Ok(content) => content,
+        Err(error) => match package_cache.load(name, version).await? {
+            Some(content) => {
+                warn!(
+                    "Unable to fetch package {}@{}, using cached package: {:#}",
+                    name, version, error
+                );
+                content
+            }
+            None => return Err(error),
+        },
+    };
+
+    let package = Package::decode(content.as_ref()).context("decode command")?;
+    let hash = package_cache.store(name, version, &content).await?;
+    info!("Cached package {}@{} ({})", name, version, hash);
+
+    Ok(package)
 }
 
 fn parse_standard_package_and_version(input: &str) -> Result<(String, String), Error> {
@@ -304,3 +675,253 @@ fn parse_standard_package_and_version(input: &str) -> Result<(String, String), E
 fn is_valid_version(version: &str) -> bool {
     Version::parse(version).is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use futures03::stream;
+    use thiserror::Error;
+
+    use super::*;
+    use crate::{
+        pb::sf::substreams::v1::{Module, Modules},
+        test_utils::{make_block, make_undo},
+    };
+
+    #[derive(Error, Debug)]
+    #[error("test sink error at block {0}")]
+    struct TestSinkError(u64);
+
+    #[derive(Default)]
+    struct TestSink {
+        failing_block: Option<u64>,
+        crashing_block: Option<u64>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        processed: Mutex<Vec<String>>,
+        cursors: Mutex<Vec<u64>>,
+    }
+
+    impl PreprocessedSink<u64> for TestSink {
+        type Error = TestSinkError;
+
+        async fn preprocess_block_scoped_data(
+            &self,
+            block_data: &BlockScopedData,
+        ) -> Result<u64, Self::Error> {
+            let number = block_data.clock.as_ref().unwrap().number;
+
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+            // Later blocks finish preprocessing first.
+            tokio::time::sleep(Duration::from_millis(20 - number)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            if self.failing_block == Some(number) {
+                return Err(TestSinkError(number));
+            }
+
+            Ok(number)
+        }
+
+        async fn process_block_scoped_data(
+            &self,
+            _block_data: &BlockScopedData,
+            decoded_data: u64,
+        ) -> Result<(), Self::Error> {
+            if self.crashing_block == Some(decoded_data) {
+                return Err(TestSinkError(decoded_data));
+            }
+
+            let entry = format!("new {}", decoded_data);
+            self.processed.lock().unwrap().push(entry);
+            Ok(())
+        }
+
+        async fn process_block_undo_signal(
+            &self,
+            undo_signal: &BlockUndoSignal,
+        ) -> Result<(), Self::Error> {
+            let number = undo_signal.last_valid_block.as_ref().unwrap().number;
+            self.processed
+                .lock()
+                .unwrap()
+                .push(format!("undo {}", number));
+            Ok(())
+        }
+
+        async fn persist_cursor(
+            &self,
+            _module_hash: &str,
+            cursor: Cursor,
+        ) -> Result<(), Self::Error> {
+            self.cursors.lock().unwrap().push(cursor.block_number);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preprocesses_ahead_and_processes_in_order() {
+        let sink = TestSink::default();
+        let responses = vec![
+            Ok(make_block(1)),
+            Ok(make_block(2)),
+            Ok(make_block(3)),
+            Ok(make_undo(2)),
+            Ok(make_block(3)),
+            Ok(make_block(4)),
+            Ok(make_block(5)),
+        ];
+
+        sink.run_stream("abc", 3, stream::iter(responses))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *sink.processed.lock().unwrap(),
+            vec![
+                "new 1", "new 2", "new 3", "undo 2", "new 3", "new 4", "new 5"
+            ]
+        );
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2, 3, 2, 3, 4, 5]);
+
+        let max_in_flight = sink.max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1 && max_in_flight <= 3);
+    }
+
+    #[tokio::test]
+    async fn test_lookahead_of_one_preprocesses_sequentially() {
+        let sink = TestSink::default();
+        let responses = vec![Ok(make_block(1)), Ok(make_block(2)), Ok(make_block(3))];
+
+        sink.run_stream("abc", 1, stream::iter(responses))
+            .await
+            .unwrap();
+
+        assert_eq!(sink.max_in_flight.load(Ordering::SeqCst), 1);
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_preprocess_error_stops_after_earlier_blocks() {
+        let sink = TestSink {
+            failing_block: Some(3),
+            ..Default::default()
+        };
+        let responses = (1..=6)
+            .map(|number| Ok(make_block(number)))
+            .collect::<Vec<_>>();
+
+        let error = sink
+            .run_stream("abc", 4, stream::iter(responses))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, StreamError::Sink(_)));
+        assert_eq!(*sink.processed.lock().unwrap(), vec!["new 1", "new 2"]);
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_records_only_processed_blocks_with_lookahead() {
+        let record_file = std::env::temp_dir().join(format!(
+            "stream-sink-record-lookahead-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&record_file);
+
+        // The sink crashes on block 3 while blocks up to 6 were taken from the
+        // stream to be preprocessed.
+        let sink = TestSink {
+            crashing_block: Some(3),
+            ..Default::default()
+        };
+        let responses = (1..=6)
+            .map(|number| Ok(make_block(number)))
+            .collect::<Vec<_>>();
+        let recorder = BlockRecorder::create(&record_file).await.unwrap();
+
+        let error =
+            process_preprocessed_stream(&sink, "abc", 4, stream::iter(responses), Some(recorder))
+                .await
+                .unwrap_err();
+        assert!(matches!(error, StreamError::Sink(_)));
+        assert_eq!(*sink.cursors.lock().unwrap(), vec![1, 2]);
+
+        // Resuming from the sink's cursor records the remaining blocks once.
+        let sink = TestSink::default();
+        let responses = vec![Ok(make_block(3)), Ok(make_undo(2)), Ok(make_block(3))];
+        let recorder = BlockRecorder::create(&record_file).await.unwrap();
+
+        process_preprocessed_stream(&sink, "abc", 4, stream::iter(responses), Some(recorder))
+            .await
+            .unwrap();
+
+        let recorded = ReplayStream::new(&record_file, None, 0, 0)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            recorded,
+            vec![
+                make_block(1),
+                make_block(2),
+                make_block(3),
+                make_undo(2),
+                make_block(3)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_package_checks_expected_module_hash() {
+        let spkg_file = std::env::temp_dir().join(format!(
+            "stream-sink-module-hash-{}.spkg",
+            std::process::id()
+        ));
+        let package = Package {
+            modules: Some(Modules {
+                modules: vec![Module {
+                    name: "geo_out".to_string(),
+                    ..Default::default()
+                }],
+                binaries: vec![],
+            }),
+            ..Default::default()
+        };
+        std::fs::write(&spkg_file, package.encode_to_vec()).unwrap();
+
+        let config = RunConfig::new(vec![], &spkg_file.display().to_string(), "geo_out");
+        let (_, module_hash) = load_package(&config).await.unwrap();
+        assert_eq!(
+            module_hash,
+            cursor::module_hash(&package.modules, "geo_out")
+        );
+
+        let pinned = RunConfig {
+            expected_module_hash: Some(module_hash.clone()),
+            ..config.clone()
+        };
+        assert!(load_package(&pinned).await.is_ok());
+
+        let changed = RunConfig {
+            expected_module_hash: Some("abc".to_string()),
+            ..config
+        };
+        assert!(matches!(
+            load_package(&changed).await,
+            Err(StreamError::UnexpectedModuleHash { expected, actual })
+                if expected == "abc" && actual == module_hash
+        ));
+    }
+}
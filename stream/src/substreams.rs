@@ -1,4 +1,9 @@
-use std::{fmt::Display, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use http::{Uri, uri::Scheme};
 use tonic::{
@@ -8,7 +13,10 @@ use tonic::{
     transport::{Channel, ClientTlsConfig},
 };
 
-use crate::pb::sf::substreams::rpc::v2::{Request, Response, stream_client::StreamClient};
+use crate::{
+    error::StreamError,
+    pb::sf::substreams::rpc::v2::{Request, Response, stream_client::StreamClient},
+};
 
 #[derive(Clone, Debug)]
 pub struct SubstreamsEndpoint {
@@ -24,18 +32,23 @@ impl Display for SubstreamsEndpoint {
 }
 
 impl SubstreamsEndpoint {
-    pub async fn new<S: AsRef<str>>(url: S, token: Option<String>) -> Result<Self, anyhow::Error> {
+    pub async fn new<S: AsRef<str>>(url: S, token: Option<String>) -> Result<Self, StreamError> {
         let uri = url
             .as_ref()
             .parse::<Uri>()
-            .expect("the url should have been validated by now, so it is a valid Uri");
+            .map_err(|e| StreamError::InvalidEndpoint(e.into()))?;
 
         let endpoint = match uri.scheme().unwrap_or(&Scheme::HTTP).as_str() {
             "http" => Channel::builder(uri),
             "https" => Channel::builder(uri)
                 .tls_config(ClientTlsConfig::new().with_native_roots())
-                .expect("TLS config on this host is invalid"),
-            _ => panic!("invalid uri scheme for firehose endpoint"),
+                .map_err(|e| StreamError::InvalidEndpoint(e.into()))?,
+            scheme => {
+                return Err(StreamError::InvalidEndpoint(anyhow::anyhow!(
+                    "invalid uri scheme {} for substreams endpoint",
+                    scheme
+                )));
+            }
         }
         .connect_timeout(Duration::from_secs(10))
         .tcp_keepalive(Some(Duration::from_secs(30)));
@@ -53,10 +66,13 @@ impl SubstreamsEndpoint {
     pub async fn substreams(
         self: Arc<Self>,
         request: Request,
-    ) -> Result<tonic::Streaming<Response>, anyhow::Error> {
+    ) -> Result<tonic::Streaming<Response>, StreamError> {
         let token_metadata: Option<MetadataValue<tonic::metadata::Ascii>> = match self.token.clone()
         {
-            Some(token) => Some(token.as_str().try_into()?),
+            Some(token) => Some(
+                MetadataValue::try_from(token.as_str())
+                    .map_err(|e| StreamError::InvalidEndpoint(e.into()))?,
+            ),
             None => None,
         };
 
@@ -74,9 +90,243 @@ impl SubstreamsEndpoint {
         .send_compressed(CompressionEncoding::Gzip)
         .max_decoding_message_size(10 * 1024 * 1024);
 
-        let response_stream = client.blocks(request).await?;
+        let response_stream = client
+            .blocks(request)
+            .await
+            .map_err(StreamError::from_status)?;
         let block_stream = response_stream.into_inner();
 
         Ok(block_stream)
     }
 }
+
+/// The endpoints a `SubstreamsStream` streams from, in order of preference.
+/// Tracks consecutive failures of the current endpoint to decide when to fail
+/// over to the next one, and when to try returning to the primary endpoint.
+pub struct EndpointPool {
+    endpoints: Vec<Arc<SubstreamsEndpoint>>,
+    current: usize,
+    failures: u32,
+    failover_after: u32,
+    primary_retry_interval: Duration,
+    failed_over_at: Option<Instant>,
+    probing_primary: bool,
+    /// Failovers and endpoints that rejected the credentials since an
+    /// endpoint last streamed a block.
+    failovers: usize,
+    rejected: HashSet<usize>,
+}
+
+impl EndpointPool {
+    /// Panics if `endpoints` is empty.
+    pub fn new(
+        endpoints: Vec<Arc<SubstreamsEndpoint>>,
+        failover_after: u32,
+        primary_retry_interval: Duration,
+    ) -> Self {
+        assert!(!endpoints.is_empty(), "endpoint pool must not be empty");
+
+        EndpointPool {
+            endpoints,
+            current: 0,
+            failures: 0,
+            failover_after: failover_after.max(1),
+            primary_retry_interval,
+            failed_over_at: None,
+            probing_primary: false,
+            failovers: 0,
+            rejected: HashSet::new(),
+        }
+    }
+
+    pub fn current(&self) -> Arc<SubstreamsEndpoint> {
+        self.endpoints[self.current].clone()
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.current == 0
+    }
+
+    /// The current endpoint streamed a block, it is healthy again.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.probing_primary = false;
+        self.failovers = 0;
+        self.rejected.clear();
+
+        if self.is_primary() {
+            self.failed_over_at = None;
+        }
+    }
+
+    /// The current endpoint failed to connect or its stream failed. Returns
+    /// whether the pool failed over to another endpoint.
+    ///
+    /// A failed attempt at returning to the primary endpoint fails over
+    /// right away, to keep streaming from a fallback endpoint known to work.
+    pub fn record_failure(&mut self) -> bool {
+        self.failures += 1;
+
+        let failed_over = self.endpoints.len() > 1
+            && (self.probing_primary || self.failures >= self.failover_after);
+
+        if failed_over {
+            self.fail_over();
+        }
+
+        failed_over
+    }
+
+    /// The current endpoint rejected the credentials. Each endpoint has its
+    /// own, so the pool fails over right away. Returns false, without
+    /// failing over, once every endpoint rejected them.
+    pub fn record_rejection(&mut self) -> bool {
+        self.rejected.insert(self.current);
+        if self.rejected.len() == self.endpoints.len() {
+            return false;
+        }
+
+        self.fail_over();
+        true
+    }
+
+    /// Whether every endpoint failed in turn since an endpoint last streamed
+    /// a block, i.e., failing over again would start another rotation.
+    pub fn completed_rotation(&self) -> bool {
+        self.failovers > 0 && self.failovers % self.endpoints.len() == 0
+    }
+
+    /// Whether the stream has been on a fallback endpoint for long enough
+    /// that it should try to return to the primary endpoint.
+    pub fn should_return_to_primary(&self) -> bool {
+        !self.is_primary()
+            && self.failed_over_at.is_some_and(|failed_over_at| {
+                failed_over_at.elapsed() >= self.primary_retry_interval
+            })
+    }
+
+    pub fn return_to_primary(&mut self) {
+        self.current = 0;
+        self.failures = 0;
+        self.probing_primary = true;
+    }
+
+    fn fail_over(&mut self) {
+        self.current = (self.current + 1) % self.endpoints.len();
+        self.failures = 0;
+        self.probing_primary = false;
+        self.failed_over_at = Some(Instant::now());
+        self.failovers += 1;
+    }
+}
+
+impl From<Arc<SubstreamsEndpoint>> for EndpointPool {
+    fn from(endpoint: Arc<SubstreamsEndpoint>) -> Self {
+        EndpointPool::new(vec![endpoint], 1, Duration::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn make_pool(
+        endpoints: usize,
+        failover_after: u32,
+        primary_retry_interval: Duration,
+    ) -> EndpointPool {
+        let mut pool = vec![];
+        for index in 0..endpoints {
+            let url = format!("http://127.0.0.1:{}", 9000 + index);
+            pool.push(Arc::new(SubstreamsEndpoint::new(url, None).await.unwrap()));
+        }
+
+        EndpointPool::new(pool, failover_after, primary_retry_interval)
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_after_consecutive_failures() {
+        let mut pool = make_pool(3, 2, Duration::MAX).await;
+
+        assert!(!pool.record_failure());
+        pool.record_success();
+        assert!(!pool.record_failure());
+        assert!(pool.is_primary());
+
+        assert!(pool.record_failure());
+        assert_eq!(pool.current().uri, "http://127.0.0.1:9001/");
+
+        assert!(!pool.record_failure());
+        assert!(pool.record_failure());
+        assert_eq!(pool.current().uri, "http://127.0.0.1:9002/");
+
+        // Wraps around to the primary once every fallback failed.
+        assert!(!pool.record_failure());
+        assert!(pool.record_failure());
+        assert!(pool.is_primary());
+    }
+
+    #[tokio::test]
+    async fn test_single_endpoint_never_fails_over() {
+        let mut pool = make_pool(1, 1, Duration::ZERO).await;
+
+        assert!(!pool.record_failure());
+        assert!(!pool.record_failure());
+        assert!(pool.is_primary());
+        assert!(!pool.should_return_to_primary());
+    }
+
+    #[tokio::test]
+    async fn test_returns_to_primary() {
+        let mut pool = make_pool(2, 1, Duration::ZERO).await;
+
+        assert!(!pool.should_return_to_primary());
+        assert!(pool.record_failure());
+        pool.record_success();
+        assert!(pool.should_return_to_primary());
+
+        pool.return_to_primary();
+        assert!(pool.is_primary());
+        assert!(!pool.should_return_to_primary());
+
+        // The primary is still failing, we fail over again right away.
+        assert!(pool.record_failure());
+        assert_eq!(pool.current().uri, "http://127.0.0.1:9001/");
+
+        pool.record_success();
+        pool.return_to_primary();
+        pool.record_success();
+        assert!(!pool.should_return_to_primary());
+    }
+
+    #[tokio::test]
+    async fn test_completes_rotation_after_every_endpoint_failed() {
+        let mut pool = make_pool(2, 1, Duration::MAX).await;
+
+        assert!(pool.record_failure());
+        assert!(!pool.completed_rotation());
+        assert!(pool.record_failure());
+        assert!(pool.completed_rotation());
+
+        pool.record_success();
+        assert!(!pool.completed_rotation());
+    }
+
+    #[tokio::test]
+    async fn test_rejections_fail_over_until_every_endpoint_rejected() {
+        let mut pool = make_pool(3, 5, Duration::MAX).await;
+
+        assert!(pool.record_rejection());
+        assert_eq!(pool.current().uri, "http://127.0.0.1:9001/");
+
+        // A streamed block means the endpoint accepted the credentials.
+        pool.record_success();
+        assert!(pool.record_rejection());
+        assert!(pool.record_rejection());
+        assert!(!pool.record_rejection());
+        assert_eq!(pool.current().uri, "http://127.0.0.1:9000/");
+
+        let mut pool = make_pool(1, 1, Duration::MAX).await;
+        assert!(!pool.record_rejection());
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::{Error, anyhow};
 use async_stream::try_stream;
 use futures03::{Stream, StreamExt};
 use std::{
@@ -9,72 +8,94 @@ use std::{
 };
 use tokio::time::sleep;
 use tokio_retry::strategy::ExponentialBackoff;
+use tracing::{info, warn};
 
 use crate::pb::sf::substreams::rpc::v2::{
-    BlockScopedData, BlockUndoSignal, Request, Response, response::Message,
+    BlockScopedData, BlockUndoSignal, Error as ModuleError, Request, Response, response::Message,
 };
 use crate::pb::sf::substreams::v1::Modules;
 
-use crate::substreams::SubstreamsEndpoint;
+use crate::{error::StreamError, metrics, observer::StreamObserver, substreams::EndpointPool};
 
+#[derive(Clone, Debug, PartialEq)]
 pub enum BlockResponse {
     New(BlockScopedData),
     Undo(BlockUndoSignal),
 }
 
 pub struct SubstreamsStream {
-    stream: Pin<Box<dyn Stream<Item = Result<BlockResponse, Error>> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = Result<BlockResponse, StreamError>> + Send>>,
 }
 
 impl SubstreamsStream {
+    /// Streams blocks for `output_module_name`, reconnecting from the latest
+    /// cursor on transient errors, on the next endpoint of the pool once the
+    /// current one keeps failing. Messages other than blocks are forwarded to
+    /// `observer` when given.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        endpoint: Arc<SubstreamsEndpoint>,
+        endpoints: impl Into<EndpointPool>,
         cursor: Option<String>,
         modules: Option<Modules>,
         output_module_name: String,
         start_block: i64,
         end_block: u64,
+        final_blocks_only: bool,
+        observer: Option<Arc<dyn StreamObserver>>,
     ) -> Self {
         SubstreamsStream {
             stream: Box::pin(stream_blocks(
-                endpoint,
+                endpoints.into(),
                 cursor,
                 modules,
                 output_module_name,
                 start_block,
                 end_block,
+                final_blocks_only,
+                observer,
             )),
         }
     }
 }
 
 // Create the Stream implementation that streams blocks with auto-reconnection.
+#[allow(clippy::too_many_arguments)]
 fn stream_blocks(
-    endpoint: Arc<SubstreamsEndpoint>,
+    mut endpoints: EndpointPool,
     cursor: Option<String>,
     modules: Option<Modules>,
     output_module_name: String,
     start_block_num: i64,
     stop_block_num: u64,
-) -> impl Stream<Item = Result<BlockResponse, Error>> {
+    final_blocks_only: bool,
+    observer: Option<Arc<dyn StreamObserver>>,
+) -> impl Stream<Item = Result<BlockResponse, StreamError>> {
     let mut latest_cursor = cursor.unwrap_or_else(|| "".to_string());
     let mut backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
     let mut last_progress_report = Instant::now();
+    let mut connected_before = false;
 
     try_stream! {
         loop {
-            println!("Blockstreams disconnected, connecting (endpoint {}, start block {}, stop block {}, cursor {})",
+            let endpoint = endpoints.current();
+
+            if connected_before {
+                metrics::RECONNECTS.inc();
+            }
+            connected_before = true;
+
+            info!("Blockstreams disconnected, connecting (endpoint {}, start block {}, stop block {}, cursor {})",
                 &endpoint,
                 start_block_num,
                 stop_block_num,
                 &latest_cursor
             );
 
-            let result = endpoint.clone().substreams(Request {
+            let result = endpoint.substreams(Request {
                 start_block_num,
                 start_cursor: latest_cursor.clone(),
                 stop_block_num,
-                final_blocks_only: false,
+                final_blocks_only,
                 modules: modules.clone(),
                 output_module: output_module_name.clone(),
                 // There is usually no good reason for you to consume the stream development mode (so switching `true`
@@ -87,16 +108,18 @@ fn stream_blocks(
 
             }).await;
 
-            match result {
+            let last_error = match result {
                 Ok(stream) => {
-                    println!("Blockstreams connected");
+                    info!("Blockstreams connected");
 
-                    let mut encountered_error = false;
+                    let mut stream_error = None;
+                    let mut returning_to_primary = false;
                     for await response in stream{
-                        match process_substreams_response(response, &mut last_progress_report).await {
+                        match process_substreams_response(response, observer.as_deref(), &mut last_progress_report).await {
                             BlockProcessedResult::BlockScopedData(block_scoped_data) => {
                                 // Reset backoff because we got a good value from the stream
                                 backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                endpoints.record_success();
 
                                 let cursor = block_scoped_data.cursor.clone();
                                 yield BlockResponse::New(block_scoped_data);
@@ -106,6 +129,7 @@ fn stream_blocks(
                             BlockProcessedResult::BlockUndoSignal(block_undo_signal) => {
                                 // Reset backoff because we got a good value from the stream
                                 backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                endpoints.record_success();
 
                                 let cursor = block_undo_signal.last_valid_cursor.clone();
                                 yield BlockResponse::Undo(block_undo_signal);
@@ -113,39 +137,92 @@ fn stream_blocks(
                                 latest_cursor = cursor;
                             },
                             BlockProcessedResult::Skip() => {},
+                            BlockProcessedResult::FatalError(error) => {
+                                // The module failed deterministically, reconnecting would
+                                // fail in the exact same way.
+                                return Err(StreamError::ModuleFailed(error))?;
+                            },
                             BlockProcessedResult::TonicError(status) => {
-                                // Unauthenticated errors are not retried, we forward the error back to the
-                                // stream consumer which handles it
-                                if status.code() == tonic::Code::Unauthenticated {
-                                    return Err(anyhow::Error::new(status.clone()))?;
+                                // Fatal errors like invalid requests are not retried, we forward
+                                // the error back to the stream consumer which handles it.
+                                // Rejected credentials fail over to the next endpoint below.
+                                let error = StreamError::from_status(status);
+                                if error.is_fatal() && !error.is_rejection() {
+                                    return Err(error)?;
                                 }
 
-                                println!("Received tonic error {:#}", status);
-                                encountered_error = true;
+                                warn!("Received tonic error {:#}", error);
+                                stream_error = Some(error);
                                 break;
                             },
                         }
+
+                        // Streaming from a fallback endpoint, we periodically
+                        // reconnect to the primary endpoint to return to it
+                        // once it has recovered.
+                        if endpoints.should_return_to_primary() {
+                            returning_to_primary = true;
+                            break;
+                        }
                     }
 
-                    if !encountered_error {
-                        println!("Stream completed, reached end block");
-                        return
+                    if returning_to_primary {
+                        endpoints.return_to_primary();
+                        info!("Returning to primary endpoint {}", endpoints.current());
+                        continue;
+                    }
+
+                    match stream_error {
+                        Some(error) => error,
+                        None => {
+                            info!("Stream completed, reached end block");
+                            return
+                        }
                     }
                 },
-                Err(e) => {
+                Err(error) => {
+                    if error.is_fatal() && !error.is_rejection() {
+                        return Err(error)?;
+                    }
+
                     // We failed to connect and will try again; this is another
                     // case where we actually _want_ to back off in case we keep
                     // having connection errors.
 
-                    println!("Unable to connect to endpoint: {:#}", e);
+                    warn!("Unable to connect to endpoint: {:#}", error);
+                    error
+                }
+            };
+
+            // The endpoint rejected the credentials or keeps failing, we resume
+            // from the latest cursor on the next endpoint right away. Rejected
+            // credentials are only fatal once every endpoint rejected them.
+            let failed_over = if last_error.is_rejection() {
+                if !endpoints.record_rejection() {
+                    return Err(last_error)?;
+                }
+                true
+            } else {
+                endpoints.record_failure()
+            };
+
+            // Once every endpoint failed in turn, we back off before starting
+            // another rotation so a full outage isn't retried in a tight loop.
+            if failed_over {
+                metrics::FAILOVERS.inc();
+                warn!("Failing over to endpoint {} after error {:#}", endpoints.current(), last_error);
+                if !endpoints.completed_rotation() {
+                    continue;
                 }
             }
 
             // If we reach this point, we must wait a bit before retrying
             if let Some(duration) = backoff.next() {
+                metrics::BACKOFFS.inc();
+                metrics::BACKOFF_SECONDS.inc_by(duration.as_secs());
                 sleep(duration).await
             } else {
-                return Err(anyhow!("backoff requested to stop retrying, quitting"))?;
+                return Err(StreamError::BackoffExhausted(Box::new(last_error)))?;
             }
         }
     }
@@ -155,11 +232,13 @@ enum BlockProcessedResult {
     Skip(),
     BlockScopedData(BlockScopedData),
     BlockUndoSignal(BlockUndoSignal),
+    FatalError(ModuleError),
     TonicError(tonic::Status),
 }
 
 async fn process_substreams_response(
     result: Result<Response, tonic::Status>,
+    observer: Option<&dyn StreamObserver>,
     last_progress_report: &mut Instant,
 ) -> BlockProcessedResult {
     let response = match result {
@@ -169,10 +248,14 @@ async fn process_substreams_response(
 
     match response.message {
         Some(Message::Session(session)) => {
-            println!(
-                "Received session message (Workers {}, Trace ID {})",
-                session.max_parallel_workers, &session.trace_id
+            info!(
+                "Received session message (Workers {}, Trace ID {}, Resolved Start Block {})",
+                session.max_parallel_workers, &session.trace_id, session.resolved_start_block
             );
+
+            if let Some(observer) = observer {
+                observer.on_session(&session);
+            }
             BlockProcessedResult::Skip()
         }
         Some(Message::BlockScopedData(block_scoped_data)) => {
@@ -181,11 +264,16 @@ async fn process_substreams_response(
         Some(Message::BlockUndoSignal(block_undo_signal)) => {
             BlockProcessedResult::BlockUndoSignal(block_undo_signal)
         }
+        Some(Message::FatalError(error)) => BlockProcessedResult::FatalError(error),
         Some(Message::Progress(progress)) => {
+            if let Some(observer) = observer {
+                observer.on_progress(&progress);
+            }
+
             if last_progress_report.elapsed() > Duration::from_secs(30) {
                 let processed_bytes = progress.processed_bytes.unwrap_or_default();
 
-                println!(
+                info!(
                     "Latest progress message received (Stages: {}, Jobs: {}, Processed Bytes: [Read: {}, Written: {}])",
                     progress.stages.len(),
                     progress.running_jobs.len(),
@@ -231,16 +319,27 @@ async fn process_substreams_response(
 
             BlockProcessedResult::Skip()
         }
+        Some(Message::DebugSnapshotData(snapshot)) => {
+            if let Some(observer) = observer {
+                observer.on_snapshot_data(&snapshot);
+            }
+            BlockProcessedResult::Skip()
+        }
+        Some(Message::DebugSnapshotComplete(complete)) => {
+            if let Some(observer) = observer {
+                observer.on_snapshot_complete(&complete);
+            }
+            BlockProcessedResult::Skip()
+        }
         None => {
-            println!("Got None on substream message");
+            warn!("Got None on substream message");
             BlockProcessedResult::Skip()
         }
-        _ => BlockProcessedResult::Skip(),
     }
 }
 
 impl Stream for SubstreamsStream {
-    type Item = Result<BlockResponse, Error>;
+    type Item = Result<BlockResponse, StreamError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         self.stream.poll_next_unpin(cx)
@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use prost::Message;
+
+use crate::{
+    config::RunConfig,
+    pb::sf::substreams::{
+        rpc::v2::{BlockScopedData, BlockUndoSignal},
+        v1::{BlockRef, Clock, Module, Modules, Package},
+    },
+    replay::BlockRecorder,
+    substreams_stream::BlockResponse,
+};
+
+/// A path in the temp directory unique to `name` and the test process,
+/// cleared of whatever a previous run left there.
+pub(crate) fn test_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("stream-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// A directory in the temp directory unique to `name` and the test process,
+/// cleared of whatever a previous run left there.
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("stream-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Block data for `number` with a cursor derived from it.
+pub(crate) fn make_block_data(number: u64) -> BlockScopedData {
+    BlockScopedData {
+        clock: Some(Clock {
+            number,
+            ..Default::default()
+        }),
+        cursor: format!("cursor-{}", number),
+        ..Default::default()
+    }
+}
+
+/// An undo signal back to `last_valid_block`, with the cursor
+/// `make_block_data` gave that block.
+pub(crate) fn make_undo_signal(last_valid_block: u64) -> BlockUndoSignal {
+    BlockUndoSignal {
+        last_valid_block: Some(BlockRef {
+            number: last_valid_block,
+            ..Default::default()
+        }),
+        last_valid_cursor: format!("cursor-{}", last_valid_block),
+    }
+}
+
+pub(crate) fn make_block(number: u64) -> BlockResponse {
+    BlockResponse::New(make_block_data(number))
+}
+
+pub(crate) fn make_undo(last_valid_block: u64) -> BlockResponse {
+    BlockResponse::Undo(make_undo_signal(last_valid_block))
+}
+
+/// Writes a package and a recording of `blocks` so sinks can run end-to-end
+/// by replaying the recording.
+pub(crate) async fn make_config(name: &str, blocks: u64) -> RunConfig {
+    let spkg_file = test_path(&format!("{}-spkg", name));
+    let package = Package {
+        modules: Some(Modules {
+            modules: vec![Module {
+                name: "geo_out".to_string(),
+                ..Default::default()
+            }],
+            binaries: vec![],
+        }),
+        ..Default::default()
+    };
+    std::fs::write(&spkg_file, package.encode_to_vec()).unwrap();
+
+    let replay_file = test_path(&format!("{}-blocks", name));
+    let mut recorder = BlockRecorder::create(&replay_file).await.unwrap();
+    for number in 1..=blocks {
+        recorder.record(&make_block(number)).await.unwrap();
+    }
+
+    RunConfig {
+        replay_file: Some(replay_file.display().to_string()),
+        ..RunConfig::new(vec![], &spkg_file.display().to_string(), "geo_out")
+    }
+}
@@ -1,16 +1,6 @@
 use crate::pb::sf::substreams::rpc::v2::BlockScopedData;
 use chrono::DateTime;
 
-pub fn output(block_data: &BlockScopedData) -> &prost_types::Any {
-    return block_data
-        .output
-        .as_ref()
-        .unwrap()
-        .map_output
-        .as_ref()
-        .unwrap();
-}
-
 #[derive(Clone, Debug)]
 pub struct BlockMetadata {
     pub cursor: String,